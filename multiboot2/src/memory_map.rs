@@ -16,12 +16,22 @@ pub struct MemoryMapTag {
 
 impl MemoryMapTag {
     pub fn memory_areas(&self) -> MemoryAreaIter {
+        self.area_iter(true)
+    }
+
+    // Includes reserved and firmware areas as well as available RAM
+    pub fn all_memory_areas(&self) -> MemoryAreaIter {
+        self.area_iter(false)
+    }
+
+    fn area_iter(&self, available_only: bool) -> MemoryAreaIter {
         let self_ptr = self as *const MemoryMapTag;
         let start_area = (&self.first_area) as *const MemoryArea;
         MemoryAreaIter {
             current_area: start_area as u64,
             last_area: (self_ptr as u64 + (self.size - self.entry_size) as u64),
             entry_size: self.entry_size,
+            available_only: available_only,
         }
     }
 }
@@ -47,6 +57,25 @@ impl MemoryArea {
     pub fn size(&self) -> usize {
         self.length as usize
     }
+
+    pub fn typ(&self) -> MemoryAreaType {
+        match self.typ {
+            1 => MemoryAreaType::Available,
+            3 => MemoryAreaType::AcpiReclaimable,
+            4 => MemoryAreaType::AcpiNvs,
+            5 => MemoryAreaType::Defective,
+            _ => MemoryAreaType::Reserved,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum MemoryAreaType {
+    Available,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    Defective,
 }
 
 #[derive(Clone, Debug)]
@@ -54,6 +83,7 @@ pub struct MemoryAreaIter {
     current_area: u64,
     last_area: u64,
     entry_size: u32,
+    available_only: bool,
 }
 
 impl Iterator for MemoryAreaIter {
@@ -64,7 +94,7 @@ impl Iterator for MemoryAreaIter {
         } else {
             let area = unsafe{&*(self.current_area as *const MemoryArea)};
             self.current_area = self.current_area + (self.entry_size as u64);
-            if area.typ == 1 {
+            if area.typ == 1 || !self.available_only {
                 Some(area)
             } else {self.next()}
         }
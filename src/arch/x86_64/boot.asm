@@ -13,11 +13,24 @@ PAGE_NO_EXEC equ 0x8000000000000000
 ; === BOOTSTRAP DATA === 
 section .bootstrap_data
 align 4096
+%ifdef LA57
+; With five-level paging the P5 table holds the recursive mapping and maps the P4 table twice,
+; once for the identity map and once for the higher half
+p5_table:
+    dq (p4_table + PAGE_PRESENT + PAGE_WRITABLE) ; 0 - Identity map
+    times (512 - 3) dq 0                        ; ...
+    dq (p5_table + PAGE_PRESENT + PAGE_WRITABLE) ; 510 - Recursive mapping of P5
+    dq (p4_table + PAGE_PRESENT + PAGE_WRITABLE) ; 511 - Higher half kernel map
+%endif
 ; Page map with both an identity map and a higher side identity map
 p4_table:
     dq (p3_table + PAGE_PRESENT + PAGE_WRITABLE) ; 0 - Identity map
     times (512 - 3) dq 0                        ; ...
+%ifdef LA57
+    dq 0                                        ; 510 - Recursive mapping is in P5
+%else
     dq (p4_table + PAGE_PRESENT + PAGE_WRITABLE) ; 510 - Recursive mapping of P4
+%endif
     dq (p3_table_higher + PAGE_PRESENT + PAGE_WRITABLE) ; 511 - Higher half kernel map
 p3_table:
     dq (p2_table + PAGE_PRESENT + PAGE_WRITABLE) ; 0
@@ -34,7 +47,7 @@ p2_table: ; Map each page entry with 2 MiB huge pages
         %assign pg pg+0x200000
     %endrep
 
-; Global Descriptor Table
+; Global Descriptor Table used to jump into long mode
 gdt64:
     dq 0 ; zero entry
 .code: equ $ - gdt64
@@ -42,9 +55,6 @@ gdt64:
 .pointer:
     dw $ - gdt64 - 1
     dq gdt64
-.pointer_high:
-    dw .pointer - gdt64 - 1
-    dq gdt64 + KERNEL_VMA
 
 ; Initial stack
 bootstrap_stack_bottom:
@@ -69,6 +79,9 @@ start:
     call check_multiboot
     call check_cpuid
     call check_long_mode
+%ifdef LA57
+    call check_la57
+%endif
 
     call enable_paging
 
@@ -136,14 +149,42 @@ check_long_mode:
     mov al, "2"
     jmp error
 
+%ifdef LA57
+; Check if five-level paging is supported, CPUID.(EAX=07H,ECX=0H):ECX.LA57[bit 16]
+check_la57:
+    mov eax, 0             ; highest supported basic leaf
+    cpuid
+    cmp eax, 7             ; it needs to be at least 7 for the extended features
+    jb .no_la57
+
+    mov eax, 7             ; structured extended feature flags
+    mov ecx, 0
+    cpuid
+    test ecx, 1 << 16      ; test if the LA57-bit is set in the C-register
+    jz .no_la57
+    ret
+.no_la57:
+    mov al, "3"
+    jmp error
+%endif
+
 enable_paging:
+%ifdef LA57
+    ; load P5 to to CR3 register
+    mov eax, p5_table
+%else
     ; load P4 to to CR3 register
     mov eax, p4_table
+%endif
     mov cr3, eax
 
     ; enable PAE-flag in cr4 (Physical Address Extension)
     mov eax, cr4
     or eax, 1 << 5
+%ifdef LA57
+    ; LA57 can only be changed while paging is disabled, so it is set together with PAE
+    or eax, 1 << 12
+%endif
     mov cr4, eax
 
     ; set the long mode bit in the EFER MSR
@@ -179,8 +220,10 @@ align 4096
 extern rust_main
 
 long_mode_start:
-    ; Reload the GDT pointer with the correct virtual address
-    lgdt [gdt64.pointer_high + KERNEL_VMA]
+    ; Switch to the higher half GDT, the bootstrap sections are not mapped after the kernel is
+    ; remapped but the CPU still reads the GDT whenever a segment register is loaded, e.g. by
+    ; iretq
+    lgdt [gdt64_high.pointer]
 
     mov ax, 0
     mov ds, ax
@@ -193,7 +236,11 @@ long_mode_start:
     mov rsp, stack_top
 
     ; Unmap the identity map
+%ifdef LA57
+    mov qword [p5_table], 0x0
+%else
     mov qword [p4_table], 0x0
+%endif
     invlpg [0x0]
 
     ; Call into the kernel proper
@@ -202,6 +249,17 @@ long_mode_start:
     ; Halt if we ever return
     hlt
 
+section .rodata
+; Long mode Global Descriptor Table, same layout as gdt64. The table is read-only once the kernel
+; is remapped, so the accessed bit is preset and the CPU never has to write it when loading CS.
+gdt64_high:
+    dq 0 ; zero entry
+.code: equ $ - gdt64_high
+    dq (1<<40) | (1<<43) | (1<<44) | (1<<47) | (1<<53) ; code segment
+.pointer:
+    dw $ - gdt64_high - 1
+    dq gdt64_high
+
 section .bss
 align 4096
 global _guard_page
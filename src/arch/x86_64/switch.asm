@@ -0,0 +1,42 @@
+global switch_context
+global thread_start
+extern thread_main
+
+; Context switching between kernel threads. Everything the System V ABI lets a called function
+; clobber is already saved by the Rust caller, so only the callee-saved registers and the flags
+; are kept on the stack of the thread that is switched away from.
+section .text
+bits 64
+
+; Save the current thread and continue another one
+; Param: rdi - address to store the stack pointer of the current thread at
+; Param: rsi - saved stack pointer of the thread to continue
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+
+    mov [rdi], rsp
+    mov rsp, rsi
+
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+; The first switch to a new thread returns here. The initial stack frame built by Thread::new
+; placed the entry function in rbx and a null frame pointer in rbp, which terminates stack traces.
+thread_start:
+    mov rdi, rbx
+    call thread_main
+
+    ; thread_main exits the thread and never returns
+    ud2
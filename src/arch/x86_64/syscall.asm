@@ -0,0 +1,135 @@
+global syscall_entry
+global int80_entry
+global syscall_kernel_stack
+global syscall_user_code
+global syscall_user_data
+global return_to_user
+extern syscall_dispatch
+
+; System call entry points. The number is passed in rax and the arguments in rdi, rsi, rdx, r10,
+; r8 and r9. The result is returned in rax, every other register keeps its value except rcx and
+; r11, which SYSCALL itself overwrites.
+section .text
+bits 64
+
+; End of user space, see memory/map.rs
+%ifdef LA57
+USER_SPACE_END equ 1 << 56
+%else
+USER_SPACE_END equ 1 << 47
+%endif
+
+; Save the number and the arguments in the layout of the Registers struct in syscall/mod.rs
+%macro SAVE_REGISTERS 0
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+%endmacro
+
+; Restore them, with rax replaced by the result
+%macro RESTORE_REGISTERS 0
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+%endmacro
+
+; Target of SYSCALL. The CPU only loads the kernel code segment, the instruction pointer and
+; masks the flags, so the stack is still the one of user mode. Interrupts stay disabled until the
+; user stack pointer is saved on the kernel stack, the scratch space is shared by all threads.
+syscall_entry:
+    mov [user_stack_pointer], rsp
+    mov rsp, [syscall_kernel_stack]
+
+    ; The user stack pointer, flags and instruction pointer to return with
+    push qword [user_stack_pointer]
+    push r11
+    push rcx
+
+    SAVE_REGISTERS
+
+    ; The stack top is page aligned and ten registers were pushed, so rsp is 16 byte aligned
+    mov rdi, rsp
+    sti
+    call syscall_dispatch
+    cli
+
+    RESTORE_REGISTERS
+
+    ; SYSRET to an address that is not canonical faults in ring 0, but only after loading the user
+    ; stack pointer, so the fault handler would run on a stack user mode controls. Returns to
+    ; anything outside of user space take the iretq path, which faults with the kernel stack.
+    mov rcx, USER_SPACE_END
+    cmp [rsp], rcx
+    jae .return_with_iretq
+
+    pop rcx
+    pop r11
+    pop rsp
+    o64 sysret
+
+.return_with_iretq:
+    pop rcx
+    pop r11
+
+    ; Turn the saved user stack pointer into the frame popped by iretq: ss, rsp, rflags, cs and
+    ; rip. rcx and r11 end up the same as with SYSRET.
+    push qword [rsp]
+    push r11
+    mov r11, [syscall_user_data]
+    mov [rsp + 16], r11
+    push qword [syscall_user_code]
+    push rcx
+    mov r11, [rsp + 16]
+    jmp return_to_user
+
+; Handler of int 0x80, which takes the same registers. The CPU switched to the kernel stack and
+; pushed the return frame, 16 byte aligned, and disabled interrupts.
+int80_entry:
+    ; The Rust code may clobber these as well
+    push rcx
+    push r11
+
+    SAVE_REGISTERS
+
+    ; User mode may have set the alignment check flag, which would allow the kernel to access
+    ; user memory despite SMAP, and the direction flag, which the ABI expects to be clear
+    pushfq
+    and qword [rsp], ~(1 << 18)
+    popfq
+    cld
+
+    ; Five registers pushed by the CPU and nine here keep rsp 16 byte aligned
+    mov rdi, rsp
+    sti
+    call syscall_dispatch
+    cli
+
+    RESTORE_REGISTERS
+
+    pop r11
+    pop rcx
+
+; Faults here are caused by the address user mode returns to, see syscall::faulted_on_return
+return_to_user:
+    iretq
+
+section .bss
+; Top of the kernel stack of the running thread, kept up to date by the scheduler
+syscall_kernel_stack:
+    resq 1
+; Scratch space for the user stack pointer until the kernel stack is set up
+user_stack_pointer:
+    resq 1
+; Segment selectors for returning to user mode with iretq, set by syscall::init
+syscall_user_code:
+    resq 1
+syscall_user_data:
+    resq 1
@@ -0,0 +1,40 @@
+global enter_user_mode
+
+section .text
+bits 64
+
+; Leave the kernel for user mode. The thread only comes back through interrupts and exceptions,
+; which start over at the top of its kernel stack, so nothing on the current stack is needed.
+; Param: rdi - user instruction pointer
+; Param: rsi - user stack pointer
+; Param: rdx - user code segment selector
+; Param: rcx - user data segment selector
+enter_user_mode:
+    ; Frame popped by iretq: ss, rsp, rflags with interrupts enabled, cs and rip
+    push rcx
+    push rsi
+    push 0x202
+    push rdx
+    push rdi
+
+    mov ds, cx
+    mov es, cx
+
+    ; Don't leak kernel values to user mode
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8, r8
+    xor r9, r9
+    xor r10, r10
+    xor r11, r11
+    xor r12, r12
+    xor r13, r13
+    xor r14, r14
+    xor r15, r15
+
+    iretq
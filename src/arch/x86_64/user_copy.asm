@@ -0,0 +1,25 @@
+global copy_user_bytes
+global copy_user_copy
+global copy_user_fixup
+
+section .text
+bits 64
+
+; Copy between kernel and user memory. The range was checked beforehand, but another thread may
+; unmap it until the copy runs. A page fault on the copy that can't be resolved continues at
+; copy_user_fixup, which fails the copy instead of the kernel panicking.
+; Param: rdi - destination
+; Param: rsi - source
+; Param: rdx - number of bytes
+; Return: rax - 0 if everything was copied, otherwise the number of bytes that were not
+copy_user_bytes:
+    mov rcx, rdx
+copy_user_copy:
+    ; rcx counts down the bytes left, so it still holds them if a page fault stops the copy
+    rep movsb
+    xor rax, rax
+    ret
+
+copy_user_fixup:
+    mov rax, rcx
+    ret
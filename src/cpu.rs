@@ -0,0 +1,137 @@
+// Queries for the CPU features the kernel makes use of and access to CR4, which enables them
+
+// CR4 bits. The x86_64 crate drops the bits its Cr4 type does not know, like UMIP, when reading
+// the register, so CR4 is accessed directly.
+pub const CR4_GLOBAL_PAGES: u64 = 1 << 7;
+pub const CR4_UMIP: u64 = 1 << 11;
+pub const CR4_PCID: u64 = 1 << 17;
+pub const CR4_SMEP: u64 = 1 << 20;
+pub const CR4_SMAP: u64 = 1 << 21;
+
+pub fn cr4() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr4, $0" : "=r" (value)) };
+    value
+}
+
+/// Set bits in CR4
+pub unsafe fn cr4_set(bits: u64) {
+    asm!("mov $0, %cr4" :: "r" (cr4() | bits) : "memory");
+}
+
+/// Clear bits in CR4
+pub unsafe fn cr4_clear(bits: u64) {
+    asm!("mov $0, %cr4" :: "r" (cr4() & !bits) : "memory");
+}
+
+// Registers returned by the cpuid instruction
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}" (eax), "={ebx}" (ebx), "={ecx}" (ecx), "={edx}" (edx)
+             : "{eax}" (leaf), "{ecx}" (subleaf)
+             :: "volatile");
+    }
+
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+// Highest basic leaf supported by cpuid
+fn max_leaf() -> u32 {
+    cpuid(0, 0).eax
+}
+
+// Structured extended feature flags, all zero if the leaf does not exist
+fn extended_features() -> CpuidResult {
+    if max_leaf() >= 7 {
+        cpuid(7, 0)
+    } else {
+        CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        }
+    }
+}
+
+/// Process-context identifiers, CPUID.01H:ECX.PCID[bit 17]
+pub fn has_pcid() -> bool {
+    cpuid(1, 0).ecx & (1 << 17) != 0
+}
+
+/// The invpcid instruction, CPUID.(EAX=07H,ECX=0H):EBX.INVPCID[bit 10]
+pub fn has_invpcid() -> bool {
+    extended_features().ebx & (1 << 10) != 0
+}
+
+/// Global pages, CPUID.01H:EDX.PGE[bit 13]
+pub fn has_global_pages() -> bool {
+    cpuid(1, 0).edx & (1 << 13) != 0
+}
+
+/// Supervisor mode execution prevention, CPUID.(EAX=07H,ECX=0H):EBX.SMEP[bit 7]
+pub fn has_smep() -> bool {
+    extended_features().ebx & (1 << 7) != 0
+}
+
+/// Supervisor mode access prevention, CPUID.(EAX=07H,ECX=0H):EBX.SMAP[bit 20]
+pub fn has_smap() -> bool {
+    extended_features().ebx & (1 << 20) != 0
+}
+
+/// User mode instruction prevention, CPUID.(EAX=07H,ECX=0H):ECX.UMIP[bit 2]
+pub fn has_umip() -> bool {
+    extended_features().ecx & (1 << 2) != 0
+}
+
+/// Check if maskable interrupts are enabled, RFLAGS.IF
+pub fn interrupts_enabled() -> bool {
+    use x86_64::registers::flags;
+
+    flags::flags().contains(flags::IF)
+}
+
+/// Allow maskable interrupts
+pub unsafe fn enable_interrupts() {
+    asm!("sti" ::: "memory" : "volatile");
+}
+
+/// Block maskable interrupts
+pub unsafe fn disable_interrupts() {
+    asm!("cli" ::: "memory" : "volatile");
+}
+
+/// Enable interrupts and halt until the next one arrives. sti only takes effect after the next
+/// instruction, so no interrupt can slip in before the hlt and leave it waiting for another.
+pub unsafe fn enable_interrupts_and_halt() {
+    asm!("sti; hlt" ::: "memory" : "volatile");
+}
+
+/// Run f with maskable interrupts disabled, restoring the previous state afterwards
+pub fn without_interrupts<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let enabled = interrupts_enabled();
+    if enabled {
+        unsafe { disable_interrupts() };
+    }
+
+    let result = f();
+
+    if enabled {
+        unsafe { enable_interrupts() };
+    }
+
+    result
+}
@@ -0,0 +1,118 @@
+use super::{EXECUTOR, READY};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Result of polling a future
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Poll<T> {
+    Ready(T),
+    Pending,
+}
+
+/// Computation that completes asynchronously. A future returning Pending has arranged for the
+/// waker to be called once polling it again can make progress.
+pub trait Future {
+    type Output;
+
+    fn poll(&mut self, waker: &Waker) -> Poll<Self::Output>;
+}
+
+/// Asynchronous sequence of values
+pub trait Stream {
+    type Item;
+
+    /// Poll for the next value, which is None once the stream ended
+    fn poll_next(&mut self, waker: &Waker) -> Poll<Option<Self::Item>>;
+
+    /// Future for the next value
+    fn next(&mut self) -> Next<Self>
+    where
+        Self: Sized,
+    {
+        Next { stream: self }
+    }
+}
+
+pub struct Next<'a, S: 'a> {
+    stream: &'a mut S,
+}
+
+impl<'a, S: Stream> Future for Next<'a, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(&mut self, waker: &Waker) -> Poll<Option<S::Item>> {
+        self.stream.poll_next(waker)
+    }
+}
+
+/// Future that calls f whenever it is polled, for writing small state machines inline
+pub fn poll_fn<T, F>(f: F) -> PollFn<F>
+where
+    F: FnMut(&Waker) -> Poll<T>,
+{
+    PollFn { f }
+}
+
+pub struct PollFn<F> {
+    f: F,
+}
+
+impl<T, F> Future for PollFn<F>
+where
+    F: FnMut(&Waker) -> Poll<T>,
+{
+    type Output = T;
+
+    fn poll(&mut self, waker: &Waker) -> Poll<T> {
+        (self.f)(waker)
+    }
+}
+
+/// Handle that makes a task ready to be polled again. Waking sets a bit and wakes the executor
+/// thread, both of which are safe from interrupt handlers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Waker {
+    task: usize,
+}
+
+impl Waker {
+    pub fn new(task: usize) -> Waker {
+        Waker { task }
+    }
+
+    /// Number of the task the waker wakes
+    pub fn task(&self) -> usize {
+        self.task
+    }
+
+    pub fn wake(&self) {
+        READY.fetch_or(1 << self.task, Ordering::Release);
+        EXECUTOR.notify_one();
+    }
+}
+
+/// Slot for the waker of a single task waiting on an event source, like an interrupt handler
+pub struct AtomicWaker {
+    // Task number plus one, 0 if no task is registered
+    task: AtomicUsize,
+}
+
+impl AtomicWaker {
+    pub const fn new() -> AtomicWaker {
+        AtomicWaker {
+            task: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wake this waker on the next event instead of the one registered before
+    pub fn register(&self, waker: &Waker) {
+        self.task.store(waker.task + 1, Ordering::Release);
+    }
+
+    /// Wake the registered task, if any
+    pub fn wake(&self) {
+        let task = self.task.swap(0, Ordering::AcqRel);
+        if task != 0 {
+            Waker::new(task - 1).wake();
+        }
+    }
+}
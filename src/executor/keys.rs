@@ -0,0 +1,70 @@
+use super::{AtomicWaker, Poll, Stream, Waker};
+use sync::IrqSafeMutex;
+
+// Scancodes that arrive while no task reads them are buffered, further ones are dropped
+const BUFFER_SIZE: usize = 128;
+
+struct Scancodes {
+    buffer: [u8; BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+static SCANCODES: IrqSafeMutex<Scancodes> = IrqSafeMutex::new(Scancodes {
+    buffer: [0; BUFFER_SIZE],
+    start: 0,
+    len: 0,
+});
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Queue a scancode for the key stream. Called by the work the keyboard interrupt defers.
+pub fn push_scancode(scancode: u8) {
+    {
+        let mut scancodes = SCANCODES.lock();
+        if scancodes.len == BUFFER_SIZE {
+            return;
+        }
+
+        let end = (scancodes.start + scancodes.len) % BUFFER_SIZE;
+        scancodes.buffer[end] = scancode;
+        scancodes.len += 1;
+    }
+
+    WAKER.wake();
+}
+
+fn pop_scancode() -> Option<u8> {
+    let mut scancodes = SCANCODES.lock();
+    if scancodes.len == 0 {
+        return None;
+    }
+
+    let scancode = scancodes.buffer[scancodes.start];
+    scancodes.start = (scancodes.start + 1) % BUFFER_SIZE;
+    scancodes.len -= 1;
+    Some(scancode)
+}
+
+/// Stream of raw scancodes from the keyboard. Only one task should read keys at a time, a second
+/// stream would take scancodes from the first one.
+pub fn keys() -> KeyStream {
+    KeyStream { _private: () }
+}
+
+pub struct KeyStream {
+    _private: (),
+}
+
+impl Stream for KeyStream {
+    type Item = u8;
+
+    fn poll_next(&mut self, waker: &Waker) -> Poll<Option<u8>> {
+        // Registering first, so a scancode pushed after the check still wakes the task
+        WAKER.register(waker);
+        match pop_scancode() {
+            Some(scancode) => Poll::Ready(Some(scancode)),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Executor for cooperative kernel tasks written as futures. Tasks run on a single executor
+// thread, which sleeps while none of them is ready.
+
+mod future;
+pub mod keys;
+pub mod timer;
+
+pub use self::future::{poll_fn, AtomicWaker, Future, Next, Poll, PollFn, Stream, Waker};
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use memory::slab;
+use sync::{IrqSafeMutex, WaitQueue};
+use task::{self, ThreadId};
+
+// Maximum number of tasks, one bit each in READY
+const MAX_TASKS: usize = 64;
+
+// Objects from kmalloc are aligned to this
+const KMALLOC_ALIGN: usize = 16;
+
+// Tasks that were woken and have to be polled
+static READY: AtomicUsize = AtomicUsize::new(0);
+
+// The executor thread sleeps here until a task is woken
+static EXECUTOR: WaitQueue = WaitQueue::new();
+
+static TASKS: IrqSafeMutex<Tasks> = IrqSafeMutex::new(Tasks {
+    slots: [Slot::Free; MAX_TASKS],
+});
+
+#[derive(Clone, Copy)]
+enum Slot {
+    Free,
+    // Spawned and not being polled. The future lives in memory from kmalloc.
+    Idle(*mut Future<Output = ()>),
+    // Taken out by the executor while it is polled
+    Polling,
+}
+
+struct Tasks {
+    slots: [Slot; MAX_TASKS],
+}
+
+// The futures are only accessed by the executor thread
+unsafe impl Send for Tasks {}
+
+/// Add a task to the executor. It is polled for the first time once the executor runs. Returns
+/// None if there are too many tasks or the future does not fit into a kmalloc object or needs
+/// a larger alignment than it has.
+pub fn spawn<F>(future: F) -> Option<usize>
+where
+    F: Future<Output = ()> + 'static,
+{
+    if mem::align_of::<F>() > KMALLOC_ALIGN {
+        return None;
+    }
+
+    let memory = match slab::kmalloc(mem::size_of::<F>()) {
+        Some(memory) => memory as *mut F,
+        None => return None,
+    };
+    unsafe { ptr::write(memory, future) };
+
+    let task = {
+        let mut tasks = TASKS.lock();
+        let free_slot = tasks.slots.iter().position(|slot| match *slot {
+            Slot::Free => true,
+            _ => false,
+        });
+
+        match free_slot {
+            Some(task) => {
+                tasks.slots[task] = Slot::Idle(memory);
+                Some(task)
+            }
+            None => None,
+        }
+    };
+
+    match task {
+        Some(task) => Waker::new(task).wake(),
+        None => free(memory),
+    }
+    task
+}
+
+/// Start the executor on a thread of its own
+pub fn start() -> Option<ThreadId> {
+    task::spawn(run)
+}
+
+// Poll the ready tasks forever
+fn run() {
+    loop {
+        let ready = READY.swap(0, Ordering::Acquire);
+        if ready == 0 {
+            // Checked with interrupts disabled, so a wakeup by an interrupt handler can't be missed
+            EXECUTOR.wait_unless(|| READY.load(Ordering::Acquire) != 0, None);
+            continue;
+        }
+
+        for task in 0..MAX_TASKS {
+            if ready & (1 << task) != 0 {
+                poll(task);
+            }
+        }
+    }
+}
+
+fn poll(task: usize) {
+    let future = {
+        let mut tasks = TASKS.lock();
+        let slot = tasks.slots[task];
+        match slot {
+            Slot::Idle(future) => {
+                tasks.slots[task] = Slot::Polling;
+                future
+            }
+            // Woken by a stale waker of a task that completed already
+            _ => return,
+        }
+    };
+
+    // Polled without the lock, so the task can spawn others
+    let finished = match unsafe { (*future).poll(&Waker::new(task)) } {
+        Poll::Ready(()) => true,
+        Poll::Pending => false,
+    };
+
+    if finished {
+        free(future);
+    }
+    TASKS.lock().slots[task] = if finished {
+        Slot::Free
+    } else {
+        Slot::Idle(future)
+    };
+}
+
+fn free(future: *mut Future<Output = ()>) {
+    unsafe {
+        ptr::drop_in_place(future);
+        slab::kfree(future as *mut u8);
+    }
+}
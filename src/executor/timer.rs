@@ -0,0 +1,51 @@
+use super::{Future, Poll, Waker};
+use interrupts::timer;
+use time::{self, TimerId};
+
+/// Future that completes once the given number of milliseconds passed
+pub fn sleep(ms: usize) -> Sleep {
+    Sleep {
+        deadline: time::deadline(ms),
+        timer: None,
+    }
+}
+
+pub struct Sleep {
+    // Timer tick to complete at
+    deadline: usize,
+    // Wakes the task once the deadline passed
+    timer: Option<TimerId>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(&mut self, waker: &Waker) -> Poll<()> {
+        if timer::ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        if self.timer.is_none() {
+            self.timer = time::add_timer_at(self.deadline, wake_task, waker.task());
+        }
+
+        // No timer left, so check again on the next run of the executor
+        if self.timer.is_none() {
+            waker.wake();
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            time::cancel(timer);
+        }
+    }
+}
+
+fn wake_task(task: usize) {
+    Waker::new(task).wake();
+}
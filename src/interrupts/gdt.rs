@@ -0,0 +1,124 @@
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::PrivilegeLevel;
+
+// Global Descriptor Table. In long mode it only needs code and data segments for the kernel and
+// user mode plus the TSS.
+pub struct Gdt {
+    table: [u64; 8],
+    next_free: usize,
+}
+
+impl Gdt {
+    pub fn new() -> Gdt {
+        Gdt {
+            table: [0; 8],
+            // Entry 0 is always the null descriptor
+            next_free: 1,
+        }
+    }
+
+    // Add a descriptor and return its selector, which requests the privilege level of the
+    // descriptor
+    pub fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
+        let (index, privilege_level) = match entry {
+            Descriptor::UserSegment(value) => {
+                let privilege_level = ((value & DescriptorFlags::DPL_RING_3.bits()) >> 45) as u16;
+                (self.push(value), privilege_level)
+            }
+            Descriptor::SystemSegment(value_low, value_high) => {
+                let index = self.push(value_low);
+                self.push(value_high);
+                (index, 0)
+            }
+        };
+
+        SegmentSelector::new(index as u16, PrivilegeLevel::from_u16(privilege_level))
+    }
+
+    fn push(&mut self, value: u64) -> usize {
+        if self.next_free < self.table.len() {
+            let index = self.next_free;
+            self.table[index] = value;
+            self.next_free += 1;
+            index
+        } else {
+            panic!("GDT full");
+        }
+    }
+
+    pub fn load(&'static self) {
+        use core::mem::size_of;
+        use x86_64::instructions::tables::{lgdt, DescriptorTablePointer};
+
+        let ptr = DescriptorTablePointer {
+            base: self.table.as_ptr() as u64,
+            limit: (self.table.len() * size_of::<u64>() - 1) as u16,
+        };
+
+        unsafe { lgdt(&ptr) };
+    }
+}
+
+pub enum Descriptor {
+    UserSegment(u64),
+    SystemSegment(u64, u64),
+}
+
+impl Descriptor {
+    pub fn kernel_code_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT
+            | DescriptorFlags::EXECUTABLE | DescriptorFlags::LONG_MODE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    // Only loaded into the stack segment register, the CPU ignores the other data segment fields
+    pub fn kernel_data_segment() -> Descriptor {
+        let flags =
+            DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT | DescriptorFlags::WRITABLE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_code_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT
+            | DescriptorFlags::EXECUTABLE | DescriptorFlags::LONG_MODE
+            | DescriptorFlags::DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_data_segment() -> Descriptor {
+        let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT
+            | DescriptorFlags::WRITABLE | DescriptorFlags::DPL_RING_3;
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
+        use core::mem::size_of;
+
+        let ptr = tss as *const _ as u64;
+
+        // Limit in bits 0-15, base in bits 16-39 and 56-63, type 0b1001 (available 64-bit TSS)
+        let mut low = DescriptorFlags::PRESENT.bits();
+        low |= (size_of::<TaskStateSegment>() - 1) as u64;
+        low |= (ptr & 0xff_ffff) << 16;
+        low |= 0b1001 << 40;
+        low |= ((ptr >> 24) & 0xff) << 56;
+
+        // Upper 32 bits of the base
+        let high = ptr >> 32;
+
+        Descriptor::SystemSegment(low, high)
+    }
+}
+
+bitflags! {
+    struct DescriptorFlags: u64 {
+        const WRITABLE     = 1 << 41;
+        const EXECUTABLE   = 1 << 43;
+        const USER_SEGMENT = 1 << 44;
+        // Descriptor privilege level in bits 45-46
+        const DPL_RING_3   = 3 << 45;
+        const PRESENT      = 1 << 47;
+        const LONG_MODE    = 1 << 53;
+    }
+}
@@ -0,0 +1,18 @@
+use super::pic;
+use x86_64::instructions::port::inb;
+
+/// IRQ of the PS/2 keyboard
+pub const KEYBOARD_IRQ: u8 = 1;
+
+const DATA_PORT: u16 = 0x60;
+
+/// Start delivering keyboard interrupts
+pub fn init() {
+    pic::unmask(KEYBOARD_IRQ);
+}
+
+/// Read the scancode that raised the interrupt. The keyboard sends no further interrupts until
+/// it is read.
+pub fn read_scancode() -> u8 {
+    unsafe { inb(DATA_PORT) }
+}
@@ -0,0 +1,368 @@
+mod gdt;
+mod keyboard;
+mod pic;
+pub mod timer;
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cpu;
+use executor;
+use memory;
+use memory::map::USER_SPACE_END;
+use spin::Once;
+use syscall;
+use task;
+use time;
+use work::{self, Priority};
+use x86_64::structures::idt::{ExceptionStackFrame, Idt, PageFaultErrorCode};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::{PrivilegeLevel, VirtualAddress};
+
+// Interrupt stack table slots. Page faults get their own stack so that a kernel stack overflow
+// can still be reported instead of escalating into a triple fault.
+const DOUBLE_FAULT_IST_INDEX: usize = 0;
+const PAGE_FAULT_IST_INDEX: usize = 1;
+
+ro_after_init! {
+    static IDT: Once<Idt> = Once::new();
+}
+static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
+static GDT: Once<gdt::Gdt> = Once::new();
+static SELECTORS: Once<Selectors> = Once::new();
+
+// The CPU reads the stack for interrupts from user mode out of the TSS, which changes with every
+// thread switch. Only written with interrupts disabled.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+/// Segment selectors of the GDT
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: u16,
+    pub kernel_data: u16,
+    pub user_code: u16,
+    pub user_data: u16,
+}
+
+extern "C" {
+    fn enter_user_mode(
+        instruction_pointer: usize,
+        stack_pointer: usize,
+        code_selector: u64,
+        data_selector: u64,
+    ) -> !;
+}
+
+// Number of interrupt handlers currently running, more than one if they nest
+static HANDLER_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the CPU is executing an interrupt handler, which must not sleep
+pub fn in_interrupt() -> bool {
+    HANDLER_DEPTH.load(Ordering::Relaxed) > 0
+}
+
+// Marks an interrupt handler as running until it is dropped
+struct HandlerGuard;
+
+impl HandlerGuard {
+    fn new() -> HandlerGuard {
+        HANDLER_DEPTH.fetch_add(1, Ordering::Relaxed);
+        HandlerGuard
+    }
+}
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        HANDLER_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Run the deferred work of the handlers once the outermost one is done. Interrupts are enabled
+// meanwhile, but it still counts as interrupt context, so nothing sleeps or preempts it and the
+// handlers of nested interrupts leave the work to it.
+fn return_from_interrupt() {
+    if in_interrupt() {
+        return;
+    }
+
+    let _guard = HandlerGuard::new();
+    unsafe { cpu::enable_interrupts() };
+    work::run_on_interrupt_return();
+    unsafe { cpu::disable_interrupts() };
+}
+
+pub fn init() {
+    use x86_64::instructions::segmentation::{load_ss, set_cs};
+    use x86_64::instructions::tables::load_tss;
+    use x86_64::structures::gdt::SegmentSelector;
+
+    let double_fault_stack =
+        memory::alloc_stack(1, "double fault").expect("Could not allocate double fault stack");
+    let page_fault_stack =
+        memory::alloc_stack(1, "page fault").expect("Could not allocate page fault stack");
+
+    // Nothing else uses the TSS before it is loaded
+    let tss = unsafe {
+        let tss = TSS.0.get();
+        (*tss).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] =
+            VirtualAddress(double_fault_stack.top());
+        (*tss).interrupt_stack_table[PAGE_FAULT_IST_INDEX] =
+            VirtualAddress(page_fault_stack.top());
+        &*tss
+    };
+
+    // The order of the segments is the one SYSCALL and SYSRET expect: kernel code, kernel data,
+    // then user data and user code
+    let mut code_selector = SegmentSelector(0);
+    let mut data_selector = SegmentSelector(0);
+    let mut user_data_selector = SegmentSelector(0);
+    let mut user_code_selector = SegmentSelector(0);
+    let mut tss_selector = SegmentSelector(0);
+    let gdt = GDT.call_once(|| {
+        let mut gdt = gdt::Gdt::new();
+        code_selector = gdt.add_entry(gdt::Descriptor::kernel_code_segment());
+        data_selector = gdt.add_entry(gdt::Descriptor::kernel_data_segment());
+        user_data_selector = gdt.add_entry(gdt::Descriptor::user_data_segment());
+        user_code_selector = gdt.add_entry(gdt::Descriptor::user_code_segment());
+        tss_selector = gdt.add_entry(gdt::Descriptor::tss_segment(tss));
+        gdt
+    });
+    gdt.load();
+
+    SELECTORS.call_once(|| Selectors {
+        kernel_code: code_selector.0,
+        kernel_data: data_selector.0,
+        user_code: user_code_selector.0,
+        user_data: user_data_selector.0,
+    });
+
+    unsafe {
+        // Reload the code and stack segment registers and load the TSS. Returning from an
+        // interrupt loads the stack segment again, which has to be valid in the new GDT.
+        set_cs(code_selector);
+        load_ss(data_selector);
+        load_tss(tss_selector);
+    }
+
+    let idt = IDT.call_once(|| {
+        let mut idt = Idt::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(PAGE_FAULT_IST_INDEX as u16);
+        }
+        idt.divide_by_zero.set_handler_fn(divide_by_zero_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        // User mode may raise the system call interrupt itself
+        idt[syscall::INT80_VECTOR]
+            .set_handler_fn(syscall::int80_handler())
+            .set_privilege_level(PrivilegeLevel::Ring3);
+        idt[pic::vector(timer::TIMER_IRQ)].set_handler_fn(timer_handler);
+        idt[pic::vector(keyboard::KEYBOARD_IRQ)].set_handler_fn(keyboard_handler);
+        idt
+    });
+
+    idt.load();
+
+    pic::init();
+    timer::init();
+    keyboard::init();
+}
+
+pub fn selectors() -> Selectors {
+    *SELECTORS.try().expect("Interrupts are not initialized")
+}
+
+/// Set the stack the CPU switches to when an interrupt, exception or system call arrives in user
+/// mode. Called with interrupts disabled whenever another thread starts running.
+pub fn set_kernel_stack(top: usize) {
+    unsafe { (*TSS.0.get()).privilege_stack_table[0] = VirtualAddress(top) };
+    syscall::set_kernel_stack(top);
+}
+
+/// Continue the current thread in user mode at entry, with the given user stack. The thread only
+/// comes back into the kernel through interrupts and exceptions, which start on the top of its
+/// kernel stack and overwrite whatever it had on it.
+pub fn enter_user(entry: usize, stack_pointer: usize) -> ! {
+    assert!(
+        entry < USER_SPACE_END && stack_pointer <= USER_SPACE_END,
+        "User mode entry in kernel space"
+    );
+
+    let selectors = selectors();
+    unsafe {
+        // Returning to user mode enables interrupts again
+        cpu::disable_interrupts();
+        enter_user_mode(
+            entry,
+            stack_pointer,
+            selectors.user_code as u64,
+            selectors.user_data as u64,
+        )
+    }
+}
+
+// Whether the exception interrupted user mode, where the code segment requests ring 3
+fn from_user_mode(stack_frame: &ExceptionStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+// End the thread whose user code caused an exception. Nothing the kernel relies on is broken, so
+// the other threads keep running.
+fn kill_user_thread(stack_frame: &ExceptionStackFrame, reason: fmt::Arguments) -> ! {
+    println!(
+        "Thread {} killed by {} at 0x{:x}",
+        task::current(),
+        reason,
+        stack_frame.instruction_pointer.0
+    );
+    task::exit();
+}
+
+// Exception that user code may cause, e.g. by dividing by zero or executing a privileged
+// instruction. Only the thread is terminated if it happened in user mode, in the kernel it is a
+// bug.
+fn exception(name: &str, stack_frame: &ExceptionStackFrame, error_code: Option<u64>) -> ! {
+    let instruction_pointer = stack_frame.instruction_pointer.0;
+    if from_user_mode(stack_frame) || syscall::faulted_on_return(instruction_pointer) {
+        kill_user_thread(stack_frame, format_args!("{}", name));
+    }
+
+    match error_code {
+        Some(error_code) => panic!(
+            "EXCEPTION: {}, error code 0x{:x}\n{:#?}",
+            name,
+            error_code,
+            stack_frame
+        ),
+        None => panic!("EXCEPTION: {}\n{:#?}", name, stack_frame),
+    }
+}
+
+macro_rules! exception_handler {
+    ($handler:ident, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut ExceptionStackFrame) {
+            exception($name, stack_frame, None);
+        }
+    };
+    ($handler:ident, $name:expr, error_code) => {
+        extern "x86-interrupt" fn $handler(
+            stack_frame: &mut ExceptionStackFrame,
+            error_code: u64,
+        ) {
+            exception($name, stack_frame, Some(error_code));
+        }
+    };
+}
+
+exception_handler!(divide_by_zero_handler, "DIVIDE BY ZERO");
+exception_handler!(invalid_opcode_handler, "INVALID OPCODE");
+exception_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE");
+exception_handler!(x87_floating_point_handler, "X87 FLOATING POINT");
+exception_handler!(simd_floating_point_handler, "SIMD FLOATING POINT");
+exception_handler!(segment_not_present_handler, "SEGMENT NOT PRESENT", error_code);
+exception_handler!(stack_segment_fault_handler, "STACK SEGMENT FAULT", error_code);
+exception_handler!(general_protection_fault_handler, "GENERAL PROTECTION FAULT", error_code);
+exception_handler!(alignment_check_handler, "ALIGNMENT CHECK", error_code);
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: &mut ExceptionStackFrame,
+    _error_code: u64,
+) {
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut ExceptionStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control_regs;
+
+    let address = control_regs::cr2().0;
+
+    // Not an error if the page was only reserved and is now backed on demand
+    let resolved = {
+        let _guard = HandlerGuard::new();
+        memory::handle_page_fault(address, error_code)
+    };
+    if resolved {
+        return;
+    }
+
+    // A copy between kernel and user memory whose range was unmapped fails instead
+    if let Some(fixup) = memory::copy_fault_fixup(stack_frame.instruction_pointer.0) {
+        stack_frame.instruction_pointer = VirtualAddress(fixup);
+        return;
+    }
+
+    // User code touching memory it has no access to only ends its thread
+    if from_user_mode(stack_frame) {
+        kill_user_thread(
+            stack_frame,
+            format_args!("PAGE FAULT at 0x{:x} {:?}", address, error_code),
+        );
+    }
+
+    if let Some(stack) = memory::overflowed_stack(address) {
+        panic!(
+            "EXCEPTION: STACK OVERFLOW of the {} stack at 0x{:x}\n{:#?}",
+            stack,
+            address,
+            stack_frame
+        );
+    }
+
+    panic!(
+        "EXCEPTION: PAGE FAULT at 0x{:x}\n{:?}\n{:?}\n{:#?}",
+        address,
+        error_code,
+        memory::translate_detailed(address),
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn timer_handler(_stack_frame: &mut ExceptionStackFrame) {
+    {
+        let _guard = HandlerGuard::new();
+        timer::tick();
+        time::run_expired(timer::ticks());
+
+        // Acknowledge before possibly switching threads, the next one might not return here for
+        // a while and would miss every timer interrupt until then
+        pic::end_of_interrupt(timer::TIMER_IRQ);
+    }
+
+    return_from_interrupt();
+
+    // Preemption switches to another thread, which is not running in the handler. The
+    // interrupted thread finishes the handler once it is switched back to.
+    task::tick();
+}
+
+extern "x86-interrupt" fn keyboard_handler(_stack_frame: &mut ExceptionStackFrame) {
+    {
+        let _guard = HandlerGuard::new();
+        let scancode = keyboard::read_scancode();
+        work::queue(queue_scancode, scancode as usize, Priority::High);
+        pic::end_of_interrupt(keyboard::KEYBOARD_IRQ);
+    }
+
+    return_from_interrupt();
+}
+
+// Hand a scancode to the key stream, deferred from the keyboard interrupt
+fn queue_scancode(scancode: usize) {
+    executor::keys::push_scancode(scancode as u8);
+}
@@ -0,0 +1,78 @@
+use x86_64::instructions::port::{inb, outb};
+
+// The two cascaded 8259 programmable interrupt controllers. Their IRQs are moved above the
+// exception vectors, which they overlap by default.
+pub const PIC1_OFFSET: u8 = 32;
+pub const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+// Start initialization, ICW4 follows
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+// IRQ line the second PIC is cascaded to
+const CASCADE_IRQ: u8 = 2;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// Remap both controllers and mask every IRQ until a driver unmasks its own
+pub fn init() {
+    unsafe {
+        outb(PIC1_COMMAND, ICW1_INIT);
+        wait();
+        outb(PIC2_COMMAND, ICW1_INIT);
+        wait();
+        outb(PIC1_DATA, PIC1_OFFSET);
+        wait();
+        outb(PIC2_DATA, PIC2_OFFSET);
+        wait();
+        outb(PIC1_DATA, 1 << CASCADE_IRQ);
+        wait();
+        outb(PIC2_DATA, CASCADE_IRQ);
+        wait();
+        outb(PIC1_DATA, ICW4_8086);
+        wait();
+        outb(PIC2_DATA, ICW4_8086);
+        wait();
+
+        // Only the cascade stays unmasked, so IRQs of the second controller can be enabled
+        outb(PIC1_DATA, !(1 << CASCADE_IRQ));
+        outb(PIC2_DATA, 0xff);
+    }
+}
+
+/// Vector of an IRQ in the IDT
+pub fn vector(irq: u8) -> usize {
+    (PIC1_OFFSET + irq) as usize
+}
+
+/// Let an IRQ through to the CPU
+pub fn unmask(irq: u8) {
+    let (port, line) = if irq < 8 {
+        (PIC1_DATA, irq)
+    } else {
+        (PIC2_DATA, irq - 8)
+    };
+
+    unsafe {
+        let mask = inb(port);
+        outb(port, mask & !(1 << line));
+    }
+}
+
+/// Acknowledge an IRQ so that the controller delivers the next one
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(PIC2_COMMAND, END_OF_INTERRUPT);
+        }
+        outb(PIC1_COMMAND, END_OF_INTERRUPT);
+    }
+}
+
+// Give the controllers time to process a command by writing to an unused port
+fn wait() {
+    unsafe { outb(0x80, 0) };
+}
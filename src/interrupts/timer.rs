@@ -0,0 +1,54 @@
+use super::pic;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::outb;
+
+/// IRQ of channel 0 of the programmable interval timer
+pub const TIMER_IRQ: u8 = 0;
+
+/// Timer interrupts per second
+pub const TICKS_PER_SECOND: usize = 100;
+
+// Input clock of the PIT in Hz
+const PIT_FREQUENCY: usize = 1_193_182;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+// Channel 0, low byte then high byte of the divisor, rate generator
+const RATE_GENERATOR: u8 = 0x34;
+
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Start the periodic timer interrupt
+pub fn init() {
+    let divisor = PIT_FREQUENCY / TICKS_PER_SECOND;
+    assert!(divisor <= 0xffff, "Timer frequency is too low");
+
+    unsafe {
+        outb(COMMAND, RATE_GENERATOR);
+        outb(CHANNEL0_DATA, divisor as u8);
+        outb(CHANNEL0_DATA, (divisor >> 8) as u8);
+    }
+
+    pic::unmask(TIMER_IRQ);
+}
+
+/// Timer interrupts since boot
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Convert a number of ticks to milliseconds
+pub fn ticks_to_ms(ticks: usize) -> usize {
+    ticks * 1000 / TICKS_PER_SECOND
+}
+
+/// Convert milliseconds to a number of ticks, rounding up so that waiting for them takes at least
+/// as long
+pub fn ms_to_ticks(ms: usize) -> usize {
+    (ms * TICKS_PER_SECOND + 999) / 1000
+}
+
+// Count a timer interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
@@ -2,11 +2,18 @@
 #![feature(const_fn)]
 #![feature(unique)]
 #![feature(asm)]
-#![no_std]
+#![feature(abi_x86_interrupt)]
+#![cfg_attr(not(test), no_std)]
+// Only paging, frame reuse, the timer wheel, the lock states, the scheduling policies and the ELF
+// loader are exercised by the host tests
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 #[macro_use]
 extern crate bitflags;
+#[cfg(test)]
+extern crate core;
 extern crate multiboot2;
+#[cfg(not(test))]
 extern crate rlibc;
 extern crate spin;
 extern crate volatile;
@@ -14,25 +21,99 @@ extern crate x86_64;
 
 #[macro_use]
 mod vga_buffer;
+mod cpu;
+#[macro_use]
 mod memory;
+mod interrupts;
+mod executor;
+mod loader;
+mod sync;
+mod syscall;
+mod task;
+mod time;
+mod work;
 
+use executor::keys::{keys, KeyStream};
+use executor::{Future, Poll, Stream, Waker};
 use memory::map::KERNEL_VMA;
 use multiboot2::BootInformation;
+use task::PolicyKind;
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn rust_main(multiboot_info_addr: usize) {
     let boot_info = unsafe { BootInformation::load(multiboot_info_addr, KERNEL_VMA) };
 
     memory::init(&boot_info);
+    interrupts::init();
+    syscall::init();
+
+    // Everything that is written only during boot has been set up by now
+    memory::protect_kernel(&boot_info);
+
+    task::init();
+
+    // The boot flow is a thread now, so the timer may start preempting it
+    unsafe { cpu::enable_interrupts() };
+
+    task::start_reaper();
+    work::init();
+    executor::start().expect("Could not start the executor");
+    executor::spawn(DebugKeys { keys: keys() }).expect("Could not start the debug keys task");
 
     println!("Hello world");
 
-    loop {}
+    // Every module GRUB loaded is a user program
+    loader::init(&boot_info);
+    loader::spawn_all();
+
+    // The idle thread takes over once nothing else is left to run
+    task::exit()
 }
 
+// Task that reacts to the function keys: F1 prints the threads, F2 to F4 switch to round robin,
+// priority and fair scheduling
+struct DebugKeys {
+    keys: KeyStream,
+}
+
+impl Future for DebugKeys {
+    type Output = ();
+
+    fn poll(&mut self, waker: &Waker) -> Poll<()> {
+        loop {
+            let scancode = match self.keys.poll_next(waker) {
+                Poll::Ready(Some(scancode)) => scancode,
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let policy = match scancode {
+                0x3b => {
+                    task::print_threads();
+                    continue;
+                }
+                0x3c => PolicyKind::RoundRobin,
+                0x3d => PolicyKind::Priority,
+                0x3e => PolicyKind::Fair,
+                _ => continue,
+            };
+            task::set_policy(policy);
+            println!("Scheduling policy: {:?}", policy);
+        }
+    }
+}
+
+#[cfg(not(test))]
 #[lang = "panic_fmt"]
 #[no_mangle]
 pub extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
+    unsafe {
+        // Nothing else runs anymore, and the code that panicked may have held the writer
+        cpu::disable_interrupts();
+        vga_buffer::WRITER.force_unlock();
+    }
+
     println!("\n\nPanic in {} at line {}:", file, line);
     println!("    {}", fmt);
     loop {}
@@ -0,0 +1,227 @@
+// Parsing and validation of ELF64 executables. Only what is needed to load a statically linked
+// x86_64 program is supported, there is no dynamic linking and no relocation.
+
+use memory::map::USER_MAP_END;
+use memory::VirtualAddress;
+
+/// Size of the ELF header
+pub const HEADER_SIZE: usize = 64;
+/// Size of a program header
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+
+// More program headers than this are rejected
+const MAX_PROGRAM_HEADERS: usize = 64;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u32 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+
+/// Program header type of a segment that is loaded into memory
+pub const PT_LOAD: u32 = 1;
+/// Program header type of the entry describing the program headers themselves
+pub const PT_PHDR: u32 = 6;
+
+// Bit of the p_flags of an executable segment
+const PF_X: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    // The file is shorter than the headers it describes
+    Truncated,
+    // Not an ELF file at all
+    BadMagic,
+    // Not a 64 bit little endian file of the current version
+    UnsupportedFormat,
+    // Not an executable, e.g. a shared object or a relocatable object file
+    NotExecutable,
+    // Built for another architecture
+    WrongMachine,
+    // The program headers are missing, too many or have an unexpected size
+    BadProgramHeaders,
+    // A segment does not fit into the file or into user space
+    BadSegment,
+    // The entry point does not lie in an executable segment
+    BadEntry,
+}
+
+/// The parts of the ELF header needed for loading
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    entry: VirtualAddress,
+    program_header_offset: usize,
+    program_header_count: usize,
+}
+
+impl Header {
+    /// Parse and validate the header of a file of the given size
+    pub fn parse(bytes: &[u8], file_size: usize) -> Result<Header, ElfError> {
+        if bytes.len() < HEADER_SIZE || file_size < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if bytes[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if bytes[4] != CLASS_64 || bytes[5] != LITTLE_ENDIAN || bytes[6] != VERSION_CURRENT as u8
+            || read_u32(bytes, 20) != VERSION_CURRENT
+        {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if read_u16(bytes, 16) != TYPE_EXECUTABLE {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(bytes, 18) != MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let header = Header {
+            entry: read_u64(bytes, 24) as usize,
+            program_header_offset: read_u64(bytes, 32) as usize,
+            program_header_count: read_u16(bytes, 56) as usize,
+        };
+
+        let entry_size = read_u16(bytes, 54) as usize;
+        if entry_size != PROGRAM_HEADER_SIZE || header.program_header_count == 0
+            || header.program_header_count > MAX_PROGRAM_HEADERS
+        {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        let table_size = header.program_header_count * PROGRAM_HEADER_SIZE;
+        match header.program_header_offset.checked_add(table_size) {
+            Some(end) if end <= file_size => Ok(header),
+            _ => Err(ElfError::Truncated),
+        }
+    }
+
+    pub fn entry(&self) -> VirtualAddress {
+        self.entry
+    }
+
+    pub fn program_header_count(&self) -> usize {
+        self.program_header_count
+    }
+
+    /// Offset of the program header with the given index in the file
+    pub fn program_header_offset(&self, index: usize) -> usize {
+        assert!(index < self.program_header_count, "No such program header");
+        self.program_header_offset + index * PROGRAM_HEADER_SIZE
+    }
+}
+
+/// A program header, describing a segment of the program
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    virtual_address: VirtualAddress,
+    file_size: usize,
+    memory_size: usize,
+    align: usize,
+}
+
+impl ProgramHeader {
+    /// Parse a program header. Loadable segments still have to be validated before use.
+    pub fn parse(bytes: &[u8]) -> Result<ProgramHeader, ElfError> {
+        if bytes.len() < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+
+        Ok(ProgramHeader {
+            kind: read_u32(bytes, 0),
+            flags: read_u32(bytes, 4),
+            offset: read_u64(bytes, 8) as usize,
+            virtual_address: read_u64(bytes, 16) as usize,
+            file_size: read_u64(bytes, 32) as usize,
+            memory_size: read_u64(bytes, 40) as usize,
+            align: read_u64(bytes, 48) as usize,
+        })
+    }
+
+    /// Check that a loadable segment of a file of the given size can be loaded into user space.
+    /// Its file contents have to lie inside the file and its memory below USER_MAP_END.
+    pub fn validate(&self, file_size: usize) -> Result<(), ElfError> {
+        if self.file_size > self.memory_size {
+            return Err(ElfError::BadSegment);
+        }
+
+        match self.offset.checked_add(self.file_size) {
+            Some(end) if end <= file_size => {}
+            _ => return Err(ElfError::Truncated),
+        }
+
+        match self.virtual_address.checked_add(self.memory_size) {
+            Some(end) if end <= USER_MAP_END => {}
+            _ => return Err(ElfError::BadSegment),
+        }
+
+        // The file offset has to be congruent to the address modulo the alignment, so that pages
+        // of the file line up with the pages of memory
+        if self.align > 1
+            && (!self.align.is_power_of_two()
+                || self.offset % self.align != self.virtual_address % self.align)
+        {
+            return Err(ElfError::BadSegment);
+        }
+
+        Ok(())
+    }
+
+    pub fn kind(&self) -> u32 {
+        self.kind
+    }
+
+    /// The p_flags, see EntryFlags::from_elf_segment
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn virtual_address(&self) -> VirtualAddress {
+        self.virtual_address
+    }
+
+    pub fn file_size(&self) -> usize {
+        self.file_size
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    /// Whether the segment covers the address in memory
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.virtual_address && address - self.virtual_address < self.memory_size
+    }
+
+    /// Where the byte at the offset of the file ends up in memory, if the segment loads it
+    pub fn address_of_offset(&self, offset: usize) -> Option<VirtualAddress> {
+        if offset >= self.offset && offset - self.offset < self.file_size {
+            Some(self.virtual_address + (offset - self.offset))
+        } else {
+            None
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
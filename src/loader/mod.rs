@@ -0,0 +1,251 @@
+// Loader for user programs. Every module on a module2 line of the GRUB configuration is an ELF
+// executable, named by the first word of its command line. The remaining words become its
+// arguments.
+
+mod elf;
+mod stack;
+#[cfg(test)]
+mod tests;
+
+pub use self::elf::ElfError;
+use self::elf::{Header, ProgramHeader, HEADER_SIZE, PROGRAM_HEADER_SIZE, PT_LOAD, PT_PHDR};
+use core::cmp;
+use memory::{self, AddressSpace, EntryFlags, PhysicalAddress, VirtualAddress, PAGE_SIZE};
+use multiboot2::BootInformation;
+use spin::Mutex;
+use task::{self, ThreadId};
+
+// Modules that are registered at most
+const MAX_PROGRAMS: usize = 16;
+
+// The user stack ends below this address, which lies in user space with both four and five
+// level paging
+const USER_STACK_TOP: VirtualAddress = 0x0000_7fff_ffff_f000;
+const USER_STACK_PAGES: usize = 16;
+
+// Bytes at the top of the stack for the arguments and the auxiliary vector
+const ARGUMENT_SPACE: usize = 1024;
+
+// Segments are copied through a buffer of this size
+const COPY_CHUNK: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    // There is no module with the name
+    NoSuchProgram,
+    // The module is not a valid executable
+    InvalidElf(ElfError),
+    // Segments overlap or there is not enough memory
+    MapFailed,
+    // The arguments do not fit onto the stack
+    TooManyArguments,
+    // No thread is left or not enough memory for it
+    SpawnFailed,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> LoadError {
+        LoadError::InvalidElf(error)
+    }
+}
+
+// A module loaded by GRUB. The memory stays reserved, so a program can be started any number of
+// times.
+#[derive(Clone, Copy)]
+struct Module {
+    command_line: &'static str,
+    start: PhysicalAddress,
+    end: PhysicalAddress,
+}
+
+impl Module {
+    fn name(&self) -> &'static str {
+        self.command_line.split_whitespace().next().unwrap_or("")
+    }
+
+    fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    // Copy bytes of the file starting at offset into the buffer. The caller checked the range.
+    fn read(&self, offset: usize, buffer: &mut [u8]) {
+        memory::read_physical(self.start + offset, buffer);
+    }
+}
+
+static PROGRAMS: Mutex<[Option<Module>; MAX_PROGRAMS]> = Mutex::new([None; MAX_PROGRAMS]);
+
+/// Register the modules GRUB loaded as programs. The multiboot information has to stay mapped.
+pub fn init(boot_info: &BootInformation) {
+    let mut programs = PROGRAMS.lock();
+    let mut count = 0;
+
+    for module in boot_info.modules() {
+        if count == MAX_PROGRAMS {
+            println!("Too many modules, ignoring {}", module.name());
+            continue;
+        }
+
+        programs[count] = Some(Module {
+            command_line: module.name(),
+            start: module.start_address() as usize,
+            end: module.end_address() as usize,
+        });
+        count += 1;
+    }
+}
+
+/// Start the program with the name in a new address space and thread, with the arguments of its
+/// module command line and an empty environment
+pub fn spawn(name: &str) -> Result<ThreadId, LoadError> {
+    let module = {
+        let programs = PROGRAMS.lock();
+        let found = programs
+            .iter()
+            .filter_map(|program| *program)
+            .find(|module| module.name() == name);
+        match found {
+            Some(module) => module,
+            None => return Err(LoadError::NoSuchProgram),
+        }
+    };
+
+    let mut argv = [""; stack::MAX_STRINGS];
+    let mut argc = 0;
+    for argument in module.command_line.split_whitespace() {
+        if argc == argv.len() {
+            return Err(LoadError::TooManyArguments);
+        }
+        argv[argc] = argument;
+        argc += 1;
+    }
+
+    let (address_space, entry, stack_pointer) = load(&module, &argv[..argc], &[])?;
+    task::spawn_user(address_space, entry, stack_pointer).ok_or(LoadError::SpawnFailed)
+}
+
+/// Start every registered program once. Nobody waits for them, their threads are freed as soon
+/// as they exit.
+pub fn spawn_all() {
+    for slot in 0..MAX_PROGRAMS {
+        let module = PROGRAMS.lock()[slot];
+        if let Some(module) = module {
+            match spawn(module.name()) {
+                Ok(id) => {
+                    task::detach(id);
+                }
+                Err(error) => println!("Could not start {}: {:?}", module.name(), error),
+            }
+        }
+    }
+}
+
+// Load the module into a fresh address space with a stack holding the arguments. Returns the
+// address space, the entry point and the initial stack pointer.
+fn load(
+    module: &Module,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(AddressSpace, VirtualAddress, VirtualAddress), LoadError> {
+    let file_size = module.size();
+    if file_size < HEADER_SIZE {
+        return Err(LoadError::InvalidElf(ElfError::Truncated));
+    }
+
+    let mut bytes = [0; HEADER_SIZE];
+    module.read(0, &mut bytes);
+    let header = Header::parse(&bytes, file_size)?;
+
+    let mut address_space = AddressSpace::new().ok_or(LoadError::MapFailed)?;
+    let mut program_headers = None;
+    let mut entry_executable = false;
+
+    for index in 0..header.program_header_count() {
+        let offset = header.program_header_offset(index);
+        let mut bytes = [0; PROGRAM_HEADER_SIZE];
+        module.read(offset, &mut bytes);
+        let segment = ProgramHeader::parse(&bytes)?;
+
+        match segment.kind() {
+            PT_LOAD => {}
+            PT_PHDR => {
+                program_headers = Some(segment.virtual_address());
+                continue;
+            }
+            _ => continue,
+        }
+
+        segment.validate(file_size)?;
+        load_segment(module, &segment, &mut address_space)?;
+
+        if segment.is_executable() && segment.contains(header.entry()) {
+            entry_executable = true;
+        }
+        // Without a PT_PHDR entry, the program headers are found in the segment that loads them
+        if program_headers.is_none() {
+            program_headers = segment.address_of_offset(header.program_header_offset(0));
+        }
+    }
+
+    if !entry_executable {
+        return Err(LoadError::InvalidElf(ElfError::BadEntry));
+    }
+
+    let stack_bottom = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+    let stack_flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    if !address_space.map_region(stack_bottom, USER_STACK_PAGES * PAGE_SIZE, stack_flags) {
+        return Err(LoadError::MapFailed);
+    }
+
+    let auxv = [
+        (stack::AT_PHDR, program_headers.unwrap_or(0)),
+        (stack::AT_PHENT, PROGRAM_HEADER_SIZE),
+        (stack::AT_PHNUM, header.program_header_count()),
+        (stack::AT_PAGESZ, PAGE_SIZE),
+        (stack::AT_ENTRY, header.entry()),
+    ];
+
+    let mut image = [0; ARGUMENT_SPACE];
+    let stack_pointer = match stack::build(&mut image, USER_STACK_TOP, argv, envp, &auxv) {
+        Some(stack_pointer) => stack_pointer,
+        None => return Err(LoadError::TooManyArguments),
+    };
+    let used = &image[(stack_pointer - (USER_STACK_TOP - ARGUMENT_SPACE))..];
+    let written = address_space.write(stack_pointer, used);
+    assert!(written, "Stack is not mapped");
+
+    Ok((address_space, header.entry(), stack_pointer))
+}
+
+// Map the pages of a validated loadable segment and copy its contents from the file. The memory
+// beyond the file contents stays zeroed.
+fn load_segment(
+    module: &Module,
+    segment: &ProgramHeader,
+    address_space: &mut AddressSpace,
+) -> Result<(), LoadError> {
+    if segment.memory_size() == 0 {
+        return Ok(());
+    }
+
+    let start = segment.virtual_address() & !(PAGE_SIZE - 1);
+    let end = segment.virtual_address() + segment.memory_size();
+    let size = (end - start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let flags = EntryFlags::from_elf_segment(segment.flags());
+    if !address_space.map_region(start, size, flags) {
+        return Err(LoadError::MapFailed);
+    }
+
+    let mut buffer = [0; COPY_CHUNK];
+    let mut copied = 0;
+    while copied < segment.file_size() {
+        let length = cmp::min(COPY_CHUNK, segment.file_size() - copied);
+        module.read(segment.offset() + copied, &mut buffer[..length]);
+        let written =
+            address_space.write(segment.virtual_address() + copied, &buffer[..length]);
+        assert!(written, "Segment is not mapped");
+        copied += length;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+// Initial stack of a program as the System V ABI describes it. From the stack pointer upwards:
+// argc, the argv pointers, a null pointer, the envp pointers, a null pointer, the auxiliary vector
+// ending with AT_NULL and, above everything, the strings the pointers point to.
+
+use core::mem;
+use memory::VirtualAddress;
+
+// Auxiliary vector entry types
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY: usize = 9;
+
+// Most arguments plus environment variables a program can be started with
+pub const MAX_STRINGS: usize = 32;
+
+const WORD: usize = mem::size_of::<usize>();
+
+/// Build the initial stack in image, which holds the memory from top minus its length up to top.
+/// Returns the stack pointer the program starts with, or None if image is too small or there are
+/// too many strings. The part of the image from the stack pointer up has to be copied to the
+/// stack.
+pub fn build(
+    image: &mut [u8],
+    top: VirtualAddress,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(usize, usize)],
+) -> Option<VirtualAddress> {
+    if argv.len() + envp.len() > MAX_STRINGS || top % 16 != 0 {
+        return None;
+    }
+
+    let bottom = top - image.len();
+    let mut position = top;
+
+    // The strings go first, at the top, each terminated by a null byte
+    let mut addresses = [0; MAX_STRINGS];
+    for (i, string) in argv.iter().chain(envp.iter()).enumerate() {
+        let size = string.len() + 1;
+        if position - bottom < size {
+            return None;
+        }
+        position -= size;
+
+        let offset = position - bottom;
+        image[offset..(offset + string.len())].copy_from_slice(string.as_bytes());
+        image[offset + string.len()] = 0;
+        addresses[i] = position;
+    }
+
+    // The stack pointer has to be 16 byte aligned when the program starts
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 1);
+    if position - bottom < words * WORD + 15 {
+        return None;
+    }
+    let stack_pointer = (position - words * WORD) & !15;
+
+    let mut next = stack_pointer;
+    {
+        let mut push = |value: usize| {
+            let offset = next - bottom;
+            for i in 0..WORD {
+                image[offset + i] = (value >> (8 * i)) as u8;
+            }
+            next += WORD;
+        };
+
+        push(argv.len());
+        for &address in &addresses[..argv.len()] {
+            push(address);
+        }
+        push(0);
+        for &address in &addresses[argv.len()..(argv.len() + envp.len())] {
+            push(address);
+        }
+        push(0);
+        for &(kind, value) in auxv {
+            push(kind);
+            push(value);
+        }
+        push(AT_NULL);
+        push(0);
+    }
+
+    Some(stack_pointer)
+}
@@ -0,0 +1,171 @@
+// Host tests for the ELF parser and the initial stack layout, run with cargo test
+
+use super::elf::{ElfError, Header, ProgramHeader, HEADER_SIZE, PROGRAM_HEADER_SIZE, PT_LOAD};
+use super::stack::{self, AT_ENTRY, AT_NULL, AT_PAGESZ};
+use memory::map::{USER_MAP_END, USER_SPACE_END};
+
+fn put(bytes: &mut [u8], offset: usize, value: u64, size: usize) {
+    for i in 0..size {
+        bytes[offset + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+fn get(bytes: &[u8], offset: usize) -> usize {
+    let mut value = 0;
+    for i in 0..8 {
+        value |= (bytes[offset + i] as usize) << (8 * i);
+    }
+    value
+}
+
+// Header of an x86_64 executable with one program header right after it
+fn header() -> [u8; HEADER_SIZE] {
+    let mut bytes = [0; HEADER_SIZE];
+    bytes[0..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+    bytes[4] = 2;
+    bytes[5] = 1;
+    bytes[6] = 1;
+    put(&mut bytes, 16, 2, 2);
+    put(&mut bytes, 18, 62, 2);
+    put(&mut bytes, 20, 1, 4);
+    put(&mut bytes, 24, 0x401000, 8);
+    put(&mut bytes, 32, HEADER_SIZE as u64, 8);
+    put(&mut bytes, 54, PROGRAM_HEADER_SIZE as u64, 2);
+    put(&mut bytes, 56, 1, 2);
+    bytes
+}
+
+// Loadable segment with the given file offset, address and sizes, aligned to pages
+fn segment(offset: u64, address: u64, file_size: u64, memory_size: u64) -> ProgramHeader {
+    let mut bytes = [0; PROGRAM_HEADER_SIZE];
+    put(&mut bytes, 0, PT_LOAD as u64, 4);
+    put(&mut bytes, 4, 5, 4);
+    put(&mut bytes, 8, offset, 8);
+    put(&mut bytes, 16, address, 8);
+    put(&mut bytes, 32, file_size, 8);
+    put(&mut bytes, 40, memory_size, 8);
+    put(&mut bytes, 48, 0x1000, 8);
+    ProgramHeader::parse(&bytes).unwrap()
+}
+
+#[test]
+fn valid_header() {
+    let header = Header::parse(&header(), 0x2000).unwrap();
+    assert_eq!(header.entry(), 0x401000);
+    assert_eq!(header.program_header_count(), 1);
+    assert_eq!(header.program_header_offset(0), HEADER_SIZE);
+}
+
+#[test]
+fn invalid_headers() {
+    let mut bytes = header();
+    bytes[1] = b'X';
+    assert_eq!(Header::parse(&bytes, 0x2000).unwrap_err(), ElfError::BadMagic);
+
+    let mut bytes = header();
+    bytes[4] = 1;
+    assert_eq!(Header::parse(&bytes, 0x2000).unwrap_err(), ElfError::UnsupportedFormat);
+
+    // A shared object
+    let mut bytes = header();
+    put(&mut bytes, 16, 3, 2);
+    assert_eq!(Header::parse(&bytes, 0x2000).unwrap_err(), ElfError::NotExecutable);
+
+    // i386
+    let mut bytes = header();
+    put(&mut bytes, 18, 3, 2);
+    assert_eq!(Header::parse(&bytes, 0x2000).unwrap_err(), ElfError::WrongMachine);
+
+    let mut bytes = header();
+    put(&mut bytes, 56, 0, 2);
+    assert_eq!(Header::parse(&bytes, 0x2000).unwrap_err(), ElfError::BadProgramHeaders);
+}
+
+#[test]
+fn program_headers_beyond_the_file() {
+    let mut bytes = header();
+    put(&mut bytes, 32, !0 - 10, 8);
+    assert_eq!(Header::parse(&bytes, 0x2000).unwrap_err(), ElfError::Truncated);
+    assert_eq!(Header::parse(&header(), 100).unwrap_err(), ElfError::Truncated);
+}
+
+#[test]
+fn valid_segment() {
+    let segment = segment(0x1000, 0x401000, 0x800, 0x2000);
+    assert!(segment.validate(0x2000).is_ok());
+    assert!(segment.is_executable());
+    assert!(segment.contains(0x402fff));
+    assert!(!segment.contains(0x403000));
+    assert_eq!(segment.address_of_offset(0x1010), Some(0x401010));
+    assert_eq!(segment.address_of_offset(0x1800), None);
+}
+
+#[test]
+fn invalid_segments() {
+    // More in the file than in memory
+    assert_eq!(
+        segment(0x1000, 0x401000, 0x2000, 0x1000).validate(0x4000),
+        Err(ElfError::BadSegment)
+    );
+    // Past the end of the file
+    assert_eq!(
+        segment(0x1000, 0x401000, 0x2000, 0x2000).validate(0x2000),
+        Err(ElfError::Truncated)
+    );
+    // Into kernel space
+    let address = (USER_SPACE_END - 0x1000) as u64;
+    assert_eq!(
+        segment(0x1000, address, 0x800, 0x2000).validate(0x2000),
+        Err(ElfError::BadSegment)
+    );
+    // Into the last page of user space, which is never mapped
+    assert_eq!(
+        segment(0x1000, address, 0x800, 0x1000).validate(0x2000),
+        Err(ElfError::BadSegment)
+    );
+    let address = (USER_MAP_END - 0x1000) as u64;
+    assert!(segment(0x1000, address, 0x800, 0x1000).validate(0x2000).is_ok());
+    // Offset and address do not line up within the page
+    assert_eq!(
+        segment(0x1010, 0x401000, 0x800, 0x800).validate(0x2000),
+        Err(ElfError::BadSegment)
+    );
+}
+
+#[test]
+fn stack_layout() {
+    let top = 0x7000_0000;
+    let mut image = [0; 512];
+    let auxv = [(AT_PAGESZ, 4096), (AT_ENTRY, 0x401000)];
+    let stack_pointer = stack::build(&mut image, top, &["hello", "world"], &["A=1"], &auxv)
+        .unwrap();
+    assert_eq!(stack_pointer % 16, 0);
+
+    let bottom = top - image.len();
+    let word = |index: usize| get(&image, stack_pointer - bottom + index * 8);
+    let string = |address: usize| {
+        let start = address - bottom;
+        let length = image[start..].iter().position(|&byte| byte == 0).unwrap();
+        String::from_utf8(image[start..(start + length)].to_vec()).unwrap()
+    };
+
+    assert_eq!(word(0), 2);
+    assert_eq!(string(word(1)), "hello");
+    assert_eq!(string(word(2)), "world");
+    assert_eq!(word(3), 0);
+    assert_eq!(string(word(4)), "A=1");
+    assert_eq!(word(5), 0);
+    assert_eq!((word(6), word(7)), (AT_PAGESZ, 4096));
+    assert_eq!((word(8), word(9)), (AT_ENTRY, 0x401000));
+    assert_eq!((word(10), word(11)), (AT_NULL, 0));
+
+    // The strings lie above the vectors
+    assert!(word(1) > stack_pointer + 12 * 8);
+}
+
+#[test]
+fn stack_too_small() {
+    let mut image = [0; 64];
+    let argv = ["a rather long argument that does not fit"; 2];
+    assert!(stack::build(&mut image, 0x7000_0000, &argv, &[], &[]).is_none());
+}
@@ -0,0 +1,229 @@
+use memory::paging::{EntryFlags, InactivePageTable, PhysicalAddress, VirtualAddress};
+use memory::map::USER_MAP_END;
+use memory::{MemoryController, MEMORY_CONTROLLER, PAGE_SIZE};
+
+// Maximum number of regions a single address space can contain
+const MAX_USER_REGIONS: usize = 32;
+
+// A range of user memory with the permissions it is mapped with
+#[derive(Debug, Clone, Copy)]
+pub struct UserRegion {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    flags: EntryFlags,
+}
+
+const EMPTY_REGION: UserRegion = UserRegion {
+    start: 0,
+    end: 0,
+    flags: EntryFlags::PRESENT,
+};
+
+impl UserRegion {
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    pub fn end_address(&self) -> VirtualAddress {
+        self.end
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    fn overlaps(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// A page table of its own for the lower half plus the kernel half shared with every other
+/// address space. All frames mapped in the lower half belong to the address space and are freed
+/// with it, unless they are still shared copy-on-write with a fork. Dropping an address space
+/// locks the memory controller.
+pub struct AddressSpace {
+    table: InactivePageTable,
+    regions: [UserRegion; MAX_USER_REGIONS],
+    count: usize,
+}
+
+impl AddressSpace {
+    /// Create an address space without any user mappings. Returns None if out of memory.
+    pub fn new() -> Option<AddressSpace> {
+        with_controller(|controller| controller.new_page_table()).map(|table| AddressSpace {
+            table,
+            regions: [EMPTY_REGION; MAX_USER_REGIONS],
+            count: 0,
+        })
+    }
+
+    /// Map a region of zeroed user memory. Returns false if the region is not page aligned, not
+    /// below USER_MAP_END, overlaps another region or there is not enough memory.
+    pub fn map_region(&mut self, start: VirtualAddress, size: usize, flags: EntryFlags) -> bool {
+        let end = match start.checked_add(size) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        if start % PAGE_SIZE != 0 || size == 0 || size % PAGE_SIZE != 0 || end > USER_MAP_END {
+            return false;
+        }
+
+        if self.count == MAX_USER_REGIONS
+            || self.regions().iter().any(|region| region.overlaps(start, end))
+        {
+            return false;
+        }
+
+        let flags = flags | EntryFlags::USER_ACCESSIBLE;
+        let table = &mut self.table;
+        let mapped = with_controller(|controller| {
+            for i in 0..size / PAGE_SIZE {
+                if !controller.map_page_in(table, start + i * PAGE_SIZE, flags) {
+                    // Out of memory, undo the pages mapped so far
+                    controller.unmap_range_in(table, start, i);
+                    return false;
+                }
+            }
+            true
+        });
+
+        if mapped {
+            self.regions[self.count] = UserRegion { start, end, flags };
+            self.count += 1;
+        }
+
+        mapped
+    }
+
+    /// Clone the address space for fork. Instead of copying, both address spaces share all frames
+    /// and writable pages are copied on the first write. Returns None if out of memory or too
+    /// many frames are shared already.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let mut child = match AddressSpace::new() {
+            Some(child) => child,
+            None => return None,
+        };
+
+        // Inner scope to end the borrow of both tables
+        let shared = {
+            let regions = &self.regions[..self.count];
+            let (table, child_table) = (&mut self.table, &mut child.table);
+            with_controller(|controller| {
+                regions.iter().all(|region| {
+                    let pages = (region.end - region.start) / PAGE_SIZE;
+                    controller.share_pages(table, child_table, region.start, pages)
+                })
+            })
+        };
+
+        if !shared {
+            // Dropping the child releases the frames shared so far
+            return None;
+        }
+
+        child.regions = self.regions;
+        child.count = self.count;
+        Some(child)
+    }
+
+    /// Unmap the region starting at start and free its frames. Returns false if there is none.
+    pub fn unmap_region(&mut self, start: VirtualAddress) -> bool {
+        let index = match self.regions().iter().position(|region| region.start == start) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let region = self.regions[index];
+        let table = &mut self.table;
+        let pages = (region.end - region.start) / PAGE_SIZE;
+        with_controller(|controller| controller.unmap_range_in(table, region.start, pages));
+
+        // Keep the regions compact
+        for i in index..(self.count - 1) {
+            self.regions[i] = self.regions[i + 1];
+        }
+        self.count -= 1;
+
+        true
+    }
+
+    pub fn regions(&self) -> &[UserRegion] {
+        &self.regions[..self.count]
+    }
+
+    // Find the region containing the given address
+    pub fn find_region(&self, address: VirtualAddress) -> Option<&UserRegion> {
+        self.regions().iter().find(|region| region.contains(address))
+    }
+
+    /// Copy data into the address space, which does not have to be active, e.g. to load a program.
+    /// Read-only regions are written as well. Pages still shared copy-on-write with a fork would
+    /// change for both, so this is only meant for address spaces that were not forked. Returns
+    /// false unless the whole range lies inside regions.
+    pub fn write(&mut self, address: VirtualAddress, data: &[u8]) -> bool {
+        let end = match address.checked_add(data.len()) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        // The regions are not sorted, so walk the range one region at a time
+        let mut next = address;
+        while next < end {
+            match self.find_region(next) {
+                Some(region) => next = region.end,
+                None => return false,
+            }
+        }
+
+        let table = &mut self.table;
+        with_controller(|controller| controller.write_in(table, address, data))
+    }
+
+    /// Physical address the virtual address is mapped to in this address space
+    pub fn translate(&mut self, address: VirtualAddress) -> Option<PhysicalAddress> {
+        let table = &mut self.table;
+        with_controller(|controller| controller.translate_in(table, address))
+    }
+
+    /// Make this the active address space. Dropping it switches back to the kernel page table.
+    pub fn switch_to(&self) {
+        with_controller(|controller| controller.switch_page_table(&self.table))
+    }
+
+    /// Make this the active address space without locking the memory controller, for switching
+    /// threads. See InactivePageTable::load.
+    pub unsafe fn load(&self) {
+        self.table.load();
+    }
+
+    pub fn is_active(&self) -> bool {
+        with_controller(|controller| controller.is_active(&self.table))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let table = &mut self.table;
+        with_controller(|controller| controller.free_page_table(table));
+    }
+}
+
+/// Switch back to the page table that only maps the kernel
+pub fn switch_to_kernel() {
+    with_controller(|controller| controller.switch_to_kernel())
+}
+
+fn with_controller<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut MemoryController) -> T,
+{
+    f(MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .expect("Memory is not initialized"))
+}
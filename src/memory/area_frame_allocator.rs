@@ -1,42 +1,66 @@
+use core::slice;
 use memory::{Frame, FrameAllocator};
+use memory::reserved::RESERVATIONS;
 use multiboot2::{MemoryArea, MemoryAreaIter};
 
+// Maximum number of disjoint runs of freed frames that are remembered until the bitmap of free
+// frames is set up
+pub const MAX_FREE_RANGES: usize = 64;
+
 // Basic memory allocator based on Multiboot mapped memory. Chooses new frames by simply looking
-// for the next multiboot provided memory area that is not already used by the kernel or the
-// multiboot info struct. All memory below the next_free_frame is considered in use and cannot be
-// freed.
+// for the next multiboot provided memory area that is not reserved in the physical memory
+// reservation registry. All memory below the next_free_frame is considered in use, so frames that
+// are deallocated again are kept for reuse and handed out first. While booting they are kept in a
+// short list of free ranges, afterwards in a bitmap covering all of the memory map.
 pub struct AreaFrameAllocator {
+    free_ranges: FreeRanges,
+    free_bitmap: Option<FreeBitmap>,
     next_free_frame: Frame,
     current_area: Option<&'static MemoryArea>,
     areas: MemoryAreaIter,
-    kernel_start: Frame,
-    kernel_end: Frame,
-    multiboot_start: Frame,
-    multiboot_end: Frame,
 }
 
 impl AreaFrameAllocator {
-    pub fn new(
-        kernel_start: usize,
-        kernel_end: usize,
-        multiboot_start: usize,
-        multiboot_end: usize,
-        memory_areas: MemoryAreaIter,
-    ) -> AreaFrameAllocator {
+    pub fn new(memory_areas: MemoryAreaIter) -> AreaFrameAllocator {
         let mut allocator = AreaFrameAllocator {
+            free_ranges: FreeRanges::new(),
+            free_bitmap: None,
             next_free_frame: Frame::containing_address(0),
             current_area: None,
             areas: memory_areas,
-            kernel_start: Frame::containing_address(kernel_start),
-            kernel_end: Frame::containing_address(kernel_end),
-            multiboot_start: Frame::containing_address(multiboot_start),
-            multiboot_end: Frame::containing_address(multiboot_end),
         };
 
         allocator.choose_next_area();
         allocator
     }
 
+    /// Number of frames up to the end of the last memory area, which is what the bitmap passed
+    /// to use_free_bitmap has to cover
+    pub fn frame_count(&self) -> usize {
+        self.areas
+            .clone()
+            .map(|area| Frame::containing_address(area.end_address() - 1).number + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Track freed frames in the zeroed memory of words instead of the list of free ranges, which
+    /// only has room for few frames that were freed out of order. The frames in the list move to
+    /// the bitmap. The memory has to stay valid and must not be used otherwise.
+    pub unsafe fn use_free_bitmap(&mut self, words: *mut u64, len: usize) {
+        assert!(self.free_bitmap.is_none(), "The free bitmap is already set up");
+
+        let mut bitmap = FreeBitmap::new(words, len);
+        assert!(
+            bitmap.frame_count() >= self.frame_count(),
+            "Free bitmap is too small"
+        );
+        while let Some(frame) = self.free_ranges.take() {
+            bitmap.insert(frame);
+        }
+        self.free_bitmap = Some(bitmap);
+    }
+
     // Choose the next area to allocate frome
     fn choose_next_area(&mut self) {
         // Get the smallest next area that contains frames bigger than the current next_free_frame
@@ -61,6 +85,15 @@ impl AreaFrameAllocator {
 
 impl FrameAllocator for AreaFrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame> {
+        // Reuse previously freed frames before taking new ones from the memory areas
+        let freed = match self.free_bitmap {
+            Some(ref mut bitmap) => bitmap.take(),
+            None => self.free_ranges.take(),
+        };
+        if freed.is_some() {
+            return freed;
+        }
+
         if let Some(area) = self.current_area {
             // Get the next available frame
             let frame = Frame {
@@ -71,19 +104,15 @@ impl FrameAllocator for AreaFrameAllocator {
             let last_frame_from_current_area =
                 Frame::containing_address((area.start_address() + area.size() - 1) as usize);
 
+            // Statement on its own so the lock is released before trying again below
+            let reserved_until = RESERVATIONS.lock().frame_after(&frame);
+
             if frame > last_frame_from_current_area {
                 // All frames from the current area are in use, switch to the next area
                 self.choose_next_area();
-            } else if frame >= self.kernel_start && frame <= self.kernel_end {
-                // Frame is in use by the kernel, skip to the next frame after
-                self.next_free_frame = Frame {
-                    number: self.kernel_end.number + 1,
-                }
-            } else if frame >= self.multiboot_start && frame <= self.multiboot_end {
-                // Frame is in use by the multiboot info struct, skip to the next frame after
-                self.next_free_frame = Frame {
-                    number: self.multiboot_end.number + 1,
-                }
+            } else if let Some(next_frame) = reserved_until {
+                // Frame is reserved, skip to the first frame after the reservation
+                self.next_free_frame = next_frame;
             } else {
                 // A new frame was found that was not in use
                 self.next_free_frame.number += 1;
@@ -99,7 +128,184 @@ impl FrameAllocator for AreaFrameAllocator {
         }
     }
 
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        unimplemented!()
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(
+            !RESERVATIONS.lock().is_reserved(&frame),
+            "Deallocated frame {:?} is reserved",
+            frame
+        );
+
+        match self.free_bitmap {
+            Some(ref mut bitmap) => bitmap.insert(frame),
+            None => self.free_ranges.insert(frame),
+        }
+    }
+}
+
+// One bit for every frame of the memory map, set if the frame was freed and can be reused
+pub struct FreeBitmap {
+    words: *mut u64,
+    len: usize,
+    // No word before this one has a bit set
+    first: usize,
+}
+
+// The memory of the bitmap belongs to it alone
+unsafe impl Send for FreeBitmap {}
+
+impl FreeBitmap {
+    // Bitmap in the zeroed memory of len words, which has to stay valid
+    pub unsafe fn new(words: *mut u64, len: usize) -> FreeBitmap {
+        FreeBitmap {
+            words,
+            len,
+            first: len,
+        }
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.len * 64
+    }
+
+    fn words(&mut self) -> &mut [u64] {
+        unsafe { slice::from_raw_parts_mut(self.words, self.len) }
+    }
+
+    // Take the free frame with the lowest number
+    pub fn take(&mut self) -> Option<Frame> {
+        let first = self.first;
+        let found = self.words()[first..]
+            .iter()
+            .position(|&word| word != 0)
+            .map(|offset| first + offset);
+
+        match found {
+            Some(index) => {
+                self.first = index;
+                let word = &mut self.words()[index];
+                let bit = word.trailing_zeros() as usize;
+                *word &= !(1 << bit);
+                Some(Frame {
+                    number: index * 64 + bit,
+                })
+            }
+            None => {
+                self.first = self.len;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, frame: Frame) {
+        let number = frame.number;
+        assert!(
+            number < self.frame_count(),
+            "Frame {} lies outside of the memory map",
+            number
+        );
+
+        let index = number / 64;
+        let bit = 1 << (number % 64);
+        {
+            let word = &mut self.words()[index];
+            assert!(*word & bit == 0, "Frame {} was deallocated twice", number);
+            *word |= bit;
+        }
+
+        if index < self.first {
+            self.first = index;
+        }
+    }
+}
+
+// A run of free frames from start up to but not including end
+#[derive(Debug, Clone, Copy)]
+struct FreeRange {
+    start: usize,
+    end: usize,
+}
+
+// Frames that were returned to the allocator, merged into runs of consecutive frame numbers
+pub struct FreeRanges {
+    ranges: [Option<FreeRange>; MAX_FREE_RANGES],
+}
+
+impl FreeRanges {
+    pub fn new() -> FreeRanges {
+        FreeRanges {
+            ranges: [None; MAX_FREE_RANGES],
+        }
+    }
+
+    // Take any free frame
+    pub fn take(&mut self) -> Option<Frame> {
+        for slot in self.ranges.iter_mut() {
+            if let Some(mut range) = *slot {
+                let frame = Frame {
+                    number: range.start,
+                };
+
+                range.start += 1;
+                *slot = if range.start == range.end {
+                    None
+                } else {
+                    Some(range)
+                };
+
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
+    // Add a frame, merging it with the runs directly before and after it. Panics if the frame
+    // can't be merged and every run is in use, since the frame would be lost for good. Only frames
+    // freed while booting end up here, mostly in order.
+    pub fn insert(&mut self, frame: Frame) {
+        let number = frame.number;
+        let mut before = None;
+        let mut after = None;
+
+        for (i, slot) in self.ranges.iter().enumerate() {
+            if let Some(range) = *slot {
+                assert!(
+                    number < range.start || number >= range.end,
+                    "Frame {} was deallocated twice",
+                    number
+                );
+
+                if range.end == number {
+                    before = Some(i);
+                } else if range.start == number + 1 {
+                    after = Some(i);
+                }
+            }
+        }
+
+        match (before, after) {
+            (Some(before), Some(after)) => {
+                let end = self.ranges[after].take().unwrap().end;
+                self.ranges[before].as_mut().unwrap().end = end;
+            }
+            (Some(before), None) => self.ranges[before].as_mut().unwrap().end += 1,
+            (None, Some(after)) => self.ranges[after].as_mut().unwrap().start -= 1,
+            (None, None) => {
+                for slot in self.ranges.iter_mut() {
+                    if slot.is_none() {
+                        *slot = Some(FreeRange {
+                            start: number,
+                            end: number + 1,
+                        });
+                        return;
+                    }
+                }
+
+                panic!(
+                    "Frame {} can't be freed, all {} free ranges are in use",
+                    number, MAX_FREE_RANGES
+                );
+            }
+        }
     }
 }
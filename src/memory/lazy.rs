@@ -0,0 +1,109 @@
+use memory::paging::{EntryFlags, VirtualAddress};
+use memory::PAGE_SIZE;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+// Maximum number of regions that can be reserved for demand paging at once
+const MAX_LAZY_REGIONS: usize = 32;
+
+// A range of virtual memory that is reserved up front but only backed by frames once touched
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    flags: EntryFlags,
+}
+
+impl LazyRegion {
+    pub fn new(start: VirtualAddress, size: usize, flags: EntryFlags) -> LazyRegion {
+        assert!(start % PAGE_SIZE == 0, "Lazy regions must be page aligned");
+        assert!(
+            size > 0 && size % PAGE_SIZE == 0,
+            "Lazy regions must be a non-zero multiple of the page size"
+        );
+
+        LazyRegion {
+            start,
+            end: start + size,
+            flags,
+        }
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    fn overlaps(&self, other: &LazyRegion) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    // Check if the faulting access would have been allowed by the final mapping. Anything else is
+    // a real protection error and must not be papered over by mapping a frame.
+    pub fn permits(&self, error_code: PageFaultErrorCode) -> bool {
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !self.flags.contains(EntryFlags::WRITABLE)
+        {
+            return false;
+        }
+
+        if error_code.contains(PageFaultErrorCode::USER_MODE)
+            && !self.flags.contains(EntryFlags::USER_ACCESSIBLE)
+        {
+            return false;
+        }
+
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && self.flags.contains(EntryFlags::NO_EXECUTE)
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+// Set of all reserved but not necessarily backed regions
+pub struct LazyRegions {
+    regions: [Option<LazyRegion>; MAX_LAZY_REGIONS],
+}
+
+impl LazyRegions {
+    pub const fn new() -> LazyRegions {
+        LazyRegions {
+            regions: [None; MAX_LAZY_REGIONS],
+        }
+    }
+
+    // Reserve a new region. Panics if it overlaps an existing region or there is no space left.
+    pub fn insert(&mut self, region: LazyRegion) {
+        assert!(
+            self.regions
+                .iter()
+                .filter_map(|r| r.as_ref())
+                .all(|r| !r.overlaps(&region)),
+            "Lazy region 0x{:x}-0x{:x} overlaps an existing region",
+            region.start,
+            region.end
+        );
+
+        for slot in self.regions.iter_mut() {
+            if slot.is_none() {
+                *slot = Some(region);
+                return;
+            }
+        }
+
+        panic!("Too many lazy regions");
+    }
+
+    // Find the region containing the given address
+    pub fn find(&self, address: VirtualAddress) -> Option<&LazyRegion> {
+        self.regions
+            .iter()
+            .filter_map(|r| r.as_ref())
+            .find(|r| r.contains(address))
+    }
+}
@@ -6,13 +6,43 @@
 // P4 at 510 instead. Everything else from 0x0 to 0xffffff7fffffff can be used by user mode.
 pub const RECURSIVE_ENTRY: usize = 510;
 
+// Number of implemented virtual address bits, 57 with five-level paging
+#[cfg(not(feature = "la57"))]
+pub const VIRTUAL_ADDRESS_BITS: usize = 48;
+#[cfg(feature = "la57")]
+pub const VIRTUAL_ADDRESS_BITS: usize = 57;
+
 // 0xFFFF + (510 << 39) + (510 << 30) + (510 << 21) + (510 << 12)
+#[cfg(not(feature = "la57"))]
 pub const P4_TABLE_ADDRESS: usize = 0o177777_000_000_000_000_0000 + (RECURSIVE_ENTRY<<39) // P4 slot
                                                                   + (RECURSIVE_ENTRY<<30) // P3 slot
                                                                   + (RECURSIVE_ENTRY<<21) // P2 slot
                                                                   + (RECURSIVE_ENTRY<<12) // P1 slot
                                                                   + (0<<0); // Offset
 
+// With five-level paging the boot code maps the kernel P4 at P5 = 511 and the recursive entry
+// moves to the P5 table, which takes one more step through it to reach the P5 table itself.
+// 0xFE00 + (510 << 48) + (510 << 39) + (510 << 30) + (510 << 21) + (510 << 12)
+#[cfg(feature = "la57")]
+pub const P5_TABLE_ADDRESS: usize = 0o177_000_000_000_000_000_0000
+    + (RECURSIVE_ENTRY << 48) // P5 slot
+    + (RECURSIVE_ENTRY << 39) // P4 slot
+    + (RECURSIVE_ENTRY << 30) // P3 slot
+    + (RECURSIVE_ENTRY << 21) // P2 slot
+    + (RECURSIVE_ENTRY << 12); // P1 slot
+
+// End of the lower half, everything below belongs to user mode.
+// 0x0000_8000_0000_0000, or 0x0100_0000_0000_0000 with five-level paging
+pub const USER_SPACE_END: usize = 1 << (VIRTUAL_ADDRESS_BITS - 1);
+
+// User memory ends one page earlier. The last page stays unmapped, so that user code never runs
+// right below USER_SPACE_END and returns from a system call to an address that is not canonical.
+pub const USER_MAP_END: usize = USER_SPACE_END - 4096;
+
+// Start of the higher half, everything from here on belongs to the kernel.
+// 0xffff_8000_0000_0000, or 0xff00_0000_0000_0000 with five-level paging
+pub const KERNEL_SPACE_START: usize = !(USER_SPACE_END - 1);
+
 pub const KERNEL_VMA: usize = 0xffffffff80000000;
 pub const VGA_BUFFER_VMA: usize = 0xffffffff80000000 + 0xb8000;
 
@@ -22,3 +52,9 @@ pub const HEAP_SIZE: usize = 100 * 1024;
 
 // 0xfffffffff0000000
 pub const TEMP_PAGE: usize = 0xfffffffff0000000;
+
+// Kernel virtual memory handed out by the VMA allocator. Covers all of P4 = 509, directly below
+// the recursive mapping, so every region shares a single P3 table.
+// 0xfffffe8000000000
+pub const VMALLOC_START: usize = 0o177777_775_000_000_000_0000;
+pub const VMALLOC_SIZE: usize = 512 * 1024 * 1024 * 1024;
@@ -1,15 +1,56 @@
+/// Place a static in .data.ro_after_init. It can be written while the kernel is initializing and
+/// becomes read-only once protect_kernel runs at the end of boot.
+macro_rules! ro_after_init {
+    ($(#[$attr:meta])* static mut $name:ident: $ty:ty = $value:expr;) => {
+        $(#[$attr])*
+        #[link_section = ".data.ro_after_init"]
+        static mut $name: $ty = $value;
+    };
+    ($(#[$attr:meta])* static $name:ident: $ty:ty = $value:expr;) => {
+        $(#[$attr])*
+        #[link_section = ".data.ro_after_init"]
+        static $name: $ty = $value;
+    };
+}
+
+mod address_space;
 mod area_frame_allocator;
+mod lazy;
 mod paging;
+mod refcount;
+mod reserved;
+mod stack_allocator;
+#[cfg(test)]
+mod tests;
+mod user;
+mod vma;
 pub mod map;
+pub mod slab;
 
+pub use self::address_space::{switch_to_kernel, AddressSpace, UserRegion};
 pub use self::area_frame_allocator::AreaFrameAllocator;
+pub use self::stack_allocator::Stack;
+pub use self::user::{copy_fault_fixup, copy_from_user, copy_to_user, UserCopyError, UserPtr};
+pub use self::paging::{EntryFlags, InactivePageTable, PhysicalAddress, Translation,
+                       VirtualAddress};
 
+use self::lazy::{LazyRegion, LazyRegions};
+use self::map::{HEAP_SIZE, HEAP_START, KERNEL_VMA, TEMP_PAGE, VMALLOC_SIZE, VMALLOC_START};
 use self::paging::remap_the_kernel;
-use self::paging::PhysicalAddress;
+use self::paging::{ActivePageTable, Mapper, Mapping, Page, TemporaryPage};
+use self::refcount::{SharedFrameAllocator, FRAME_REFCOUNTS};
+use self::vma::{RegionKind, VmaAllocator};
+use cpu;
 use multiboot2::BootInformation;
+use sync::IrqSafeMutex;
+use x86_64::structures::idt::PageFaultErrorCode;
 
 pub const PAGE_SIZE: usize = 4096;
 
+// Kernel sections that are writable in the ELF file but are only written while booting, if at all
+const READ_ONLY_AFTER_INIT: [&'static str; 4] =
+    [".rodata", ".data.rel.ro", ".got", ".data.ro_after_init"];
+
 // Allocates physical memory
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
@@ -68,28 +109,696 @@ impl Iterator for FrameIter {
 pub fn init(boot_info: &BootInformation) {
     let memory_map = boot_info.memory_map().expect("Memory map tag required");
 
-    // Contains the start and end values of the kernel
+    // Record all memory that is already in use before the first frame is handed out
+    reserved::init(boot_info);
+
+    let mut frame_allocator = AreaFrameAllocator::new(memory_map.memory_areas());
+
+    enable_nxe_bit();
+    enable_write_protect_bit();
+    enable_smep_bit();
+    enable_smap_bit();
+    enable_umip_bit();
+
+    let (mut active_table, kernel_table) = remap_the_kernel(&mut frame_allocator, &boot_info);
+
+    // Kernel pages are global, so they stay cached across address space switches
+    enable_global_pages();
+    paging::enable_pcids();
+
+    // Every top level entry of the kernel half has to exist before address spaces copy them
+    active_table.create_kernel_table(VMALLOC_START, &mut frame_allocator);
+
+    let temporary_page =
+        TemporaryPage::new(Page::containing_address(TEMP_PAGE), &mut frame_allocator);
+
+    // The heap is only backed by frames as it is used
+    let mut lazy_regions = LazyRegions::new();
+    lazy_regions.insert(LazyRegion::new(
+        HEAP_START,
+        HEAP_SIZE,
+        EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+    ));
+
+    *MEMORY_CONTROLLER.lock() = Some(MemoryController {
+        active_table,
+        kernel_table,
+        frame_allocator,
+        temporary_page,
+        lazy_regions,
+        vma_allocator: VmaAllocator::new(VMALLOC_START, VMALLOC_SIZE),
+    });
+
+    MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .unwrap()
+        .set_up_free_bitmap();
+    slab::init();
+}
+
+// Owns the kernel page table and frame allocator once paging has been set up
+pub struct MemoryController {
+    active_table: ActivePageTable,
+    // The page table set up by remap_the_kernel, only maps the kernel half
+    kernel_table: InactivePageTable,
+    frame_allocator: AreaFrameAllocator,
+    temporary_page: TemporaryPage,
+    lazy_regions: LazyRegions,
+    vma_allocator: VmaAllocator,
+}
+
+// Held with interrupts disabled, so a thread is never preempted while holding it and page faults
+// of other threads always find it free
+pub static MEMORY_CONTROLLER: IrqSafeMutex<Option<MemoryController>> = IrqSafeMutex::new(None);
+
+impl MemoryController {
+    /// Run f with the recursive mapping pointing to the given inactive table
+    pub fn with_inactive<F>(&mut self, table: &mut InactivePageTable, f: F)
+    where
+        F: FnOnce(&mut Mapper),
+    {
+        self.active_table.with(table, &mut self.temporary_page, f);
+    }
+
+    /// Print all mappings of the active page table
+    pub fn dump_page_table(&self) {
+        paging::print(&self.active_table);
+    }
+
+    /// Print all mappings of an inactive page table
+    pub fn dump_inactive_page_table(&mut self, table: &mut InactivePageTable) {
+        self.with_inactive(table, |mapper| paging::print(mapper));
+    }
+
+    /// Check the active page table for W+X pages, user accessible kernel pages and aliasing.
+    /// Returns the number of problems that were printed.
+    pub fn check_page_table(&mut self) -> usize {
+        let count = paging::count_mappings(&self.active_table);
+        self.with_mapping_buffer(count, |controller, buffer| {
+            paging::check(&controller.active_table, buffer)
+        })
+    }
+
+    /// Check an inactive page table, see check_page_table
+    pub fn check_inactive_page_table(&mut self, table: &mut InactivePageTable) -> usize {
+        let mut count = 0;
+        self.with_inactive(table, |mapper| count = paging::count_mappings(mapper));
+        self.with_mapping_buffer(count, |controller, buffer| {
+            let mut problems = 0;
+            controller.with_inactive(table, |mapper| problems = paging::check(mapper, buffer));
+            problems
+        })
+    }
+
+    // Call f with a buffer for count mappings plus the ones added by mapping the buffer itself,
+    // as every page of it may end up in a mapping of its own
+    fn with_mapping_buffer<F>(&mut self, count: usize, f: F) -> usize
+    where
+        F: FnOnce(&mut Self, &mut [Option<Mapping>]) -> usize,
+    {
+        let size = ::core::mem::size_of::<Option<Mapping>>();
+        let pages = count * size / (PAGE_SIZE - size) + 1;
+        let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        let start = match self.vmalloc(pages * PAGE_SIZE, 0, flags, "page table check") {
+            Some(start) => start,
+            None => {
+                println!("Not enough memory to check for aliasing");
+                return f(self, &mut []);
+            }
+        };
+
+        let len = pages * PAGE_SIZE / size;
+        let buffer = start as *mut Option<Mapping>;
+        let problems = unsafe {
+            for i in 0..len {
+                ::core::ptr::write(buffer.offset(i as isize), None);
+            }
+            f(self, ::core::slice::from_raw_parts_mut(buffer, len))
+        };
+
+        self.vfree(start);
+        problems
+    }
+
+    // Track freed frames in a bitmap covering the memory map from now on. Frames are freed in any
+    // order once address spaces come and go, which the list of free ranges can't keep up with.
+    fn set_up_free_bitmap(&mut self) {
+        let words = (self.frame_allocator.frame_count() + 63) / 64;
+        let size = words * ::core::mem::size_of::<u64>();
+        let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        let bitmap = self.vmalloc(size, 0, flags, "free frame bitmap")
+            .expect("Could not allocate the free frame bitmap");
+
+        unsafe {
+            ::core::ptr::write_bytes(bitmap as *mut u64, 0, words);
+            self.frame_allocator.use_free_bitmap(bitmap as *mut u64, words);
+        }
+    }
+
+    /// Reserve a page aligned region of virtual memory that is mapped to a zeroed frame on the
+    /// first access of each page.
+    pub fn reserve_lazy(&mut self, start: VirtualAddress, size: usize, flags: EntryFlags) {
+        self.lazy_regions.insert(LazyRegion::new(start, size, flags));
+    }
+
+    /// Allocate and map at least size bytes of kernel virtual memory with guard_pages unmapped
+    /// pages below it. The owner names the subsystem the memory belongs to.
+    pub fn vmalloc(
+        &mut self,
+        size: usize,
+        guard_pages: usize,
+        flags: EntryFlags,
+        owner: &'static str,
+    ) -> Option<VirtualAddress> {
+        self.map_region(size, guard_pages, flags, owner, RegionKind::Memory)
+    }
+
+    /// Allocate a kernel stack of the given number of pages with an unmapped guard page below it
+    pub fn alloc_stack(&mut self, pages: usize, owner: &'static str) -> Option<Stack> {
+        let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        self.map_region(pages * PAGE_SIZE, 1, flags, owner, RegionKind::Stack)
+            .map(|bottom| Stack::new(bottom + pages * PAGE_SIZE, bottom))
+    }
+
+    /// Unmap and free a stack returned by alloc_stack
+    pub fn free_stack(&mut self, stack: Stack) {
+        self.vfree(stack.bottom());
+    }
+
+    /// Reserve kernel virtual address space without backing it. The owner maps and unmaps pages
+    /// inside it with map_page and unmap_page.
+    pub fn reserve_window(&mut self, pages: usize, owner: &'static str) -> Option<VirtualAddress> {
+        self.vma_allocator.allocate(pages, 0, owner, RegionKind::Window)
+    }
+
+    /// Back a single page with a new frame. Returns false if out of memory.
+    pub fn map_page(&mut self, address: VirtualAddress, flags: EntryFlags) -> bool {
+        match self.frame_allocator.allocate_frame() {
+            Some(frame) => {
+                let page = Page::containing_address(address);
+                self.active_table.map_to(page, frame, flags, &mut self.frame_allocator);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Unmap a single page and return its frame to the frame allocator
+    pub fn unmap_page(&mut self, address: VirtualAddress) {
+        self.unmap_and_free(address, 1);
+    }
+
+    /// Create an empty page table that shares the kernel half with the kernel page table
+    pub fn new_page_table(&mut self) -> Option<InactivePageTable> {
+        let frame = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return None,
+        };
+
+        Some(InactivePageTable::new_sharing_kernel(
+            frame,
+            &mut self.active_table,
+            &mut self.temporary_page,
+        ))
+    }
+
+    /// Free a table created by new_page_table together with everything mapped in its lower half.
+    /// Shared frames are only freed once no other table maps them. Switches back to the kernel
+    /// page table if the table is active.
+    pub fn free_page_table(&mut self, table: &mut InactivePageTable) {
+        if self.active_table.is_active(table) {
+            self.active_table.switch(&self.kernel_table);
+        }
+
+        // Inner scope to end the borrow of the frame allocator
+        {
+            let mut allocator = SharedFrameAllocator::new(&mut self.frame_allocator);
+            self.active_table.with(table, &mut self.temporary_page, |mapper| {
+                mapper.free_lower_half(&mut allocator)
+            });
+        }
+
+        table.release_pcid();
+        self.frame_allocator.deallocate_frame(table.p4_frame().clone());
+    }
+
+    /// Back a page of an inactive table with a zeroed frame. Returns false if out of memory.
+    pub fn map_page_in(
+        &mut self,
+        table: &mut InactivePageTable,
+        address: VirtualAddress,
+        flags: EntryFlags,
+    ) -> bool {
+        let frame = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        // The frame has to be zeroed through the active table before it is handed out
+        let zeroed = self.temporary_page.map(frame.clone(), &mut self.active_table);
+        unsafe {
+            ::core::ptr::write_bytes(zeroed as *mut u8, 0, PAGE_SIZE);
+        }
+        self.temporary_page.unmap(&mut self.active_table);
+
+        let page = Page::containing_address(address);
+        let allocator = &mut self.frame_allocator;
+        self.active_table.with(table, &mut self.temporary_page, |mapper| {
+            mapper.map_to(page, frame, flags, allocator)
+        });
+
+        true
+    }
+
+    /// Unmap a run of pages of an inactive table and free their frames
+    pub fn unmap_range_in(
+        &mut self,
+        table: &mut InactivePageTable,
+        start: VirtualAddress,
+        pages: usize,
+    ) {
+        let page = Page::containing_address(start);
+        let mut allocator = SharedFrameAllocator::new(&mut self.frame_allocator);
+        self.active_table.with(table, &mut self.temporary_page, |mapper| {
+            mapper.unmap_range(page, pages, &mut allocator)
+        });
+    }
+
+    /// Copy data into the pages of an inactive table, whatever their flags. Returns false if one
+    /// of them is not mapped.
+    pub fn write_in(
+        &mut self,
+        table: &mut InactivePageTable,
+        address: VirtualAddress,
+        data: &[u8],
+    ) -> bool {
+        let mut written = 0;
+        while written < data.len() {
+            let physical = match self.translate_in(table, address + written) {
+                Some(physical) => physical,
+                None => return false,
+            };
+
+            // Up to the end of the page, the next one can be anywhere in physical memory
+            let offset = physical % PAGE_SIZE;
+            let length = ::core::cmp::min(PAGE_SIZE - offset, data.len() - written);
+
+            let page = self.temporary_page
+                .map(Frame::containing_address(physical), &mut self.active_table);
+            unsafe {
+                ::core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    (page + offset) as *mut u8,
+                    length,
+                );
+            }
+            self.temporary_page.unmap(&mut self.active_table);
+
+            written += length;
+        }
+
+        true
+    }
+
+    /// Copy physical memory that is not mapped anywhere, like a boot module, into the buffer
+    pub fn read_physical(&mut self, address: PhysicalAddress, buffer: &mut [u8]) {
+        let mut read = 0;
+        while read < buffer.len() {
+            let offset = (address + read) % PAGE_SIZE;
+            let length = ::core::cmp::min(PAGE_SIZE - offset, buffer.len() - read);
+
+            let page = self.temporary_page
+                .map(Frame::containing_address(address + read), &mut self.active_table);
+            unsafe {
+                ::core::ptr::copy_nonoverlapping(
+                    (page + offset) as *const u8,
+                    buffer[read..].as_mut_ptr(),
+                    length,
+                );
+            }
+            self.temporary_page.unmap(&mut self.active_table);
+
+            read += length;
+        }
+    }
+
+    /// Share the mapped pages of one table with another. Writable pages become copy-on-write in
+    /// both. Returns false if too many frames are shared already.
+    pub fn share_pages(
+        &mut self,
+        from: &mut InactivePageTable,
+        to: &mut InactivePageTable,
+        start: VirtualAddress,
+        pages: usize,
+    ) -> bool {
+        for i in 0..pages {
+            let page = Page::containing_address(start + i * PAGE_SIZE);
+
+            let mut shared = None;
+            self.with_inactive(from, |mapper| shared = mapper.make_copy_on_write(page));
+            let (frame, flags) = match shared {
+                Some(shared) => shared,
+                None => continue,
+            };
+
+            // A copy-on-write page with a single reference is made writable on the next write
+            if !FRAME_REFCOUNTS.lock().share(&frame) {
+                return false;
+            }
+
+            let allocator = &mut self.frame_allocator;
+            self.active_table.with(to, &mut self.temporary_page, |mapper| {
+                mapper.map_to(page, frame, flags, allocator)
+            });
+        }
+
+        true
+    }
+
+    /// Translate a virtual address of an inactive table
+    pub fn translate_in(
+        &mut self,
+        table: &mut InactivePageTable,
+        address: VirtualAddress,
+    ) -> Option<PhysicalAddress> {
+        let mut physical = None;
+        self.with_inactive(table, |mapper| physical = mapper.translate(address));
+        physical
+    }
+
+    /// Make the table active. It has to be created by new_page_table and stay alive while active.
+    pub fn switch_page_table(&mut self, table: &InactivePageTable) {
+        self.active_table.switch(table);
+    }
+
+    /// Switch back to the page table that only maps the kernel
+    pub fn switch_to_kernel(&mut self) {
+        self.active_table.switch(&self.kernel_table);
+    }
+
+    pub fn is_active(&self, table: &InactivePageTable) -> bool {
+        self.active_table.is_active(table)
+    }
+
+    /// How the address is mapped in the active table, see Mapper::translate_detailed
+    pub fn translate_detailed(&self, address: VirtualAddress) -> Translation {
+        self.active_table.translate_detailed(address)
+    }
+
+    /// See protect_kernel
+    pub fn protect_kernel(&mut self, boot_info: &BootInformation) {
+        let elf_sections_tag = boot_info.elf_sections().expect("Elf sections tag required");
+        let string_table = elf_sections_tag.string_table(boot_info);
+
+        for section in elf_sections_tag.sections() {
+            // Only sections of the higher half kernel, the bootstrap sections are gone
+            if !section.is_allocated() || section.start_address() < KERNEL_VMA {
+                continue;
+            }
+
+            let name = string_table.section_name(section);
+            if section.size() == 0 || !READ_ONLY_AFTER_INIT.contains(&name) {
+                continue;
+            }
+
+            let pages = (section.size() + PAGE_SIZE - 1) / PAGE_SIZE;
+            self.active_table.update_flags_range(
+                Page::containing_address(section.start_address()),
+                pages,
+                EntryFlags::NO_EXECUTE,
+            );
+        }
+
+        let problems = paging::check_wx(&self.active_table);
+        assert!(
+            problems == 0,
+            "{} kernel mappings are writable and executable",
+            problems
+        );
+    }
+
+    /// Name of the stack whose guard page contains the given address
+    pub fn overflowed_stack(&self, address: VirtualAddress) -> Option<&'static str> {
+        match self.vma_allocator.find(address) {
+            Some(region)
+                if region.kind() == RegionKind::Stack && region.is_guard_page(address) =>
+            {
+                Some(region.owner())
+            }
+            _ => None,
+        }
+    }
+
+    // Allocate a region of kernel virtual memory and back all of its pages with frames
+    fn map_region(
+        &mut self,
+        size: usize,
+        guard_pages: usize,
+        flags: EntryFlags,
+        owner: &'static str,
+        kind: RegionKind,
+    ) -> Option<VirtualAddress> {
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let start = match self.vma_allocator.allocate(pages, guard_pages, owner, kind) {
+            Some(start) => start,
+            None => return None,
+        };
+
+        for i in 0..pages {
+            let page = Page::containing_address(start + i * PAGE_SIZE);
+            match self.frame_allocator.allocate_frame() {
+                Some(frame) => {
+                    self.active_table
+                        .map_to(page, frame, flags, &mut self.frame_allocator)
+                }
+                None => {
+                    // Out of memory, undo the pages mapped so far
+                    self.unmap_and_free(start, i);
+                    self.vma_allocator.free(start);
+                    return None;
+                }
+            }
+        }
+
+        Some(start)
+    }
+
+    /// Unmap and free a region returned by vmalloc
+    pub fn vfree(&mut self, start: VirtualAddress) {
+        let region = self.vma_allocator
+            .free(start)
+            .expect("vfree of an address that was not allocated by vmalloc");
+
+        self.unmap_and_free(start, region.pages());
+    }
+
+    // Unmap a run of pages and return their frames to the frame allocator
+    fn unmap_and_free(&mut self, start: VirtualAddress, pages: usize) {
+        let page = Page::containing_address(start);
+        self.active_table.unmap_range(page, pages, &mut self.frame_allocator);
+    }
+
+    // Give the faulting page a private frame if it is a copy-on-write page
+    fn handle_cow_fault(&mut self, address: VirtualAddress) -> bool {
+        let page = Page::containing_address(address);
+        let mut flags = match self.active_table.flags(page) {
+            Some(flags) if flags.contains(EntryFlags::COPY_ON_WRITE) => flags,
+            _ => return false,
+        };
+        flags.remove(EntryFlags::COPY_ON_WRITE | EntryFlags::ACCESSED | EntryFlags::DIRTY);
+        flags.insert(EntryFlags::WRITABLE);
+
+        let shared = self.active_table.translate_page(page).unwrap();
+        if FRAME_REFCOUNTS.lock().count(&shared) == 1 {
+            // Every other table has dropped the frame already, so there is nothing to copy
+            self.active_table.update_flags(page, flags);
+            return true;
+        }
+
+        let copy = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        let destination = self.temporary_page.map(copy.clone(), &mut self.active_table);
+        user::with_user_access(|| unsafe {
+            ::core::ptr::copy_nonoverlapping(
+                page.start_address() as *const u8,
+                destination as *mut u8,
+                PAGE_SIZE,
+            );
+        });
+        self.temporary_page.unmap(&mut self.active_table);
+
+        self.active_table.unmap(page, &mut self.frame_allocator);
+        self.active_table.map_to(page, copy, flags, &mut self.frame_allocator);
+        SharedFrameAllocator::new(&mut self.frame_allocator).deallocate_frame(shared);
+
+        true
+    }
+
+    // Back the faulting page with a fresh zeroed frame if it lies in a lazy region
+    fn handle_lazy_fault(
+        &mut self,
+        address: VirtualAddress,
+        error_code: PageFaultErrorCode,
+    ) -> bool {
+        let flags = match self.lazy_regions.find(address) {
+            Some(region) if region.permits(error_code) => region.flags(),
+            _ => return false,
+        };
+
+        let page = Page::containing_address(address);
+        let frame = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        // Map writable first so that the frame can be zeroed, then apply the real flags
+        self.active_table.map_to(
+            page,
+            frame,
+            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+            &mut self.frame_allocator,
+        );
+        unsafe {
+            ::core::ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE);
+        }
+        self.active_table.update_flags(page, flags);
+
+        true
+    }
+}
+
+/// Allocate mapped kernel virtual memory, see MemoryController::vmalloc
+pub fn vmalloc(
+    size: usize,
+    guard_pages: usize,
+    flags: EntryFlags,
+    owner: &'static str,
+) -> Option<VirtualAddress> {
+    MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .expect("Memory is not initialized")
+        .vmalloc(size, guard_pages, flags, owner)
+}
+
+/// Free memory returned by vmalloc
+pub fn vfree(start: VirtualAddress) {
+    MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .expect("Memory is not initialized")
+        .vfree(start)
+}
+
+/// Allocate a guarded kernel stack, see MemoryController::alloc_stack
+pub fn alloc_stack(pages: usize, owner: &'static str) -> Option<Stack> {
+    MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .expect("Memory is not initialized")
+        .alloc_stack(pages, owner)
+}
+
+/// Free a stack returned by alloc_stack
+pub fn free_stack(stack: Stack) {
+    MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .expect("Memory is not initialized")
+        .free_stack(stack)
+}
+
+/// Copy unmapped physical memory, see MemoryController::read_physical
+pub fn read_physical(address: PhysicalAddress, buffer: &mut [u8]) {
+    MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .expect("Memory is not initialized")
+        .read_physical(address, buffer)
+}
+
+/// Make read-only data and ro_after_init statics read-only and non-executable, then verify that no
+/// kernel page is writable and executable. Called once boot has completed.
+pub fn protect_kernel(boot_info: &BootInformation) {
+    MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .expect("Memory is not initialized")
+        .protect_kernel(boot_info)
+}
+
+/// Name of the stack that overflowed if the address lies in a stack guard page
+pub fn overflowed_stack(address: VirtualAddress) -> Option<&'static str> {
+    // The boot stack from boot.asm has its own guard page that is unmapped by remap_the_kernel
     extern "C" {
-        static _higher_start: u8;
-        static _end: u8;
+        static _guard_page: u8;
+    }
+    let boot_guard_page = unsafe { ((&_guard_page as *const u8) as *const usize) as usize };
+    if address >= boot_guard_page && address < boot_guard_page + PAGE_SIZE {
+        return Some("boot");
     }
 
-    // Drop the bootstrap code which lives before _higher_start
-    let kernel_start = unsafe { ((&_higher_start as *const u8) as *const usize) as usize };
-    let kernel_end = unsafe { ((&_end as *const u8) as *const usize) as usize };
+    MEMORY_CONTROLLER
+        .try_lock()
+        .and_then(|controller| controller.as_ref().and_then(|c| c.overflowed_stack(address)))
+}
 
-    let mut frame_allocator = AreaFrameAllocator::new(
-        kernel_start as usize,
-        kernel_end as usize,
-        boot_info.start_address() as usize,
-        boot_info.end_address() as usize,
-        memory_map.memory_areas(),
-    );
+/// Print all mappings of the active page table
+pub fn dump_page_table() {
+    MEMORY_CONTROLLER
+        .lock()
+        .as_ref()
+        .expect("Memory is not initialized")
+        .dump_page_table()
+}
 
-    enable_nxe_bit();
-    enable_write_protect_bit();
+/// Check the active page table for W+X pages, user accessible kernel pages and aliasing
+pub fn check_page_table() -> usize {
+    MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .expect("Memory is not initialized")
+        .check_page_table()
+}
 
-    remap_the_kernel(&mut frame_allocator, &boot_info);
+/// Print all reserved physical memory
+pub fn print_reservations() {
+    reserved::RESERVATIONS.lock().print();
+}
+
+/// How the address is mapped in the active table. None if the memory controller is in use, e.g.
+/// by the code that caused a page fault.
+pub fn translate_detailed(address: VirtualAddress) -> Option<Translation> {
+    MEMORY_CONTROLLER
+        .try_lock()
+        .and_then(|controller| controller.as_ref().map(|c| c.translate_detailed(address)))
+}
+
+/// Try to resolve a page fault. Returns false if the fault is a real error.
+pub fn handle_page_fault(address: VirtualAddress, error_code: PageFaultErrorCode) -> bool {
+    // Protection violations happen on present pages, only writes to copy-on-write pages can be
+    // fixed. Demand paging handles the faults on pages that are not present.
+    let protection_violation = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    if protection_violation && !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        return false;
+    }
+
+    // The memory controller is only taken if the code holding it faulted, which cannot be
+    // resolved without deadlocking
+    let mut controller = match MEMORY_CONTROLLER.try_lock() {
+        Some(controller) => controller,
+        None => return false,
+    };
+
+    match controller.as_mut() {
+        Some(controller) if protection_violation => controller.handle_cow_fault(address),
+        Some(controller) => controller.handle_lazy_fault(address, error_code),
+        None => false,
+    }
 }
 
 fn enable_nxe_bit() {
@@ -102,6 +811,42 @@ fn enable_nxe_bit() {
     }
 }
 
+fn enable_global_pages() {
+    if cpu::has_global_pages() {
+        unsafe {
+            cpu::cr4_set(cpu::CR4_GLOBAL_PAGES);
+        }
+    }
+}
+
+// The kernel faults when executing user pages
+fn enable_smep_bit() {
+    if cpu::has_smep() {
+        unsafe {
+            cpu::cr4_set(cpu::CR4_SMEP);
+        }
+    }
+}
+
+// The kernel faults when accessing user pages outside of the user copy helpers
+fn enable_smap_bit() {
+    if cpu::has_smap() {
+        unsafe {
+            cpu::cr4_set(cpu::CR4_SMAP);
+        }
+        user::smap_enabled();
+    }
+}
+
+// Instructions that leak kernel addresses, like sgdt and sidt, fault in user mode
+fn enable_umip_bit() {
+    if cpu::has_umip() {
+        unsafe {
+            cpu::cr4_set(cpu::CR4_UMIP);
+        }
+    }
+}
+
 fn enable_write_protect_bit() {
     use x86_64::registers::control_regs::{cr0, cr0_write, Cr0};
 
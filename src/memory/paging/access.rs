@@ -0,0 +1,61 @@
+use super::{pcid, sign_extend, VirtualAddress};
+use memory::Frame;
+
+/// How the mapper reaches the page tables and keeps the TLB in sync with them. The kernel walks
+/// its tables through the recursive mapping, while host tests use simulated physical memory.
+pub trait TableAccess: Copy {
+    /// Address of the table in the given frame, which the entry at index of the table at
+    /// table_address points to
+    fn next_table_address(
+        &self,
+        table_address: VirtualAddress,
+        index: usize,
+        frame: Frame,
+    ) -> VirtualAddress;
+
+    /// Invalidate the cached translation of a single page
+    fn flush_page(&self, address: VirtualAddress);
+
+    /// Invalidate the cached translations of all non-global pages of the current context
+    fn flush_context(&self);
+
+    /// Invalidate every cached translation, including global pages
+    fn flush_everything(&self);
+}
+
+/// Access that can reach any frame directly, so tables can be edited without being active
+pub trait DirectAccess: TableAccess {
+    /// Address at which the contents of the frame can be accessed
+    fn frame_address(&self, frame: Frame) -> VirtualAddress;
+}
+
+/// Access through the recursive entry of the active table
+#[derive(Debug, Clone, Copy)]
+pub struct Recursive;
+
+impl TableAccess for Recursive {
+    fn next_table_address(
+        &self,
+        table_address: VirtualAddress,
+        index: usize,
+        _frame: Frame,
+    ) -> VirtualAddress {
+        // Going through the recursive entry once more moves every index one level up
+        sign_extend((table_address << 9) | (index << 12))
+    }
+
+    fn flush_page(&self, address: VirtualAddress) {
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+
+        tlb::flush(VirtualAddress(address));
+    }
+
+    fn flush_context(&self) {
+        pcid::flush_context();
+    }
+
+    fn flush_everything(&self) {
+        pcid::flush_everything();
+    }
+}
@@ -0,0 +1,337 @@
+use core::{cmp, fmt};
+use memory::map::{KERNEL_SPACE_START, RECURSIVE_ENTRY};
+use super::{sign_extend, Mapper, PageSize, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
+use super::access::TableAccess;
+use super::entry::{Entry, EntryFlags};
+use super::table::{Level4, Table, TopLevel};
+
+// A run of virtually and physically contiguous pages with the same size and effective flags
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    physical: PhysicalAddress,
+    page_size: PageSize,
+    flags: EntryFlags,
+}
+
+impl Mapping {
+    fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    // Try to append the directly following page to this mapping
+    fn extend(&mut self, next: &Mapping) -> bool {
+        let contiguous = self.end == next.start && self.physical + self.size() == next.physical;
+        if contiguous && self.page_size == next.page_size && self.flags == next.flags {
+            self.end = next.end;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_writable_executable(&self) -> bool {
+        self.flags.contains(EntryFlags::WRITABLE) && !self.flags.contains(EntryFlags::NO_EXECUTE)
+    }
+
+    // Check if both mappings point to overlapping physical memory
+    fn aliases(&self, other: &Mapping) -> bool {
+        self.physical < other.physical + other.size()
+            && other.physical < self.physical + self.size()
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = match self.page_size {
+            PageSize::Small => "4K",
+            PageSize::Large => "2M",
+            PageSize::Huge => "1G",
+        };
+
+        let flag = |flag, set, unset| if self.flags.contains(flag) { set } else { unset };
+
+        write!(
+            f,
+            "{:016x}-{:016x} -> {:012x} {} {}r{}{}{}",
+            self.start,
+            self.end,
+            self.physical,
+            size,
+            flag(EntryFlags::USER_ACCESSIBLE, 'u', '-'),
+            flag(EntryFlags::WRITABLE, 'w', '-'),
+            flag(EntryFlags::NO_EXECUTE, '-', 'x'),
+            flag(EntryFlags::GLOBAL, 'g', '-')
+        )
+    }
+}
+
+/// Walk all present mappings of the mapper's table and call f for each coalesced run of pages.
+/// Inactive tables behind the recursive mapping have to be walked from inside
+/// ActivePageTable::with.
+pub fn walk<M, F>(mapper: &Mapper<M>, mut f: F)
+where
+    M: TableAccess,
+    F: FnMut(&Mapping),
+{
+    let mut current: Option<Mapping> = None;
+
+    // Inner scope to end the borrow of current and f by visit
+    {
+        let mut visit = |start: VirtualAddress,
+                         entry: &Entry,
+                         page_size: PageSize,
+                         mut flags: EntryFlags| {
+            // Bits that differ between otherwise identical pages would prevent coalescing
+            flags.remove(EntryFlags::ACCESSED | EntryFlags::DIRTY | EntryFlags::HUGE_PAGE);
+
+            let physical = entry.pointed_frame().unwrap().start_address();
+            let page = Mapping {
+                start,
+                end: start + page_size.bytes(),
+                physical,
+                page_size,
+                flags,
+            };
+
+            current = match current.take() {
+                Some(mut mapping) => {
+                    if !mapping.extend(&page) {
+                        f(&mapping);
+                        mapping = page;
+                    }
+                    Some(mapping)
+                }
+                None => Some(page),
+            };
+        };
+
+        walk_root(mapper.root(), mapper.access(), &mut visit);
+    }
+
+    if let Some(mapping) = current {
+        f(&mapping);
+    }
+}
+
+#[cfg(not(feature = "la57"))]
+fn walk_root<M, F>(p4: &Table<TopLevel>, access: M, visit: &mut F)
+where
+    M: TableAccess,
+    F: FnMut(VirtualAddress, &Entry, PageSize, EntryFlags),
+{
+    walk_p4(p4, 0, EntryFlags::unrestricted(), true, access, visit);
+}
+
+#[cfg(feature = "la57")]
+fn walk_root<M, F>(p5: &Table<TopLevel>, access: M, visit: &mut F)
+where
+    M: TableAccess,
+    F: FnMut(VirtualAddress, &Entry, PageSize, EntryFlags),
+{
+    for i5 in 0..ENTRY_COUNT {
+        // The recursive entry maps the tables themselves
+        if i5 == RECURSIVE_ENTRY {
+            continue;
+        }
+
+        if let Some(p4) = p5.next_table(i5, access) {
+            walk_p4(p4, i5, p5[i5].flags(), false, access, visit);
+        }
+    }
+}
+
+// Visit the mappings of a P4 table. The recursive entry is in it if it is the root table.
+fn walk_p4<M, F>(
+    p4: &Table<Level4>,
+    i5: usize,
+    parent_flags: EntryFlags,
+    root: bool,
+    access: M,
+    visit: &mut F,
+) where
+    M: TableAccess,
+    F: FnMut(VirtualAddress, &Entry, PageSize, EntryFlags),
+{
+    for i4 in 0..ENTRY_COUNT {
+        // The recursive entry maps the tables themselves
+        if root && i4 == RECURSIVE_ENTRY {
+            continue;
+        }
+
+        let p3 = match p4.next_table(i4, access) {
+            Some(p3) => p3,
+            None => continue,
+        };
+        let p4_flags = p4[i4].flags().combine_with_parent(parent_flags);
+
+        for i3 in 0..ENTRY_COUNT {
+            let p3_flags = p3[i3].flags().combine_with_parent(p4_flags);
+            if !p3_flags.contains(EntryFlags::PRESENT) {
+                continue;
+            }
+
+            if p3_flags.contains(EntryFlags::HUGE_PAGE) {
+                visit(address(i5, i4, i3, 0, 0), &p3[i3], PageSize::Huge, p3_flags);
+                continue;
+            }
+
+            let p2 = p3.next_table(i3, access).unwrap();
+            for i2 in 0..ENTRY_COUNT {
+                let p2_flags = p2[i2].flags().combine_with_parent(p3_flags);
+                if !p2_flags.contains(EntryFlags::PRESENT) {
+                    continue;
+                }
+
+                if p2_flags.contains(EntryFlags::HUGE_PAGE) {
+                    visit(address(i5, i4, i3, i2, 0), &p2[i2], PageSize::Large, p2_flags);
+                    continue;
+                }
+
+                let p1 = p2.next_table(i2, access).unwrap();
+                for i1 in 0..ENTRY_COUNT {
+                    let p1_flags = p1[i1].flags().combine_with_parent(p2_flags);
+                    if p1_flags.contains(EntryFlags::PRESENT) {
+                        visit(address(i5, i4, i3, i2, i1), &p1[i1], PageSize::Small, p1_flags);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Print all mappings of a page table, similar to QEMU's info mem
+pub fn print<M>(mapper: &Mapper<M>)
+where
+    M: TableAccess,
+{
+    walk(mapper, |mapping| println!("{}", mapping));
+}
+
+/// Count the coalesced mappings of the mapper's table, e.g. to size the buffer for check
+pub fn count_mappings<M>(mapper: &Mapper<M>) -> usize
+where
+    M: TableAccess,
+{
+    let mut count = 0;
+    walk(mapper, |_| count += 1);
+    count
+}
+
+/// Print every mapping that is writable and executable, user accessible in the kernel half or
+/// aliased with another mapping. The buffer holds the mappings while looking for aliases.
+/// Returns the number of problems found.
+pub fn check<M>(mapper: &Mapper<M>, buffer: &mut [Option<Mapping>]) -> usize
+where
+    M: TableAccess,
+{
+    let mut problems = 0;
+
+    walk(mapper, |mapping| {
+        if mapping.is_writable_executable() {
+            println!("W+X: {}", mapping);
+            problems += 1;
+        }
+
+        let user = mapping.flags.contains(EntryFlags::USER_ACCESSIBLE);
+        if user && mapping.start >= KERNEL_SPACE_START {
+            println!("User accessible kernel page: {}", mapping);
+            problems += 1;
+        }
+    });
+
+    let count = collect(mapper, buffer);
+    if count > buffer.len() {
+        println!("Only {} of {} mappings were checked for aliasing", buffer.len(), count);
+    }
+
+    let checked = &mut buffer[..cmp::min(count, buffer.len())];
+    problems += find_aliases(checked, |first, second| {
+        println!("Aliased: {}\n     and {}", first, second);
+    });
+
+    problems
+}
+
+/// Store the mappings of the mapper's table in the buffer. Returns the number of mappings, which
+/// is larger than the buffer if not all of them fit.
+pub fn collect<M>(mapper: &Mapper<M>, buffer: &mut [Option<Mapping>]) -> usize
+where
+    M: TableAccess,
+{
+    let mut count = 0;
+    walk(mapper, |mapping| {
+        if count < buffer.len() {
+            buffer[count] = Some(*mapping);
+        }
+        count += 1;
+    });
+    count
+}
+
+/// Sort the mappings by physical address and call f for every mapping that overlaps one before
+/// it. Comparing against the mapping that reaches furthest so far is enough, because every
+/// earlier mapping starts at or below the current one. Returns the number of aliases.
+pub fn find_aliases<F>(mappings: &mut [Option<Mapping>], mut f: F) -> usize
+where
+    F: FnMut(&Mapping, &Mapping),
+{
+    mappings.sort_unstable_by_key(|mapping| mapping.map(|mapping| mapping.physical));
+
+    let mut furthest: Option<Mapping> = None;
+    let mut aliases = 0;
+
+    for mapping in mappings.iter().filter_map(|mapping| *mapping) {
+        let end = mapping.physical + mapping.size();
+        furthest = match furthest {
+            Some(previous) => {
+                if previous.aliases(&mapping) {
+                    f(&previous, &mapping);
+                    aliases += 1;
+                }
+
+                if previous.physical + previous.size() < end {
+                    Some(mapping)
+                } else {
+                    Some(previous)
+                }
+            }
+            None => Some(mapping),
+        };
+    }
+
+    aliases
+}
+
+/// Print every kernel mapping that is writable and executable. Returns the number of mappings.
+pub fn check_wx<M>(mapper: &Mapper<M>) -> usize
+where
+    M: TableAccess,
+{
+    let mut problems = 0;
+
+    walk(mapper, |mapping| {
+        if mapping.start >= KERNEL_SPACE_START && mapping.is_writable_executable() {
+            println!("W+X: {}", mapping);
+            problems += 1;
+        }
+    });
+
+    problems
+}
+
+// Build the sign extended virtual address from table indices. The P5 index is always zero
+// without five-level paging.
+fn address(
+    p5_index: usize,
+    p4_index: usize,
+    p3_index: usize,
+    p2_index: usize,
+    p1_index: usize,
+) -> VirtualAddress {
+    sign_extend(
+        (p5_index << 48) | (p4_index << 39) | (p3_index << 30) | (p2_index << 21)
+            | (p1_index << 12),
+    )
+}
@@ -2,6 +2,7 @@ use memory::Frame;
 use multiboot2::ElfSection;
 
 // A single entry into the page table. An unused entry is defined to be 0
+#[derive(Clone)]
 pub struct Entry(u64);
 
 impl Entry {
@@ -28,6 +29,10 @@ impl Entry {
         }
     }
 
+    pub fn insert_flags(&mut self, flags: EntryFlags) {
+        self.0 |= flags.bits();
+    }
+
     pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
         // Assert the address doesn't have any non-address bits set
         assert!(frame.start_address() & !0x000fffff_fffff000 == 0);
@@ -55,12 +60,39 @@ bitflags! {
         const HUGE_PAGE       = 1 << 7;
         // Page not flushed on address space switch
         const GLOBAL          = 1 << 8;
+        // Available to the OS. Shared read-only page that is copied on the first write
+        const COPY_ON_WRITE   = 1 << 9;
         // Forbid code execution
         const NO_EXECUTE      = 1 << 63;
     }
 }
 
 impl EntryFlags {
+    // Flags of a parent entry that leave the flags of its children unchanged when combined
+    pub fn unrestricted() -> EntryFlags {
+        EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE
+    }
+
+    // Combine with the flags of the entry one level up. User and write access have to be granted
+    // at every level of the walk while no execute on any level applies to the whole mapping.
+    pub fn combine_with_parent(self, parent: EntryFlags) -> EntryFlags {
+        let mut flags = self;
+
+        if !parent.contains(EntryFlags::WRITABLE) {
+            flags.remove(EntryFlags::WRITABLE);
+        }
+
+        if !parent.contains(EntryFlags::USER_ACCESSIBLE) {
+            flags.remove(EntryFlags::USER_ACCESSIBLE);
+        }
+
+        if parent.contains(EntryFlags::NO_EXECUTE) {
+            flags.insert(EntryFlags::NO_EXECUTE);
+        }
+
+        flags
+    }
+
     pub fn from_elf_section(section: &ElfSection) -> EntryFlags {
         use multiboot2::{ELF_SECTION_ALLOCATED, ELF_SECTION_EXECUTABLE, ELF_SECTION_WRITABLE};
         let mut flags = EntryFlags::empty();
@@ -79,4 +111,22 @@ impl EntryFlags {
 
         flags
     }
+
+    // Flags of a loadable segment of an ELF program with the given p_flags. Segments are always
+    // mapped, readable or not.
+    pub fn from_elf_segment(segment_flags: u32) -> EntryFlags {
+        const SEGMENT_EXECUTABLE: u32 = 1 << 0;
+        const SEGMENT_WRITABLE: u32 = 1 << 1;
+        let mut flags = EntryFlags::PRESENT;
+
+        if segment_flags & SEGMENT_WRITABLE != 0 {
+            flags |= EntryFlags::WRITABLE;
+        }
+
+        if segment_flags & SEGMENT_EXECUTABLE == 0 {
+            flags |= EntryFlags::NO_EXECUTE;
+        }
+
+        flags
+    }
 }
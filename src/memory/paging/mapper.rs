@@ -1,17 +1,60 @@
-use super::{Page, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
-use super::entry::EntryFlags;
-use super::table::{self, Level4, Table};
+use super::{InactivePageTable, Page, PageSize, PhysicalAddress, VirtualAddress, ENTRY_COUNT};
+use super::access::{DirectAccess, Recursive, TableAccess};
+use super::entry::{Entry, EntryFlags};
+use super::table::{self, Level4, Table, TopLevel};
+use memory::map::KERNEL_SPACE_START;
 use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use core::ops::Range;
 use core::ptr::Unique;
 
-pub struct Mapper {
-    p4: Unique<Table<Level4>>,
+/// How an address is mapped, see Mapper::translate_detailed
+#[derive(Debug)]
+pub enum Translation {
+    /// Mapped by a page of the given size starting at frame. The flags are the effective ones:
+    /// user and write access only if every level grants them, no execute if any level sets it.
+    Mapped {
+        frame: Frame,
+        physical: PhysicalAddress,
+        page_size: PageSize,
+        flags: EntryFlags,
+    },
+    /// The entry in the table of the given level was not present, 4 for the P4 table and 5 for
+    /// the P5 table with five-level paging
+    NotMapped { level: usize },
+}
+
+pub struct Mapper<M: TableAccess = Recursive> {
+    root: Unique<Table<TopLevel>>,
+    access: M,
 }
 
 impl Mapper {
     pub unsafe fn new() -> Mapper {
+        Mapper::from_root(table::ROOT, Recursive)
+    }
+}
+
+impl<M> Mapper<M>
+where
+    M: DirectAccess,
+{
+    /// Mapper for a table that is not active. Unlike ActivePageTable::with this works without
+    /// touching the recursive mapping, since every frame can be reached directly.
+    pub unsafe fn for_inactive(table: &InactivePageTable, access: M) -> Mapper<M> {
+        let root = access.frame_address(table.p4_frame().clone());
+        Mapper::from_root(root as *mut _, access)
+    }
+}
+
+impl<M> Mapper<M>
+where
+    M: TableAccess,
+{
+    /// Mapper for the root table at the given address, whose tables are reached through access
+    pub unsafe fn from_root(root: *mut Table<TopLevel>, access: M) -> Mapper<M> {
         Mapper {
-            p4: Unique::new_unchecked(table::P4),
+            root: Unique::new_unchecked(root),
+            access,
         }
     }
 
@@ -34,27 +77,159 @@ impl Mapper {
     where
         A: FrameAllocator,
     {
-        let p4 = self.p4_mut();
-        let p3 = p4.next_table_or_create(page.p4_index(), allocator);
-        let p2 = p3.next_table_or_create(page.p3_index(), allocator);
-        let p1 = p2.next_table_or_create(page.p2_index(), allocator);
+        let access = self.access;
+        let p4 = self.p4_or_create(page, flags, allocator);
+        let p3 = p4.next_table_or_create(page.p4_index(), flags, access, allocator);
+        let p2 = p3.next_table_or_create(page.p3_index(), flags, access, allocator);
+        let p1 = p2.next_table_or_create(page.p2_index(), flags, access, allocator);
 
         // Assert page is unmapped
         assert!(p1[page.p1_index()].is_unused());
 
-        p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+        p1[page.p1_index()].set(frame, leaf_flags(page, flags));
+    }
+
+    // Create the table below the top level entry covering the address so that the entry never
+    // changes afterwards. Address spaces copy the kernel top level entries once, so the kernel
+    // half must not grow new ones.
+    pub fn create_kernel_table<A>(&mut self, address: VirtualAddress, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let page = Page::containing_address(address);
+        let access = self.access;
+        self.root_mut()
+            .next_table_or_create(page.root_index(), EntryFlags::empty(), access, allocator);
+    }
+
+    // The table CR3 points to, a P4 table or a P5 table with five-level paging
+    pub fn root(&self) -> &Table<TopLevel> {
+        unsafe { self.root.as_ref() }
+    }
+
+    pub fn root_mut(&mut self) -> &mut Table<TopLevel> {
+        unsafe { self.root.as_mut() }
+    }
+
+    pub fn access(&self) -> M {
+        self.access
+    }
+
+    // The P4 table covering the page, which is the root table itself without five-level paging
+    #[cfg(not(feature = "la57"))]
+    fn p4(&self, _page: Page) -> Option<&Table<Level4>> {
+        Some(self.root())
+    }
+
+    #[cfg(feature = "la57")]
+    fn p4(&self, page: Page) -> Option<&Table<Level4>> {
+        let access = self.access;
+        self.root().next_table(page.p5_index(), access)
+    }
+
+    #[cfg(not(feature = "la57"))]
+    fn p4_mut(&mut self, _page: Page) -> Option<&mut Table<Level4>> {
+        Some(self.root_mut())
+    }
+
+    #[cfg(feature = "la57")]
+    fn p4_mut(&mut self, page: Page) -> Option<&mut Table<Level4>> {
+        let access = self.access;
+        self.root_mut().next_table_mut(page.p5_index(), access)
+    }
+
+    #[cfg(not(feature = "la57"))]
+    fn p4_or_create<A>(
+        &mut self,
+        _page: Page,
+        _flags: EntryFlags,
+        _allocator: &mut A,
+    ) -> &mut Table<Level4>
+    where
+        A: FrameAllocator,
+    {
+        self.root_mut()
+    }
+
+    #[cfg(feature = "la57")]
+    fn p4_or_create<A>(
+        &mut self,
+        page: Page,
+        flags: EntryFlags,
+        allocator: &mut A,
+    ) -> &mut Table<Level4>
+    where
+        A: FrameAllocator,
+    {
+        let access = self.access;
+        self.root_mut()
+            .next_table_or_create(page.p5_index(), flags, access, allocator)
+    }
+
+    /// Walk the tables like the MMU does and report how the address is mapped
+    pub fn translate_detailed(&self, address: VirtualAddress) -> Translation {
+        let access = self.access;
+        let page = Page::containing_address(address);
+
+        let (p4, parent_flags) = match self.p4_and_flags(page) {
+            Some(p4) => p4,
+            None => return Translation::NotMapped { level: 5 },
+        };
+
+        let p4_flags = p4[page.p4_index()].flags().combine_with_parent(parent_flags);
+        let p3 = match p4.next_table(page.p4_index(), access) {
+            Some(p3) => p3,
+            None => return Translation::NotMapped { level: 4 },
+        };
+
+        let p3_entry = &p3[page.p3_index()];
+        let p3_flags = p3_entry.flags().combine_with_parent(p4_flags);
+        if !p3_flags.contains(EntryFlags::PRESENT) {
+            return Translation::NotMapped { level: 3 };
+        }
+        if p3_flags.contains(EntryFlags::HUGE_PAGE) {
+            return translation(p3_entry, address, PageSize::Huge, p3_flags);
+        }
+
+        let p2 = p3.next_table(page.p3_index(), access).unwrap();
+        let p2_entry = &p2[page.p2_index()];
+        let p2_flags = p2_entry.flags().combine_with_parent(p3_flags);
+        if !p2_flags.contains(EntryFlags::PRESENT) {
+            return Translation::NotMapped { level: 2 };
+        }
+        if p2_flags.contains(EntryFlags::HUGE_PAGE) {
+            return translation(p2_entry, address, PageSize::Large, p2_flags);
+        }
+
+        let p1 = p2.next_table(page.p2_index(), access).unwrap();
+        let p1_entry = &p1[page.p1_index()];
+        let p1_flags = p1_entry.flags().combine_with_parent(p2_flags);
+        if !p1_flags.contains(EntryFlags::PRESENT) {
+            return Translation::NotMapped { level: 1 };
+        }
+
+        translation(p1_entry, address, PageSize::Small, p1_flags)
     }
 
-    pub fn p4(&self) -> &Table<Level4> {
-        unsafe { self.p4.as_ref() }
+    // The P4 table covering the page and the flags of the entries above it
+    #[cfg(not(feature = "la57"))]
+    fn p4_and_flags(&self, _page: Page) -> Option<(&Table<Level4>, EntryFlags)> {
+        Some((self.root(), EntryFlags::unrestricted()))
     }
 
-    pub fn p4_mut(&mut self) -> &mut Table<Level4> {
-        unsafe { self.p4.as_mut() }
+    #[cfg(feature = "la57")]
+    fn p4_and_flags(&self, page: Page) -> Option<(&Table<Level4>, EntryFlags)> {
+        let access = self.access;
+        let flags = self.root()[page.p5_index()].flags();
+        self.root()
+            .next_table(page.p5_index(), access)
+            .map(|p4| (p4, flags))
     }
 
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
-        let p3 = self.p4().next_table(page.p4_index());
+        let access = self.access;
+        let p3 = self.p4(page)
+            .and_then(|p4| p4.next_table(page.p4_index(), access));
 
         let huge_page = || {
             p3.and_then(|p3| {
@@ -73,7 +248,7 @@ impl Mapper {
                     }
                 }
 
-                if let Some(p2) = p3.next_table(page.p3_index()) {
+                if let Some(p2) = p3.next_table(page.p3_index(), access) {
                     let p2_entry = &p2[page.p2_index()];
 
                     // Is 2 MiB page?
@@ -93,26 +268,148 @@ impl Mapper {
             })
         };
 
-        p3.and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
+        p3.and_then(|p3| p3.next_table(page.p3_index(), access))
+            .and_then(|p2| p2.next_table(page.p2_index(), access))
             .and_then(|p1| p1[page.p1_index()].pointed_frame())
             .or_else(huge_page)
     }
 
-    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+    // Flags of a page mapped by a P1 entry
+    pub fn flags(&self, page: Page) -> Option<EntryFlags> {
+        let access = self.access;
+        let flags = self.p4(page)
+            .and_then(|p4| p4.next_table(page.p4_index(), access))
+            .and_then(|p3| p3.next_table(page.p3_index(), access))
+            .and_then(|p2| p2.next_table(page.p2_index(), access))
+            .map(|p1| p1[page.p1_index()].flags());
+
+        match flags {
+            Some(flags) if flags.contains(EntryFlags::PRESENT) => Some(flags),
+            _ => None,
+        }
+    }
+
+    // Turn a writable page into a read-only copy-on-write page so that its frame can be shared.
+    // Returns the frame and the flags it has to be mapped with by the other sharers.
+    pub fn make_copy_on_write(&mut self, page: Page) -> Option<(Frame, EntryFlags)> {
+        let mut flags = match self.flags(page) {
+            Some(flags) => flags,
+            None => return None,
+        };
+
+        flags.remove(EntryFlags::ACCESSED | EntryFlags::DIRTY);
+        if flags.contains(EntryFlags::WRITABLE) {
+            flags.remove(EntryFlags::WRITABLE);
+            flags.insert(EntryFlags::COPY_ON_WRITE);
+            self.update_flags(page, flags);
+        }
+
+        self.translate_page(page).map(|frame| (frame, flags))
+    }
+
+    // Change the flags of an already mapped page
+    pub fn update_flags(&mut self, page: Page, flags: EntryFlags) {
+        self.set_flags(page, flags);
+        self.access.flush_page(page.start_address());
+    }
+
+    // Change the flags of a run of mapped pages, invalidating the TLB entries once at the end
+    pub fn update_flags_range(&mut self, start: Page, pages: usize, flags: EntryFlags) {
+        let mut batch = FlushBatch::new();
+        for i in 0..pages {
+            let page = start.offset(i);
+            self.set_flags(page, flags);
+            batch.add(page);
+        }
+        batch.flush(self.access);
+    }
+
+    fn set_flags(&mut self, page: Page, flags: EntryFlags) {
+        let access = self.access;
+        let p1 = self.p4_mut(page)
+            .and_then(|p4| p4.next_table_mut(page.p4_index(), access))
+            .and_then(|p3| p3.next_table_mut(page.p3_index(), access))
+            .and_then(|p2| p2.next_table_mut(page.p2_index(), access))
+            .expect("Mapping code does not support huge pages");
+
+        let frame = p1[page.p1_index()]
+            .pointed_frame()
+            .expect("Page is not mapped");
+        p1[page.p1_index()].set(frame, leaf_flags(page, flags));
+    }
+
+    // Unmap everything in the lower half and free the mapped frames together with the tables
+    // mapping them. Only used to tear down user address spaces, which own all of those frames.
+    #[cfg(not(feature = "la57"))]
+    pub fn free_lower_half<A>(&mut self, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let access = self.access;
+        free_p4_entries(self.root_mut(), 0..ENTRY_COUNT / 2, access, allocator);
+    }
+
+    #[cfg(feature = "la57")]
+    pub fn free_lower_half<A>(&mut self, allocator: &mut A)
+    where
+        A: FrameAllocator,
+    {
+        let access = self.access;
+        let p5 = self.root_mut();
+        for i5 in 0..ENTRY_COUNT / 2 {
+            if p5[i5].is_unused() {
+                continue;
+            }
+
+            // Inner scope to end the borrow of the P5 table by the P4 table
+            {
+                let p4 = p5.next_table_mut(i5, access).expect("Huge pages in user space");
+                free_p4_entries(p4, 0..ENTRY_COUNT, access, allocator);
+            }
+
+            free_table(&mut p5[i5], allocator);
+        }
+    }
+
+    // Unmap a page and return the frame it was mapped to. The frame is not deallocated since it
+    // may not be owned by the caller, e.g. the table frame behind a temporary page.
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+    where
+        A: FrameAllocator,
+    {
+        let frame = self.clear_entry(page);
+        self.access.flush_page(page.start_address());
+
+        // TODO: free p1/2/3 if empty
+
+        frame
+    }
+
+    // Unmap a run of pages and deallocate their frames, invalidating the TLB entries once at the
+    // end. Unlike unmap the frames have to be owned by the caller.
+    pub fn unmap_range<A>(&mut self, start: Page, pages: usize, allocator: &mut A)
     where
         A: FrameAllocator,
     {
-        use x86_64::instructions::tlb;
-        use x86_64::VirtualAddress;
+        let mut batch = FlushBatch::new();
+        for i in 0..pages {
+            let page = start.offset(i);
+            let frame = self.clear_entry(page);
+            batch.add(page);
+            allocator.deallocate_frame(frame);
+        }
+        batch.flush(self.access);
+    }
 
+    fn clear_entry(&mut self, page: Page) -> Frame {
         // Assert page is mapped
         assert!(self.translate(page.start_address()).is_some());
 
-        let p1 = self.p4_mut()
-            .next_table_mut(page.p4_index())
-            .and_then(|p3| p3.next_table_mut(page.p3_index()))
-            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+        let access = self.access;
+        let p1 = self.p4_mut(page)
+            .and_then(|p4| p4.next_table_mut(page.p4_index(), access))
+            .and_then(|p3| p3.next_table_mut(page.p3_index(), access))
+            .and_then(|p2| p2.next_table_mut(page.p2_index(), access))
             .expect("Mapping code does not support huge pages");
 
         let frame = p1[page.p1_index()].pointed_frame().unwrap();
@@ -120,10 +417,149 @@ impl Mapper {
         // Set p1 frame unused
         p1[page.p1_index()].set_unused();
 
-        tlb::flush(VirtualAddress(page.start_address()));
+        frame
+    }
+}
 
-        // TODO: free p1/2/3 if empty
+// Free the tables and frames mapped by a range of P4 entries
+fn free_p4_entries<M, A>(
+    p4: &mut Table<Level4>,
+    entries: Range<usize>,
+    access: M,
+    allocator: &mut A,
+) where
+    M: TableAccess,
+    A: FrameAllocator,
+{
+    for i4 in entries {
+        if p4[i4].is_unused() {
+            continue;
+        }
+
+        // Inner scopes to end the borrow of each table by its next table
+        {
+            let p3 = p4.next_table_mut(i4, access).expect("Huge pages in user space");
+            for i3 in 0..ENTRY_COUNT {
+                if p3[i3].is_unused() {
+                    continue;
+                }
+
+                {
+                    let p2 = p3.next_table_mut(i3, access).expect("Huge pages in user space");
+                    for i2 in 0..ENTRY_COUNT {
+                        if p2[i2].is_unused() {
+                            continue;
+                        }
+
+                        {
+                            let p1 = p2.next_table_mut(i2, access)
+                                .expect("Huge pages in user space");
+                            for i1 in 0..ENTRY_COUNT {
+                                if let Some(frame) = p1[i1].pointed_frame() {
+                                    allocator.deallocate_frame(frame);
+                                }
+                                p1[i1].set_unused();
+                            }
+                        }
+
+                        free_table(&mut p2[i2], allocator);
+                    }
+                }
+
+                free_table(&mut p3[i3], allocator);
+            }
+        }
+
+        free_table(&mut p4[i4], allocator);
+    }
+}
+
+// Translation of an address mapped by a present leaf entry
+fn translation(
+    entry: &Entry,
+    address: VirtualAddress,
+    page_size: PageSize,
+    flags: EntryFlags,
+) -> Translation {
+    let frame = entry.pointed_frame().unwrap();
+    let physical = frame.start_address() + address % page_size.bytes();
+
+    Translation::Mapped {
+        frame,
+        physical,
+        page_size,
+        flags,
+    }
+}
+
+// Clear an entry pointing to a table that is no longer used and free the table's frame
+fn free_table<A>(entry: &mut Entry, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    let frame = entry.pointed_frame().unwrap();
+    entry.set_unused();
+    allocator.deallocate_frame(frame);
+}
+
+// Maximum number of pages invalidated one by one before flushing whole contexts instead
+const MAX_FLUSH_BATCH: usize = 32;
+
+// Collects the pages changed by a range operation so that their TLB entries are invalidated
+// together. Large batches flush everything instead of issuing one invlpg per page.
+struct FlushBatch {
+    pages: [VirtualAddress; MAX_FLUSH_BATCH],
+    count: usize,
+    overflowed: bool,
+    // Kernel pages are global and survive a flush of the current context only
+    global: bool,
+}
+
+impl FlushBatch {
+    fn new() -> FlushBatch {
+        FlushBatch {
+            pages: [0; MAX_FLUSH_BATCH],
+            count: 0,
+            overflowed: false,
+            global: false,
+        }
+    }
+
+    fn add(&mut self, page: Page) {
+        if page.start_address() >= KERNEL_SPACE_START {
+            self.global = true;
+        }
+
+        if self.count < MAX_FLUSH_BATCH {
+            self.pages[self.count] = page.start_address();
+            self.count += 1;
+        } else {
+            self.overflowed = true;
+        }
+    }
+
+    fn flush<M>(self, access: M)
+    where
+        M: TableAccess,
+    {
+        if !self.overflowed {
+            for &address in &self.pages[..self.count] {
+                access.flush_page(address);
+            }
+        } else if self.global {
+            access.flush_everything();
+        } else {
+            access.flush_context();
+        }
+    }
+}
 
-        //allocator.deallocate_frame(frame);
+// Flags of the P1 entry mapping a page. The kernel half is the same in every address space, so its
+// pages are global and stay in the TLB across address space switches.
+fn leaf_flags(page: Page, flags: EntryFlags) -> EntryFlags {
+    if page.start_address() >= KERNEL_SPACE_START {
+        flags | EntryFlags::PRESENT | EntryFlags::GLOBAL
+    } else {
+        flags | EntryFlags::PRESENT
     }
 }
@@ -1,14 +1,24 @@
+mod access;
+mod dump;
 mod entry;
 mod mapper;
+mod pcid;
 mod table;
 mod temporary_page;
+#[cfg(test)]
+mod tests;
 
 use core::ops::{Deref, DerefMut};
 use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use memory::reserved::{ReservationKind, RESERVATIONS};
+pub use self::dump::{check, check_wx, count_mappings, print, Mapping};
 pub use self::entry::{Entry, EntryFlags};
-use self::temporary_page::TemporaryPage;
-use self::mapper::Mapper;
-use super::map::{KERNEL_VMA, RECURSIVE_ENTRY, TEMP_PAGE, VGA_BUFFER_VMA};
+pub use self::temporary_page::TemporaryPage;
+pub use self::mapper::{Mapper, Translation};
+pub use self::pcid::init as enable_pcids;
+use self::pcid::{Pcid, NO_PCID};
+use super::map::{KERNEL_SPACE_START, KERNEL_VMA, RECURSIVE_ENTRY, TEMP_PAGE, USER_SPACE_END};
+use super::map::{VGA_BUFFER_VMA, VIRTUAL_ADDRESS_BITS};
 use multiboot2::BootInformation;
 
 // Number of entries per page table
@@ -17,8 +27,40 @@ const ENTRY_COUNT: usize = 512;
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
-/// Remap the kernel sections properly
-pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation)
+// Sizes of the pages that can be mapped by the table hierarchy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    // 4 KiB page mapped by a P1 entry
+    Small,
+    // 2 MiB page mapped by a P2 entry
+    Large,
+    // 1 GiB page mapped by a P3 entry
+    Huge,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> usize {
+        match *self {
+            PageSize::Small => PAGE_SIZE,
+            PageSize::Large => PAGE_SIZE * ENTRY_COUNT,
+            PageSize::Huge => PAGE_SIZE * ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+}
+
+// Fill the bits above the highest implemented address bit with copies of it, which makes the
+// address canonical
+fn sign_extend(address: usize) -> VirtualAddress {
+    let shift = 64 - VIRTUAL_ADDRESS_BITS;
+    (((address << shift) as isize) >> shift) as usize
+}
+
+/// Remap the kernel sections properly. Returns the active page table and the kernel page table
+/// that is now active.
+pub fn remap_the_kernel<A>(
+    allocator: &mut A,
+    boot_info: &BootInformation,
+) -> (ActivePageTable, InactivePageTable)
 where
     A: FrameAllocator,
 {
@@ -80,7 +122,7 @@ where
         mapper.map_to(
             Page::containing_address(VGA_BUFFER_VMA),
             Frame::containing_address(0xb8000),
-            EntryFlags::WRITABLE,
+            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
             allocator,
         );
 
@@ -92,7 +134,7 @@ where
             mapper.map_to(
                 Page::containing_address(frame.start_address()),
                 Frame::containing_address(frame.start_address() - KERNEL_VMA),
-                EntryFlags::PRESENT,
+                EntryFlags::PRESENT | EntryFlags::NO_EXECUTE,
                 allocator,
             );
         }
@@ -101,7 +143,40 @@ where
         mapper.unmap(Page::containing_address(guard_page_addr), allocator);
     });
 
-    active_table.switch(new_table);
+    let boot_table = active_table.current_table();
+    active_table.switch(&new_table);
+    reclaim_bootstrap(boot_table, allocator);
+
+    (active_table, new_table)
+}
+
+// Return the frames of the bootstrap sections to the allocator. Nothing in them is used once the
+// new page table is active: the GDT was moved to the higher half and the stack was switched in
+// long_mode_start. This includes the boot page tables, so the old table is consumed here.
+fn reclaim_bootstrap<A>(boot_table: InactivePageTable, allocator: &mut A)
+where
+    A: FrameAllocator,
+{
+    extern "C" {
+        static _start: u8;
+        static _bootstrap_end: u8;
+    }
+
+    let bootstrap_start = unsafe { ((&_start as *const u8) as *const usize) as usize };
+    let bootstrap_end = unsafe { ((&_bootstrap_end as *const u8) as *const usize) as usize };
+
+    let start_frame = Frame::containing_address(bootstrap_start - KERNEL_VMA);
+    let end_frame = Frame::containing_address(bootstrap_end - KERNEL_VMA - 1);
+
+    assert!(
+        boot_table.p4_frame >= start_frame && boot_table.p4_frame <= end_frame,
+        "Boot page table is not part of the bootstrap sections"
+    );
+
+    RESERVATIONS.lock().release(ReservationKind::Bootstrap);
+    for frame in Frame::range_inclusive(start_frame, end_frame) {
+        allocator.deallocate_frame(frame);
+    }
 }
 
 pub struct ActivePageTable {
@@ -138,52 +213,75 @@ impl ActivePageTable {
     ) where
         F: FnOnce(&mut Mapper),
     {
-        use x86_64::instructions::tlb;
         use x86_64::registers::control_regs;
 
+        let active = self.is_active(table);
+
         // Inner scope to end the borrow of "temporary page"
         {
             // Backup the current P4 and temporarily remap it
             let original_p4 = Frame::containing_address(control_regs::cr3().0 as usize);
             let p4_table = temporary_page.map_table_frame(original_p4.clone(), self);
 
-            // Overwrite recursive mapping
-            self.p4_mut()[RECURSIVE_ENTRY].set(
+            // Overwrite recursive mapping. Only the non-global entries of the current context
+            // can point through it, the kernel pages stay cached.
+            self.root_mut()[RECURSIVE_ENTRY].set(
                 table.p4_frame.clone(),
                 EntryFlags::PRESENT | EntryFlags::WRITABLE,
             );
-            tlb::flush_all();
+            pcid::flush_context();
 
             f(self);
 
             // Restore recursive mapping to original P4 table
             p4_table[RECURSIVE_ENTRY].set(original_p4, EntryFlags::PRESENT | EntryFlags::WRITABLE);
-            tlb::flush_all();
+            pcid::flush_context();
         }
 
         temporary_page.unmap(self);
-    }
 
-    /// Switch the active page table with the passed in page table. Returns the old page table.
-    pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
-        use x86_64::registers::control_regs;
+        // The closure may have changed mappings the inactive table still has cached under its PCID
+        if !active {
+            pcid::invalidate(table.pcid);
+        }
+    }
 
-        let old_table = InactivePageTable {
-            p4_frame: Frame::containing_address(control_regs::cr3().0 as usize),
-        };
+    /// Make the passed in page table the active one. The table stays owned by the caller, who
+    /// has to keep it alive and must not free it while it is active.
+    pub fn switch(&mut self, new_table: &InactivePageTable) {
+        // Reloading CR3 would only flush the TLB
+        if self.is_active(new_table) {
+            return;
+        }
 
         unsafe {
-            let address = new_table.p4_frame.start_address() as usize;
-            asm!("mov $0, %cr3" :: "r" (address));
+            let value = pcid::cr3_value(new_table.p4_frame.start_address(), new_table.pcid);
+            asm!("mov $0, %cr3" :: "r" (value) : "memory");
         }
+    }
 
-        old_table
+    /// Check if the table is the one currently loaded in CR3
+    pub fn is_active(&self, table: &InactivePageTable) -> bool {
+        self.current_table().p4_frame == table.p4_frame
+    }
+
+    // Handle to the currently loaded table. Only used where the caller already owns that table.
+    fn current_table(&self) -> InactivePageTable {
+        use x86_64::registers::control_regs;
+
+        let cr3 = control_regs::cr3().0 as usize;
+        InactivePageTable {
+            p4_frame: Frame::containing_address(cr3),
+            pcid: (cr3 % PAGE_SIZE) as Pcid,
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct InactivePageTable {
     p4_frame: Frame,
+    // Tags the TLB entries of this table so they survive switches to other tables
+    pcid: Pcid,
 }
 
 impl InactivePageTable {
@@ -200,7 +298,63 @@ impl InactivePageTable {
 
         temporary_page.unmap(active_table);
 
-        InactivePageTable { p4_frame: frame }
+        InactivePageTable {
+            p4_frame: frame,
+            pcid: NO_PCID,
+        }
+    }
+
+    /// Set up a table for a new address space. The kernel half of the top level table is copied
+    /// from the active table, so both share the same kernel tables and with them all kernel
+    /// mappings.
+    pub fn new_sharing_kernel(
+        frame: Frame,
+        active_table: &mut ActivePageTable,
+        temporary_page: &mut TemporaryPage,
+    ) -> InactivePageTable {
+        {
+            let table = temporary_page.map_table_frame(frame.clone(), active_table);
+            table.zero();
+
+            for i in (ENTRY_COUNT / 2)..ENTRY_COUNT {
+                if i != RECURSIVE_ENTRY {
+                    table[i] = active_table.root()[i].clone();
+                }
+            }
+
+            table[RECURSIVE_ENTRY].set(frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+        }
+
+        temporary_page.unmap(active_table);
+
+        InactivePageTable {
+            p4_frame: frame,
+            pcid: pcid::allocate(),
+        }
+    }
+
+    pub fn p4_frame(&self) -> &Frame {
+        &self.p4_frame
+    }
+
+    /// Load the table into CR3 without going through the active table, which is locked inside the
+    /// memory controller. Finding out whether its TLB entries are stale takes a lock as well, so
+    /// they are always flushed. For switching threads, which cannot wait for locks.
+    pub unsafe fn load(&self) {
+        use x86_64::registers::control_regs;
+
+        if Frame::containing_address(control_regs::cr3().0 as usize) == self.p4_frame {
+            return;
+        }
+
+        let value = self.p4_frame.start_address() as u64 | self.pcid as u64;
+        asm!("mov $0, %cr3" :: "r" (value) : "memory");
+    }
+
+    /// Give up the PCID of a table that is about to be freed
+    pub fn release_pcid(&mut self) {
+        pcid::free(self.pcid);
+        self.pcid = NO_PCID;
     }
 }
 
@@ -213,7 +367,7 @@ pub struct Page {
 impl Page {
     pub fn containing_address(address: VirtualAddress) -> Page {
         assert!(
-            address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000,
+            address < USER_SPACE_END || address >= KERNEL_SPACE_START,
             "invalid adress: 0x{:x}",
             address
         );
@@ -222,10 +376,33 @@ impl Page {
         }
     }
 
-    fn start_address(&self) -> usize {
+    pub fn start_address(&self) -> usize {
         self.number * PAGE_SIZE
     }
 
+    // The page the given number of pages after this one
+    fn offset(&self, pages: usize) -> Page {
+        Page {
+            number: self.number + pages,
+        }
+    }
+
+    // Index into the table CR3 points to
+    #[cfg(not(feature = "la57"))]
+    fn root_index(&self) -> usize {
+        self.p4_index()
+    }
+
+    #[cfg(feature = "la57")]
+    fn root_index(&self) -> usize {
+        self.p5_index()
+    }
+
+    #[cfg(feature = "la57")]
+    fn p5_index(&self) -> usize {
+        (self.number >> 36) & 0o777
+    }
+
     fn p4_index(&self) -> usize {
         (self.number >> 27) & 0o777
     }
@@ -0,0 +1,161 @@
+use cpu;
+use spin::Mutex;
+use super::PhysicalAddress;
+
+// Number of process-context identifiers that fit into CR3
+const PCID_COUNT: usize = 4096;
+
+// Keep the TLB entries of the new PCID when writing CR3
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+pub type Pcid = u16;
+
+// Used by page tables without a PCID of their own. Switching to them always flushes the TLB.
+pub const NO_PCID: Pcid = 0;
+
+// Invalidation types of the invpcid instruction
+#[derive(Debug, Clone, Copy)]
+enum InvpcidType {
+    // All non-global entries of a single context
+    SingleContext = 1,
+    // All entries of all contexts including global ones
+    AllContextsGlobal = 2,
+}
+
+struct PcidAllocator {
+    enabled: bool,
+    invpcid: bool,
+    used: [u64; PCID_COUNT / 64],
+    // PCIDs that may still have TLB entries for mappings that have since changed. The next
+    // switch to one of them flushes its entries.
+    stale: [u64; PCID_COUNT / 64],
+}
+
+static PCIDS: Mutex<PcidAllocator> = Mutex::new(PcidAllocator {
+    enabled: false,
+    invpcid: false,
+    used: [0; PCID_COUNT / 64],
+    stale: [0; PCID_COUNT / 64],
+});
+
+impl PcidAllocator {
+    fn allocate(&mut self) -> Pcid {
+        if !self.enabled {
+            return NO_PCID;
+        }
+
+        for (i, word) in self.used.iter_mut().enumerate() {
+            if *word != !0 {
+                let bit = (!*word).trailing_zeros() as usize;
+                *word |= 1 << bit;
+                return (i * 64 + bit) as Pcid;
+            }
+        }
+
+        // Out of PCIDs, the table is simply flushed on every switch
+        NO_PCID
+    }
+
+    fn invalidate(&mut self, pcid: Pcid) {
+        if pcid == NO_PCID {
+            return;
+        }
+
+        if self.invpcid {
+            unsafe { invpcid(InvpcidType::SingleContext, pcid, 0) };
+        } else {
+            self.stale[pcid as usize / 64] |= 1 << (pcid % 64);
+        }
+    }
+
+    fn take_stale(&mut self, pcid: Pcid) -> bool {
+        let bit = 1 << (pcid % 64);
+        let word = &mut self.stale[pcid as usize / 64];
+        let stale = *word & bit != 0;
+        *word &= !bit;
+        stale
+    }
+}
+
+/// Enable PCIDs if the CPU supports them. CR4.PCIDE can only be set while the current CR3 has
+/// no PCID, which holds for every table before this is called.
+pub fn init() {
+    if !cpu::has_pcid() {
+        return;
+    }
+
+    let mut pcids = PCIDS.lock();
+    unsafe { cpu::cr4_set(cpu::CR4_PCID) };
+    pcids.enabled = true;
+    // NO_PCID is never handed out
+    pcids.used[0] |= 1;
+    pcids.invpcid = cpu::has_invpcid();
+}
+
+/// Allocate a PCID for a new page table. Returns NO_PCID if PCIDs are disabled or all in use.
+pub fn allocate() -> Pcid {
+    PCIDS.lock().allocate()
+}
+
+/// Free the PCID of a page table that is no longer used, dropping its TLB entries
+pub fn free(pcid: Pcid) {
+    if pcid == NO_PCID {
+        return;
+    }
+
+    let mut pcids = PCIDS.lock();
+    pcids.invalidate(pcid);
+    pcids.used[pcid as usize / 64] &= !(1 << (pcid % 64));
+}
+
+/// Drop the TLB entries of an inactive page table whose mappings were changed
+pub fn invalidate(pcid: Pcid) {
+    PCIDS.lock().invalidate(pcid);
+}
+
+/// Value to load into CR3 to switch to the given table. Its TLB entries are kept unless it has no
+/// PCID or they are stale.
+pub fn cr3_value(p4: PhysicalAddress, pcid: Pcid) -> u64 {
+    let mut pcids = PCIDS.lock();
+    if !pcids.enabled || pcid == NO_PCID || pcids.take_stale(pcid) {
+        p4 as u64 | pcid as u64
+    } else {
+        p4 as u64 | pcid as u64 | CR3_NO_FLUSH
+    }
+}
+
+/// Flush all non-global TLB entries of the current context. Kernel pages are global and stay.
+pub fn flush_context() {
+    use x86_64::instructions::tlb;
+    use x86_64::registers::control_regs::cr3;
+
+    if PCIDS.lock().invpcid {
+        let pcid = (cr3().0 & 0xfff) as Pcid;
+        unsafe { invpcid(InvpcidType::SingleContext, pcid, 0) };
+    } else {
+        // Writing CR3 without the no flush bit drops the non-global entries of the current PCID
+        tlb::flush_all();
+    }
+}
+
+/// Flush every TLB entry of every context, including global pages
+pub fn flush_everything() {
+    use x86_64::instructions::tlb;
+
+    if PCIDS.lock().invpcid {
+        unsafe { invpcid(InvpcidType::AllContextsGlobal, 0, 0) };
+    } else if cpu::cr4() & cpu::CR4_GLOBAL_PAGES != 0 {
+        // Toggling CR4.PGE flushes all entries of all PCIDs
+        unsafe {
+            cpu::cr4_clear(cpu::CR4_GLOBAL_PAGES);
+            cpu::cr4_set(cpu::CR4_GLOBAL_PAGES);
+        }
+    } else {
+        tlb::flush_all();
+    }
+}
+
+unsafe fn invpcid(kind: InvpcidType, pcid: Pcid, address: usize) {
+    let descriptor: [u64; 2] = [pcid as u64, address as u64];
+    asm!("invpcid ($0), $1" :: "r" (&descriptor), "r" (kind as u64) : "memory");
+}
@@ -1,20 +1,37 @@
 use memory::FrameAllocator;
 use memory::paging::entry::{Entry, EntryFlags};
 use memory::paging::ENTRY_COUNT;
+use memory::paging::access::TableAccess;
+#[cfg(not(feature = "la57"))]
 use memory::map::P4_TABLE_ADDRESS;
+#[cfg(feature = "la57")]
+use memory::map::P5_TABLE_ADDRESS;
 
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 
-pub const P4: *mut Table<Level4> = P4_TABLE_ADDRESS as *mut _;
+// The table CR3 points to, reached through the recursive entry
+#[cfg(not(feature = "la57"))]
+pub type TopLevel = Level4;
+#[cfg(feature = "la57")]
+pub type TopLevel = Level5;
+
+#[cfg(not(feature = "la57"))]
+pub const ROOT: *mut Table<TopLevel> = P4_TABLE_ADDRESS as *mut _;
+#[cfg(feature = "la57")]
+pub const ROOT: *mut Table<TopLevel> = P5_TABLE_ADDRESS as *mut _;
 
 pub trait TableLevel {}
 
+#[cfg(feature = "la57")]
+pub enum Level5 {}
 pub enum Level4 {}
 pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
+#[cfg(feature = "la57")]
+impl TableLevel for Level5 {}
 impl TableLevel for Level4 {}
 impl TableLevel for Level3 {}
 impl TableLevel for Level2 {}
@@ -24,6 +41,11 @@ pub trait HierarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
 }
 
+#[cfg(feature = "la57")]
+impl HierarchicalLevel for Level5 {
+    type NextLevel = Level4;
+}
+
 impl HierarchicalLevel for Level4 {
     type NextLevel = Level3;
 }
@@ -56,38 +78,51 @@ impl<L> Table<L>
 where
     L: HierarchicalLevel,
 {
-    pub fn next_table_address(&self, index: usize) -> Option<usize> {
+    pub fn next_table_address<M>(&self, index: usize, access: M) -> Option<usize>
+    where
+        M: TableAccess,
+    {
         let entry_flags = self[index].flags();
         if entry_flags.contains(EntryFlags::PRESENT) && !entry_flags.contains(EntryFlags::HUGE_PAGE)
         {
             let table_address = (self as *const _) as usize;
-            let sign_extension = 0o177777_000_000_000_000_0000 * ((table_address >> 47) & 0b1);
-            Some((((table_address << 9) | (index << 12)) & ((1 << 48) - 1)) | sign_extension)
+            let frame = self[index].pointed_frame().unwrap();
+            Some(access.next_table_address(table_address, index, frame))
         } else {
             None
         }
     }
 
-    pub fn next_table(&self, index: usize) -> Option<&Table<L::NextLevel>> {
-        self.next_table_address(index)
+    pub fn next_table<M>(&self, index: usize, access: M) -> Option<&Table<L::NextLevel>>
+    where
+        M: TableAccess,
+    {
+        self.next_table_address(index, access)
             .map(|address| unsafe { &*(address as *const _) })
     }
 
-    pub fn next_table_mut(&mut self, index: usize) -> Option<&mut Table<L::NextLevel>> {
-        self.next_table_address(index)
+    pub fn next_table_mut<M>(&mut self, index: usize, access: M) -> Option<&mut Table<L::NextLevel>>
+    where
+        M: TableAccess,
+    {
+        self.next_table_address(index, access)
             .map(|address| unsafe { &mut *(address as *mut _) })
     }
 
-    // Return the next table or create it if it does not exist
-    pub fn next_table_or_create<A>(
+    // Return the next table or create it if it does not exist. User pages need user access on
+    // every level of the walk, so the entry is made user accessible if the page flags are.
+    pub fn next_table_or_create<M, A>(
         &mut self,
         index: usize,
+        page_flags: EntryFlags,
+        access: M,
         allocator: &mut A,
     ) -> &mut Table<L::NextLevel>
     where
+        M: TableAccess,
         A: FrameAllocator,
     {
-        if self.next_table(index).is_none() {
+        if self.next_table(index, access).is_none() {
             assert!(
                 !self.entries[index].flags().contains(EntryFlags::HUGE_PAGE),
                 "Mapping code does not support huge pages"
@@ -95,10 +130,14 @@ where
 
             let frame = allocator.allocate_frame().expect("No frames available");
             self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
-            self.next_table_mut(index).unwrap().zero();
+            self.next_table_mut(index, access).unwrap().zero();
+        }
+
+        if page_flags.contains(EntryFlags::USER_ACCESSIBLE) {
+            self.entries[index].insert_flags(EntryFlags::USER_ACCESSIBLE);
         }
 
-        self.next_table_mut(index).unwrap()
+        self.next_table_mut(index, access).unwrap()
     }
 }
 
@@ -18,7 +18,8 @@ impl TemporaryPage {
             "Temporary page is already mapped"
         );
 
-        active_table.map_to(self.page, frame, EntryFlags::PRESENT | EntryFlags::WRITABLE, &mut self.allocator);
+        let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        active_table.map_to(self.page, frame, flags, &mut self.allocator);
         self.page.start_address()
     }
 
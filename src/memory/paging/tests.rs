@@ -0,0 +1,427 @@
+// Host tests that drive the mapper against simulated physical memory instead of the recursive
+// mapping, run with cargo test
+
+use super::access::{DirectAccess, TableAccess};
+use super::dump::{collect, find_aliases};
+use super::table::{Level3, Table};
+use super::{EntryFlags, InactivePageTable, Mapper, Page, PageSize, Translation, VirtualAddress};
+use super::{ENTRY_COUNT, NO_PCID};
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+
+// Size of the simulated physical memory
+const FRAME_COUNT: usize = 64;
+
+// The allocator hands out the frames below this for tables. Frames from here on are free to be
+// used as mapping targets.
+const TABLE_FRAMES: usize = 32;
+
+type Memory = Vec<[u64; ENTRY_COUNT]>;
+
+// Reaches frame n of the simulated memory at base + n * PAGE_SIZE
+#[derive(Debug, Clone, Copy)]
+struct Simulated {
+    base: VirtualAddress,
+}
+
+impl TableAccess for Simulated {
+    fn next_table_address(
+        &self,
+        _table_address: VirtualAddress,
+        _index: usize,
+        frame: Frame,
+    ) -> VirtualAddress {
+        self.frame_address(frame)
+    }
+
+    // There is no TLB to keep in sync
+    fn flush_page(&self, _address: VirtualAddress) {}
+
+    fn flush_context(&self) {}
+
+    fn flush_everything(&self) {}
+}
+
+impl DirectAccess for Simulated {
+    fn frame_address(&self, frame: Frame) -> VirtualAddress {
+        assert!(frame.number < FRAME_COUNT, "Table outside of simulated memory");
+        self.base + frame.start_address()
+    }
+}
+
+// Hands out the table frames in order and records the frames it gets back
+struct MockAllocator {
+    next: usize,
+    freed: Vec<usize>,
+}
+
+impl FrameAllocator for MockAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        if self.next == TABLE_FRAMES {
+            return None;
+        }
+
+        self.next += 1;
+        Some(Frame {
+            number: self.next - 1,
+        })
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.freed.push(frame.number);
+    }
+}
+
+// Zeroed simulated memory with an empty root table in frame 0. The memory has to outlive the
+// mapper.
+fn setup() -> (Memory, Mapper<Simulated>, MockAllocator) {
+    let mut memory = vec![[0; ENTRY_COUNT]; FRAME_COUNT];
+    let access = Simulated {
+        base: memory.as_mut_ptr() as usize,
+    };
+
+    let root = access.frame_address(Frame { number: 0 });
+    let mapper = unsafe { Mapper::from_root(root as *mut _, access) };
+    let allocator = MockAllocator {
+        next: 1,
+        freed: Vec::new(),
+    };
+
+    (memory, mapper, allocator)
+}
+
+// The P3 table covering the page, created if it does not exist
+#[cfg(not(feature = "la57"))]
+fn p3_table<'a>(
+    mapper: &'a mut Mapper<Simulated>,
+    page: Page,
+    allocator: &mut MockAllocator,
+) -> &'a mut Table<Level3> {
+    let access = mapper.access();
+    mapper
+        .root_mut()
+        .next_table_or_create(page.p4_index(), EntryFlags::empty(), access, allocator)
+}
+
+#[cfg(feature = "la57")]
+fn p3_table<'a>(
+    mapper: &'a mut Mapper<Simulated>,
+    page: Page,
+    allocator: &mut MockAllocator,
+) -> &'a mut Table<Level3> {
+    let access = mapper.access();
+    mapper
+        .root_mut()
+        .next_table_or_create(page.p5_index(), EntryFlags::empty(), access, allocator)
+        .next_table_or_create(page.p4_index(), EntryFlags::empty(), access, allocator)
+}
+
+#[test]
+fn map_and_translate() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let address = 0x1234_5000;
+    let page = Page::containing_address(address);
+
+    mapper.map_to(page, Frame { number: 40 }, EntryFlags::WRITABLE, &mut allocator);
+    assert_eq!(mapper.translate(address + 0x123), Some(40 * PAGE_SIZE + 0x123));
+    assert_eq!(mapper.translate(address + PAGE_SIZE), None);
+    assert_eq!(mapper.translate(address - PAGE_SIZE), None);
+
+    let flags = mapper.flags(page).unwrap();
+    assert!(flags.contains(EntryFlags::PRESENT | EntryFlags::WRITABLE));
+    assert!(!flags.contains(EntryFlags::GLOBAL));
+
+    // The neighbouring page shares all tables
+    let tables = allocator.next;
+    let next = Page::containing_address(address + PAGE_SIZE);
+    mapper.map_to(next, Frame { number: 41 }, EntryFlags::empty(), &mut allocator);
+    assert_eq!(allocator.next, tables);
+    assert_eq!(mapper.translate(address + PAGE_SIZE), Some(41 * PAGE_SIZE));
+    assert!(!mapper.flags(next).unwrap().contains(EntryFlags::WRITABLE));
+}
+
+#[test]
+fn kernel_pages_are_global() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let page = Page::containing_address(0xffff_ffff_8000_0000);
+
+    mapper.map_to(page, Frame { number: 40 }, EntryFlags::WRITABLE, &mut allocator);
+    assert_eq!(mapper.translate(0xffff_ffff_8000_0042), Some(40 * PAGE_SIZE + 0x42));
+    assert!(mapper.flags(page).unwrap().contains(EntryFlags::GLOBAL));
+}
+
+#[test]
+fn user_pages_are_user_accessible_on_every_level() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let page = Page::containing_address(0x40_0000);
+
+    mapper.map_to(page, Frame { number: 40 }, EntryFlags::USER_ACCESSIBLE, &mut allocator);
+    let p3 = p3_table(&mut mapper, page, &mut allocator);
+    assert!(p3[page.p3_index()].flags().contains(EntryFlags::USER_ACCESSIBLE));
+}
+
+#[test]
+fn unmap() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let page = Page::containing_address(0x1000);
+
+    mapper.map_to(page, Frame { number: 40 }, EntryFlags::WRITABLE, &mut allocator);
+    let frame = mapper.unmap(page, &mut allocator);
+    assert_eq!(frame.number, 40);
+    assert_eq!(mapper.translate(0x1000), None);
+    assert_eq!(mapper.flags(page), None);
+
+    // The frame is not owned by the mapper, so it is left alone
+    assert!(allocator.freed.is_empty());
+
+    // The page can be mapped again
+    mapper.map_to(page, Frame { number: 41 }, EntryFlags::empty(), &mut allocator);
+    assert_eq!(mapper.translate(0x1000), Some(41 * PAGE_SIZE));
+}
+
+#[test]
+fn unmap_range_frees_frames() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let start = Page::containing_address(0x10_0000);
+
+    for i in 0..4 {
+        let page = Page::containing_address(0x10_0000 + i * PAGE_SIZE);
+        let frame = Frame { number: 40 + i };
+        mapper.map_to(page, frame, EntryFlags::WRITABLE, &mut allocator);
+    }
+
+    mapper.unmap_range(start, 4, &mut allocator);
+    assert_eq!(allocator.freed, vec![40, 41, 42, 43]);
+    for i in 0..4 {
+        assert_eq!(mapper.translate(0x10_0000 + i * PAGE_SIZE), None);
+    }
+}
+
+#[test]
+fn update_flags() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let page = Page::containing_address(0x2000);
+
+    mapper.map_to(page, Frame { number: 40 }, EntryFlags::WRITABLE, &mut allocator);
+    mapper.update_flags(page, EntryFlags::NO_EXECUTE);
+
+    let flags = mapper.flags(page).unwrap();
+    assert!(flags.contains(EntryFlags::PRESENT | EntryFlags::NO_EXECUTE));
+    assert!(!flags.contains(EntryFlags::WRITABLE));
+    assert_eq!(mapper.translate(0x2000), Some(40 * PAGE_SIZE));
+}
+
+#[test]
+fn translate_large_page() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let address = 0x4000_0000 + 3 * 0x20_0000;
+    let page = Page::containing_address(address);
+
+    {
+        let access = mapper.access();
+        let p3 = p3_table(&mut mapper, page, &mut allocator);
+        let p2 =
+            p3.next_table_or_create(page.p3_index(), EntryFlags::empty(), access, &mut allocator);
+        let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::HUGE_PAGE;
+        p2[page.p2_index()].set(Frame { number: ENTRY_COUNT }, flags);
+    }
+
+    let physical = ENTRY_COUNT * PAGE_SIZE;
+    assert_eq!(mapper.translate(address), Some(physical));
+    assert_eq!(mapper.translate(address + 0x5_0012), Some(physical + 0x5_0012));
+    assert_eq!(mapper.translate(address + 0x20_0000), None);
+
+    // Only P1 entries carry page flags
+    assert_eq!(mapper.flags(page), None);
+}
+
+#[test]
+fn translate_huge_page() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let address = 0x80_0000_0000;
+    let page = Page::containing_address(address);
+
+    {
+        let p3 = p3_table(&mut mapper, page, &mut allocator);
+        let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::HUGE_PAGE;
+        p3[page.p3_index()].set(Frame { number: ENTRY_COUNT * ENTRY_COUNT }, flags);
+    }
+
+    let physical = ENTRY_COUNT * ENTRY_COUNT * PAGE_SIZE;
+    let offset = 0x1234_5678;
+    assert_eq!(mapper.translate(address + offset), Some(physical + offset));
+    assert_eq!(mapper.translate(address + 0x4000_0000), None);
+}
+
+#[test]
+fn translate_detailed() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let page = Page::containing_address(0x5000);
+    let flags = EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE;
+    mapper.map_to(page, Frame { number: 40 }, flags, &mut allocator);
+
+    match mapper.translate_detailed(0x5abc) {
+        Translation::Mapped {
+            frame,
+            physical,
+            page_size,
+            flags,
+        } => {
+            assert_eq!(frame.number, 40);
+            assert_eq!(physical, 40 * PAGE_SIZE + 0xabc);
+            assert_eq!(page_size, PageSize::Small);
+            assert!(flags.contains(EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE));
+        }
+        translation => panic!("Unexpected {:?}", translation),
+    }
+
+    // Tables above the page take away access for the whole range they map
+    {
+        let p3 = p3_table(&mut mapper, page, &mut allocator);
+        let frame = p3[page.p3_index()].pointed_frame().unwrap();
+        p3[page.p3_index()].set(frame, EntryFlags::PRESENT | EntryFlags::NO_EXECUTE);
+    }
+
+    match mapper.translate_detailed(0x5000) {
+        Translation::Mapped { flags, .. } => {
+            assert!(!flags.intersects(EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE));
+            assert!(flags.contains(EntryFlags::NO_EXECUTE));
+        }
+        translation => panic!("Unexpected {:?}", translation),
+    }
+}
+
+#[test]
+fn translate_detailed_huge_page() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let address = 0x80_0000_0000;
+    let page = Page::containing_address(address);
+
+    {
+        let p3 = p3_table(&mut mapper, page, &mut allocator);
+        let flags = EntryFlags::PRESENT | EntryFlags::HUGE_PAGE;
+        p3[page.p3_index()].set(Frame { number: ENTRY_COUNT * ENTRY_COUNT }, flags);
+    }
+
+    match mapper.translate_detailed(address + 0x20_1000) {
+        Translation::Mapped {
+            frame,
+            physical,
+            page_size,
+            ..
+        } => {
+            assert_eq!(frame.number, ENTRY_COUNT * ENTRY_COUNT);
+            assert_eq!(physical, frame.start_address() + 0x20_1000);
+            assert_eq!(page_size, PageSize::Huge);
+        }
+        translation => panic!("Unexpected {:?}", translation),
+    }
+}
+
+#[test]
+fn translate_detailed_missing_level() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let page = Page::containing_address(0x20_0000);
+    mapper.map_to(page, Frame { number: 40 }, EntryFlags::empty(), &mut allocator);
+
+    let level = |address| match mapper.translate_detailed(address) {
+        Translation::NotMapped { level } => level,
+        translation => panic!("Unexpected {:?}", translation),
+    };
+
+    // Same P1 table, next P2 entry, next P3 entry and next P4 entry
+    assert_eq!(level(0x20_1000), 1);
+    assert_eq!(level(0x40_0000), 2);
+    assert_eq!(level(0x4000_0000), 3);
+    assert_eq!(level(0x80_0000_0000), 4);
+}
+
+#[test]
+fn edit_inactive_table() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let access = mapper.access();
+    let table = InactivePageTable {
+        p4_frame: allocator.allocate_frame().unwrap(),
+        pcid: NO_PCID,
+    };
+    let mut inactive = unsafe { Mapper::for_inactive(&table, access) };
+
+    let page = Page::containing_address(0x3000);
+    inactive.map_to(page, Frame { number: 40 }, EntryFlags::WRITABLE, &mut allocator);
+    assert_eq!(inactive.translate(0x3000), Some(40 * PAGE_SIZE));
+    assert_eq!(mapper.translate(0x3000), None);
+
+    // The same page can be mapped differently in both tables
+    mapper.map_to(page, Frame { number: 41 }, EntryFlags::empty(), &mut allocator);
+    assert_eq!(mapper.translate(0x3000), Some(41 * PAGE_SIZE));
+    assert_eq!(inactive.translate(0x3000), Some(40 * PAGE_SIZE));
+
+    // Tearing down the lower half returns the mapped frame and every table below the root
+    let first_table = table.p4_frame().number + 1;
+    let before = allocator.freed.len();
+    inactive.free_lower_half(&mut allocator);
+    assert_eq!(inactive.translate(0x3000), None);
+    assert_eq!(mapper.translate(0x3000), Some(41 * PAGE_SIZE));
+
+    let mut freed = allocator.freed[before..].to_vec();
+    freed.sort();
+    let mut expected: Vec<usize> = (first_table..first_table + tables_per_page()).collect();
+    expected.push(40);
+    assert_eq!(freed, expected);
+}
+
+// Tables below the root needed to map a single page
+fn tables_per_page() -> usize {
+    if cfg!(feature = "la57") {
+        4
+    } else {
+        3
+    }
+}
+
+#[test]
+fn find_aliases_beyond_neighbours() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    let map = |mapper: &mut Mapper<Simulated>, allocator: &mut MockAllocator, address, frame| {
+        let page = Page::containing_address(address);
+        mapper.map_to(page, Frame { number: frame }, EntryFlags::empty(), allocator);
+    };
+
+    // Two pages coalesced into one mapping of frames 50 and 51
+    map(&mut mapper, &mut allocator, 0x10000, 50);
+    map(&mut mapper, &mut allocator, 0x11000, 51);
+    // Frame 50 again, then frame 51 again behind it in physical order
+    map(&mut mapper, &mut allocator, 0x20000, 50);
+    map(&mut mapper, &mut allocator, 0x30000, 51);
+    // Not aliased with anything
+    map(&mut mapper, &mut allocator, 0x40000, 52);
+    map(&mut mapper, &mut allocator, 0x50000, 45);
+
+    let mut buffer = vec![None; 8];
+    assert_eq!(collect(&mapper, &mut buffer), 5);
+
+    let mut aliased = Vec::new();
+    let count = find_aliases(&mut buffer[..5], |first, second| {
+        aliased.push((first.to_string(), second.to_string()));
+    });
+    assert_eq!(count, 2);
+    assert_eq!(aliased.len(), 2);
+    // Both are reported against the coalesced mapping, whichever of two equal starts sorts first
+    let coalesced = "0000000000010000-0000000000012000";
+    for &(ref first, ref second) in &aliased {
+        assert!(first.starts_with(coalesced) || second.starts_with(coalesced));
+    }
+}
+
+#[test]
+fn collect_counts_mappings_that_do_not_fit() {
+    let (_memory, mut mapper, mut allocator) = setup();
+    for i in 0..4 {
+        let page = Page::containing_address(0x10000 + i * 2 * PAGE_SIZE);
+        mapper.map_to(page, Frame { number: 40 + i }, EntryFlags::empty(), &mut allocator);
+    }
+
+    let mut buffer = vec![None; 2];
+    assert_eq!(collect(&mapper, &mut buffer), 4);
+    assert!(buffer.iter().all(|mapping| mapping.is_some()));
+    assert_eq!(find_aliases(&mut buffer, |_, _| ()), 0);
+}
@@ -0,0 +1,108 @@
+use memory::{Frame, FrameAllocator};
+use spin::Mutex;
+
+// Maximum number of frames that can be shared between address spaces at once
+const MAX_SHARED_FRAMES: usize = 4096;
+
+// A frame with more than one reference, unused slots have a count of zero
+#[derive(Clone, Copy)]
+struct SharedFrame {
+    number: usize,
+    count: usize,
+}
+
+// Reference counts of shared frames. Only frames with more than one reference are stored, every
+// other frame is implicitly referenced exactly once by whoever allocated it.
+pub struct FrameRefcounts {
+    entries: [SharedFrame; MAX_SHARED_FRAMES],
+}
+
+pub static FRAME_REFCOUNTS: Mutex<FrameRefcounts> = Mutex::new(FrameRefcounts::new());
+
+impl FrameRefcounts {
+    pub const fn new() -> FrameRefcounts {
+        FrameRefcounts {
+            entries: [SharedFrame { number: 0, count: 0 }; MAX_SHARED_FRAMES],
+        }
+    }
+
+    fn find(&self, frame: &Frame) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.count > 0 && entry.number == frame.number)
+    }
+
+    // Number of references to the frame
+    pub fn count(&self, frame: &Frame) -> usize {
+        self.find(frame).map_or(1, |index| self.entries[index].count)
+    }
+
+    // Add a reference to the frame. Returns false if too many frames are shared already.
+    pub fn share(&mut self, frame: &Frame) -> bool {
+        if let Some(index) = self.find(frame) {
+            self.entries[index].count += 1;
+            return true;
+        }
+
+        match self.entries.iter_mut().find(|entry| entry.count == 0) {
+            Some(entry) => {
+                *entry = SharedFrame {
+                    number: frame.number,
+                    count: 2,
+                };
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Drop a reference to the frame. Returns true if it was the last one and the frame is free.
+    pub fn release(&mut self, frame: &Frame) -> bool {
+        match self.find(frame) {
+            Some(index) => {
+                let entry = &mut self.entries[index];
+                entry.count -= 1;
+
+                // A single reference is implicit again
+                if entry.count == 1 {
+                    entry.count = 0;
+                }
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+// Frame allocator that only frees a frame once its last reference is dropped. Used wherever
+// frames that may be shared by copy-on-write are returned.
+pub struct SharedFrameAllocator<'a, A: 'a> {
+    allocator: &'a mut A,
+}
+
+impl<'a, A> SharedFrameAllocator<'a, A>
+where
+    A: FrameAllocator,
+{
+    pub fn new(allocator: &'a mut A) -> SharedFrameAllocator<'a, A> {
+        SharedFrameAllocator { allocator }
+    }
+}
+
+impl<'a, A> FrameAllocator for SharedFrameAllocator<'a, A>
+where
+    A: FrameAllocator,
+{
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocator.allocate_frame()
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        // Statement on its own so the lock is released before freeing the frame
+        let last_reference = FRAME_REFCOUNTS.lock().release(&frame);
+
+        if last_reference {
+            self.allocator.deallocate_frame(frame);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use memory::map::KERNEL_VMA;
+use memory::paging::PhysicalAddress;
+use memory::{Frame, PAGE_SIZE};
+use multiboot2::{BootInformation, MemoryAreaType};
+use spin::Mutex;
+
+// Maximum number of physical memory ranges that can be reserved
+const MAX_RESERVATIONS: usize = 64;
+
+// Why a range of physical memory must not be handed out by a frame allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationKind {
+    // Higher half kernel sections
+    Kernel,
+    // Sections that are only used until the kernel is remapped
+    Bootstrap,
+    // The multiboot information structure
+    Multiboot,
+    // A module loaded by the bootloader
+    Module,
+    // Memory reserved by the firmware or used by legacy hardware
+    Firmware,
+}
+
+// A page aligned range of physical memory from start up to but not including end
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    start: PhysicalAddress,
+    end: PhysicalAddress,
+    kind: ReservationKind,
+    name: &'static str,
+}
+
+impl Reservation {
+    fn contains(&self, address: PhysicalAddress) -> bool {
+        address >= self.start && address < self.end
+    }
+}
+
+// Registry of reserved physical memory, sorted by start address. Every frame allocator has to
+// consult it before handing out a frame.
+pub struct Reservations {
+    entries: [Option<Reservation>; MAX_RESERVATIONS],
+}
+
+pub static RESERVATIONS: Mutex<Reservations> = Mutex::new(Reservations::new());
+
+impl Reservations {
+    pub const fn new() -> Reservations {
+        Reservations {
+            entries: [None; MAX_RESERVATIONS],
+        }
+    }
+
+    // Reserve the pages overlapping start up to end
+    pub fn reserve(
+        &mut self,
+        start: PhysicalAddress,
+        end: PhysicalAddress,
+        kind: ReservationKind,
+        name: &'static str,
+    ) {
+        assert!(start < end, "Empty reservation {}", name);
+
+        let mut reservation = Reservation {
+            start: start / PAGE_SIZE * PAGE_SIZE,
+            end: (end + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE,
+            kind,
+            name,
+        };
+
+        // Insertion sort into the list of entries, the list is never sparse
+        for entry in self.entries.iter_mut() {
+            let current = *entry;
+            match current {
+                Some(existing) if existing.start <= reservation.start => continue,
+                Some(existing) => {
+                    *entry = Some(reservation);
+                    reservation = existing;
+                }
+                None => {
+                    *entry = Some(reservation);
+                    return;
+                }
+            }
+        }
+
+        panic!("Too many physical memory reservations");
+    }
+
+    // Drop all reservations of the given kind
+    pub fn release(&mut self, kind: ReservationKind) {
+        let mut kept = 0;
+        for i in 0..MAX_RESERVATIONS {
+            match self.entries[i].take() {
+                Some(entry) if entry.kind != kind => {
+                    self.entries[kept] = Some(entry);
+                    kept += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn find(&self, address: PhysicalAddress) -> Option<&Reservation> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.as_ref())
+            .find(|entry| entry.contains(address))
+    }
+
+    pub fn is_reserved(&self, frame: &Frame) -> bool {
+        self.find(frame.start_address()).is_some()
+    }
+
+    // If the frame is reserved, return the first frame after the reservation containing it
+    pub fn frame_after(&self, frame: &Frame) -> Option<Frame> {
+        self.find(frame.start_address())
+            .map(|entry| Frame::containing_address(entry.end))
+    }
+
+    pub fn print(&self) {
+        for entry in self.entries.iter().filter_map(|entry| entry.as_ref()) {
+            println!(
+                "{:012x}-{:012x} {:?} {}",
+                entry.start,
+                entry.end,
+                entry.kind,
+                entry.name
+            );
+        }
+    }
+}
+
+// Fill the registry from the multiboot information. Must be called before any frame is allocated.
+pub fn init(boot_info: &BootInformation) {
+    let mut reservations = RESERVATIONS.lock();
+
+    // The real mode IVT and BIOS data area, and the VGA buffer and BIOS ROMs in the legacy hole
+    reservations.reserve(0, PAGE_SIZE, ReservationKind::Firmware, "real mode IVT");
+    reservations.reserve(0xa0000, 0x100000, ReservationKind::Firmware, "legacy hole");
+
+    let memory_map = boot_info.memory_map().expect("Memory map tag required");
+    for area in memory_map.all_memory_areas() {
+        let name = match area.typ() {
+            MemoryAreaType::Available => continue,
+            MemoryAreaType::Reserved => "reserved",
+            MemoryAreaType::AcpiReclaimable => "ACPI reclaimable",
+            MemoryAreaType::AcpiNvs => "ACPI NVS",
+            MemoryAreaType::Defective => "defective",
+        };
+
+        if area.size() > 0 {
+            reservations.reserve(
+                area.start_address(),
+                area.end_address(),
+                ReservationKind::Firmware,
+                name,
+            );
+        }
+    }
+
+    let elf_sections_tag = boot_info.elf_sections().expect("Elf sections tag required");
+    let string_table = elf_sections_tag.string_table(boot_info);
+    for section in elf_sections_tag.sections() {
+        if !section.is_allocated() || section.size() == 0 {
+            continue;
+        }
+
+        // Bootstrap sections are linked at their physical address
+        let (start, kind) = if section.start_address() >= KERNEL_VMA {
+            (section.start_address() - KERNEL_VMA, ReservationKind::Kernel)
+        } else {
+            (section.start_address(), ReservationKind::Bootstrap)
+        };
+
+        reservations.reserve(
+            start,
+            start + section.size(),
+            kind,
+            string_table.section_name(section),
+        );
+    }
+
+    // The multiboot structure is accessed through the higher half
+    reservations.reserve(
+        boot_info.start_address() - KERNEL_VMA,
+        boot_info.end_address() - KERNEL_VMA,
+        ReservationKind::Multiboot,
+        "multiboot information",
+    );
+
+    for module in boot_info.modules() {
+        reservations.reserve(
+            module.start_address() as usize,
+            module.end_address() as usize,
+            ReservationKind::Module,
+            module.name(),
+        );
+    }
+}
@@ -0,0 +1,484 @@
+use core::{ptr, slice};
+use core::mem::size_of;
+use memory::{EntryFlags, VirtualAddress, MEMORY_CONTROLLER, PAGE_SIZE};
+use spin::Mutex;
+
+// Slabs are single pages mapped into a window of kernel virtual memory, 16 MiB in size
+const WINDOW_PAGES: usize = 4096;
+
+const MAX_CACHES: usize = 32;
+
+// Every object is aligned to this
+const OBJECT_ALIGN: usize = 16;
+
+// Largest object size a cache can be created for
+const MAX_OBJECT_SIZE: usize = PAGE_SIZE / 4;
+
+// End of a slab's free list
+const NO_OBJECT: u16 = 0xffff;
+
+// Generic caches used by kmalloc
+const KMALLOC_CACHES: [(&'static str, usize); 7] = [
+    ("kmalloc-16", 16),
+    ("kmalloc-32", 32),
+    ("kmalloc-64", 64),
+    ("kmalloc-128", 128),
+    ("kmalloc-256", 256),
+    ("kmalloc-512", 512),
+    ("kmalloc-1024", 1024),
+];
+
+// Placed at the start of every slab page. It is followed by one free list link per object, so
+// free objects are never written to and stay in their constructed state, and then the objects.
+#[repr(C)]
+struct SlabHeader {
+    cache: usize,
+    next: *mut SlabHeader,
+    free: u16,
+    in_use: u16,
+}
+
+impl SlabHeader {
+    unsafe fn links(&mut self, count: usize) -> &mut [u16] {
+        let start = (self as *mut SlabHeader).offset(1) as *mut u16;
+        slice::from_raw_parts_mut(start, count)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub allocations: usize,
+    pub frees: usize,
+    pub failed_allocations: usize,
+    pub active_objects: usize,
+    pub slabs: usize,
+    pub reclaimed_slabs: usize,
+}
+
+// A cache of equally sized objects
+#[derive(Clone, Copy)]
+struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    objects_per_slab: usize,
+    objects_offset: usize,
+    constructor: Option<fn(*mut u8)>,
+    // Slabs with at least one free object
+    partial: *mut SlabHeader,
+    // Slabs without free objects
+    full: *mut SlabHeader,
+    stats: CacheStats,
+}
+
+impl SlabCache {
+    fn new(name: &'static str, size: usize, constructor: Option<fn(*mut u8)>) -> SlabCache {
+        assert!(
+            size > 0 && size <= MAX_OBJECT_SIZE,
+            "Invalid slab object size {}",
+            size
+        );
+
+        let object_size = align_up(size, OBJECT_ALIGN);
+
+        // Fit as many objects as possible next to the header and their free list links
+        let mut objects_per_slab = PAGE_SIZE / object_size;
+        let mut objects_offset;
+        loop {
+            objects_offset = align_up(
+                size_of::<SlabHeader>() + objects_per_slab * size_of::<u16>(),
+                OBJECT_ALIGN,
+            );
+            if objects_offset + objects_per_slab * object_size <= PAGE_SIZE {
+                break;
+            }
+            objects_per_slab -= 1;
+        }
+
+        SlabCache {
+            name,
+            object_size,
+            objects_per_slab,
+            objects_offset,
+            constructor,
+            partial: ptr::null_mut(),
+            full: ptr::null_mut(),
+            stats: CacheStats {
+                allocations: 0,
+                frees: 0,
+                failed_allocations: 0,
+                active_objects: 0,
+                slabs: 0,
+                reclaimed_slabs: 0,
+            },
+        }
+    }
+
+    fn object(&self, slab: *mut SlabHeader, index: usize) -> *mut u8 {
+        (slab as usize + self.objects_offset + index * self.object_size) as *mut u8
+    }
+}
+
+/// Handle to a cache returned by create_cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheId(usize);
+
+pub struct SlabAllocator {
+    caches: [Option<SlabCache>; MAX_CACHES],
+    kmalloc_caches: [CacheId; 7],
+    window: VirtualAddress,
+    // One bit per page of the window that is mapped to a slab
+    used_pages: [u64; WINDOW_PAGES / 64],
+}
+
+// The slab pointers are only ever used with the lock held
+unsafe impl Send for SlabAllocator {}
+
+static SLAB_ALLOCATOR: Mutex<Option<SlabAllocator>> = Mutex::new(None);
+
+impl SlabAllocator {
+    fn create_cache(
+        &mut self,
+        name: &'static str,
+        size: usize,
+        constructor: Option<fn(*mut u8)>,
+    ) -> CacheId {
+        for (i, slot) in self.caches.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(SlabCache::new(name, size, constructor));
+                return CacheId(i);
+            }
+        }
+
+        panic!("Too many slab caches");
+    }
+
+    fn alloc(&mut self, id: CacheId) -> Option<*mut u8> {
+        let needs_slab = self.cache(id).partial.is_null();
+        if needs_slab && !self.grow(id) {
+            self.cache(id).stats.failed_allocations += 1;
+            return None;
+        }
+
+        let cache = self.cache(id);
+        unsafe {
+            let slab = cache.partial;
+            let index = (*slab).free as usize;
+            (*slab).free = (*slab).links(cache.objects_per_slab)[index];
+            (*slab).in_use += 1;
+
+            // The last free object was taken
+            if (*slab).free == NO_OBJECT {
+                cache.partial = (*slab).next;
+                (*slab).next = cache.full;
+                cache.full = slab;
+            }
+
+            cache.stats.allocations += 1;
+            cache.stats.active_objects += 1;
+
+            Some(cache.object(slab, index))
+        }
+    }
+
+    fn free(&mut self, object: *mut u8) {
+        let page = object as usize & !(PAGE_SIZE - 1);
+        assert!(
+            self.is_slab_page(page),
+            "Freed object {:?} is not from a slab",
+            object
+        );
+
+        let slab = page as *mut SlabHeader;
+        let cache = self.caches[unsafe { (*slab).cache }].as_mut().unwrap();
+
+        let offset = (object as usize).wrapping_sub(page + cache.objects_offset);
+        assert!(
+            offset < cache.objects_per_slab * cache.object_size && offset % cache.object_size == 0,
+            "Freed pointer {:?} is not the start of a {} object",
+            object,
+            cache.name
+        );
+        let index = offset / cache.object_size;
+
+        unsafe {
+            let was_full = (*slab).free == NO_OBJECT;
+
+            // Catch double frees before corrupting the free list
+            let mut free = (*slab).free;
+            while free != NO_OBJECT {
+                assert!(
+                    free as usize != index,
+                    "Double free of {:?} in {}",
+                    object,
+                    cache.name
+                );
+                free = (*slab).links(cache.objects_per_slab)[free as usize];
+            }
+
+            let next = (*slab).free;
+            (*slab).links(cache.objects_per_slab)[index] = next;
+            (*slab).free = index as u16;
+            (*slab).in_use -= 1;
+
+            if was_full {
+                unlink(&mut cache.full, slab);
+                (*slab).next = cache.partial;
+                cache.partial = slab;
+            }
+        }
+
+        cache.stats.frees += 1;
+        cache.stats.active_objects -= 1;
+    }
+
+    // Add a new slab to the partial list of a cache
+    fn grow(&mut self, id: CacheId) -> bool {
+        let page = match self.allocate_page() {
+            Some(page) => page,
+            None => return false,
+        };
+
+        let cache = self.cache(id);
+        let count = cache.objects_per_slab;
+        unsafe {
+            let slab = page as *mut SlabHeader;
+            (*slab).cache = id.0;
+            (*slab).free = 0;
+            (*slab).in_use = 0;
+
+            // Inner scope to end the borrow of the links
+            {
+                let links = (*slab).links(count);
+                for i in 0..count {
+                    links[i] = if i + 1 < count { (i + 1) as u16 } else { NO_OBJECT };
+                }
+            }
+
+            if let Some(constructor) = cache.constructor {
+                for i in 0..count {
+                    constructor(cache.object(slab, i));
+                }
+            }
+
+            (*slab).next = cache.partial;
+            cache.partial = slab;
+        }
+
+        cache.stats.slabs += 1;
+        true
+    }
+
+    // Return all empty slabs of a cache to the frame allocator
+    fn shrink(&mut self, id: CacheId) -> usize {
+        let mut reclaimed = 0;
+
+        loop {
+            let slab = {
+                let cache = self.cache(id);
+                let mut current = cache.partial;
+                unsafe {
+                    while !current.is_null() && (*current).in_use != 0 {
+                        current = (*current).next;
+                    }
+
+                    if current.is_null() {
+                        break;
+                    }
+
+                    unlink(&mut cache.partial, current);
+                }
+
+                cache.stats.slabs -= 1;
+                cache.stats.reclaimed_slabs += 1;
+                current
+            };
+
+            self.free_page(slab as VirtualAddress);
+            reclaimed += 1;
+        }
+
+        reclaimed
+    }
+
+    fn cache(&mut self, id: CacheId) -> &mut SlabCache {
+        self.caches[id.0].as_mut().expect("Invalid slab cache")
+    }
+
+    // Map a free page of the window
+    fn allocate_page(&mut self) -> Option<VirtualAddress> {
+        for (i, word) in self.used_pages.iter_mut().enumerate() {
+            if *word == !0 {
+                continue;
+            }
+
+            let bit = (!*word).trailing_zeros() as usize;
+            let address = self.window + (i * 64 + bit) * PAGE_SIZE;
+            let mapped = MEMORY_CONTROLLER
+                .lock()
+                .as_mut()
+                .expect("Memory is not initialized")
+                .map_page(address, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+
+            if !mapped {
+                return None;
+            }
+
+            *word |= 1 << bit;
+            return Some(address);
+        }
+
+        None
+    }
+
+    fn free_page(&mut self, address: VirtualAddress) {
+        MEMORY_CONTROLLER
+            .lock()
+            .as_mut()
+            .expect("Memory is not initialized")
+            .unmap_page(address);
+
+        let page = (address - self.window) / PAGE_SIZE;
+        self.used_pages[page / 64] &= !(1 << (page % 64));
+    }
+
+    fn is_slab_page(&self, address: VirtualAddress) -> bool {
+        if address < self.window || address >= self.window + WINDOW_PAGES * PAGE_SIZE {
+            return false;
+        }
+
+        let page = (address - self.window) / PAGE_SIZE;
+        self.used_pages[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn print_stats(&self) {
+        println!("cache          size objs/slab  active slabs allocs  frees reclaimed");
+        for cache in self.caches.iter().filter_map(|cache| cache.as_ref()) {
+            let stats = &cache.stats;
+            println!(
+                "{:14} {:4} {:9} {:7} {:5} {:6} {:6} {:9}",
+                cache.name,
+                cache.object_size,
+                cache.objects_per_slab,
+                stats.active_objects,
+                stats.slabs,
+                stats.allocations,
+                stats.frees,
+                stats.reclaimed_slabs
+            );
+        }
+    }
+}
+
+// Remove a slab from a singly linked slab list
+unsafe fn unlink(list: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    let mut current = list as *mut *mut SlabHeader;
+    while !(*current).is_null() {
+        if *current == slab {
+            *current = (*slab).next;
+            return;
+        }
+        current = &mut (**current).next;
+    }
+
+    panic!("Slab is not part of the list");
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+// Reserve the slab window and set up the kmalloc caches. Called by memory::init.
+pub fn init() {
+    let window = MEMORY_CONTROLLER
+        .lock()
+        .as_mut()
+        .expect("Memory is not initialized")
+        .reserve_window(WINDOW_PAGES, "slab")
+        .expect("Could not reserve the slab window");
+
+    let mut allocator = SlabAllocator {
+        caches: [None; MAX_CACHES],
+        kmalloc_caches: [CacheId(0); 7],
+        window,
+        used_pages: [0; WINDOW_PAGES / 64],
+    };
+
+    for (i, &(name, size)) in KMALLOC_CACHES.iter().enumerate() {
+        allocator.kmalloc_caches[i] = allocator.create_cache(name, size, None);
+    }
+
+    *SLAB_ALLOCATOR.lock() = Some(allocator);
+}
+
+fn with_allocator<F, T>(f: F) -> T
+where
+    F: FnOnce(&mut SlabAllocator) -> T,
+{
+    f(SLAB_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("Slab allocator is not initialized"))
+}
+
+/// Create a named cache for objects of the given size. The constructor is run once on every
+/// object when its slab is created, freed objects have to be returned in constructed state.
+pub fn create_cache(
+    name: &'static str,
+    size: usize,
+    constructor: Option<fn(*mut u8)>,
+) -> CacheId {
+    with_allocator(|allocator| allocator.create_cache(name, size, constructor))
+}
+
+/// Allocate an object from a cache
+pub fn alloc(cache: CacheId) -> Option<*mut u8> {
+    with_allocator(|allocator| allocator.alloc(cache))
+}
+
+/// Return an object to the cache it was allocated from
+pub fn free(object: *mut u8) {
+    with_allocator(|allocator| allocator.free(object))
+}
+
+/// Return the empty slabs of a cache to the frame allocator. Returns the number of slabs freed.
+pub fn shrink(cache: CacheId) -> usize {
+    with_allocator(|allocator| allocator.shrink(cache))
+}
+
+/// Shrink every cache
+pub fn shrink_all() -> usize {
+    with_allocator(|allocator| {
+        let mut reclaimed = 0;
+        for i in 0..MAX_CACHES {
+            if allocator.caches[i].is_some() {
+                reclaimed += allocator.shrink(CacheId(i));
+            }
+        }
+        reclaimed
+    })
+}
+
+pub fn stats(cache: CacheId) -> CacheStats {
+    with_allocator(|allocator| allocator.cache(cache).stats)
+}
+
+pub fn print_stats() {
+    with_allocator(|allocator| allocator.print_stats())
+}
+
+/// Allocate memory from the smallest generic cache that fits size bytes
+pub fn kmalloc(size: usize) -> Option<*mut u8> {
+    with_allocator(|allocator| {
+        let index = match KMALLOC_CACHES.iter().position(|&(_, s)| s >= size) {
+            Some(index) => index,
+            None => return None,
+        };
+        let cache = allocator.kmalloc_caches[index];
+        allocator.alloc(cache)
+    })
+}
+
+/// Free memory returned by kmalloc
+pub fn kfree(object: *mut u8) {
+    free(object)
+}
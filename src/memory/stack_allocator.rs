@@ -0,0 +1,25 @@
+use memory::paging::VirtualAddress;
+
+// A kernel stack allocated by MemoryController::alloc_stack. The page below bottom is an unmapped
+// guard page, so running off the end of the stack page faults instead of corrupting memory.
+#[derive(Debug)]
+pub struct Stack {
+    top: VirtualAddress,
+    bottom: VirtualAddress,
+}
+
+impl Stack {
+    pub fn new(top: VirtualAddress, bottom: VirtualAddress) -> Stack {
+        assert!(top > bottom);
+        Stack { top, bottom }
+    }
+
+    // Initial stack pointer, the stack grows down from here
+    pub fn top(&self) -> VirtualAddress {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtualAddress {
+        self.bottom
+    }
+}
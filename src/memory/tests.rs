@@ -0,0 +1,102 @@
+// Host tests for the free ranges and the bitmap of freed frames the frame allocator reuses, run
+// with cargo test
+
+use super::area_frame_allocator::{FreeBitmap, FreeRanges, MAX_FREE_RANGES};
+use super::Frame;
+
+// Take every free frame and return the sorted frame numbers
+fn take_all(ranges: &mut FreeRanges) -> Vec<usize> {
+    let mut numbers = Vec::new();
+    while let Some(frame) = ranges.take() {
+        numbers.push(frame.number);
+    }
+    numbers.sort();
+    numbers
+}
+
+#[test]
+fn neighbours_are_merged() {
+    let mut ranges = FreeRanges::new();
+    for &number in &[10, 12, 11, 9, 13] {
+        ranges.insert(Frame { number: number });
+    }
+
+    assert_eq!(take_all(&mut ranges), vec![9, 10, 11, 12, 13]);
+    assert!(ranges.take().is_none());
+}
+
+#[test]
+fn scattered_frames_are_all_reused() {
+    let mut ranges = FreeRanges::new();
+    let numbers: Vec<usize> = (0..MAX_FREE_RANGES).map(|i| 100 + 2 * i).collect();
+    for &number in &numbers {
+        ranges.insert(Frame { number: number });
+    }
+
+    assert_eq!(take_all(&mut ranges), numbers);
+}
+
+#[test]
+#[should_panic(expected = "free ranges are in use")]
+fn untracked_frame_panics() {
+    let mut ranges = FreeRanges::new();
+    for i in 0..(MAX_FREE_RANGES + 1) {
+        ranges.insert(Frame { number: 2 * i });
+    }
+}
+
+#[test]
+#[should_panic(expected = "deallocated twice")]
+fn double_free_panics() {
+    let mut ranges = FreeRanges::new();
+    ranges.insert(Frame { number: 5 });
+    ranges.insert(Frame { number: 5 });
+}
+
+// Bitmap for the frames below count in zeroed memory that lives as long as the test
+fn bitmap(memory: &mut Vec<u64>, count: usize) -> FreeBitmap {
+    *memory = vec![0; (count + 63) / 64];
+    unsafe { FreeBitmap::new(memory.as_mut_ptr(), memory.len()) }
+}
+
+#[test]
+fn bitmap_reuses_frames_freed_in_any_order() {
+    let mut memory = Vec::new();
+    let mut bitmap = bitmap(&mut memory, 4096);
+
+    // Every third frame in an order that jumps around
+    let mut numbers: Vec<usize> = (0..1000).map(|i| (i * 769) % 1000 * 3).collect();
+    for &number in &numbers {
+        bitmap.insert(Frame { number: number });
+    }
+
+    // The lowest frames come first
+    numbers.sort();
+    for &number in &numbers {
+        assert_eq!(bitmap.take().map(|frame| frame.number), Some(number));
+    }
+    assert!(bitmap.take().is_none());
+
+    // Freeing a frame below the ones taken last makes it available again
+    bitmap.insert(Frame { number: 5 });
+    bitmap.insert(Frame { number: 4000 });
+    assert_eq!(bitmap.take().map(|frame| frame.number), Some(5));
+    assert_eq!(bitmap.take().map(|frame| frame.number), Some(4000));
+}
+
+#[test]
+#[should_panic(expected = "deallocated twice")]
+fn bitmap_double_free_panics() {
+    let mut memory = Vec::new();
+    let mut bitmap = bitmap(&mut memory, 128);
+    bitmap.insert(Frame { number: 70 });
+    bitmap.insert(Frame { number: 70 });
+}
+
+#[test]
+#[should_panic(expected = "outside of the memory map")]
+fn bitmap_rejects_unknown_frames() {
+    let mut memory = Vec::new();
+    let mut bitmap = bitmap(&mut memory, 128);
+    bitmap.insert(Frame { number: 128 });
+}
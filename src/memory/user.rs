@@ -0,0 +1,185 @@
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{mem, slice};
+use cpu;
+use memory::map::USER_SPACE_END;
+use memory::paging::{EntryFlags, Translation, VirtualAddress};
+use memory::{MEMORY_CONTROLLER, PAGE_SIZE};
+
+extern "C" {
+    fn copy_user_bytes(destination: *mut u8, source: *const u8, size: usize) -> usize;
+    static copy_user_copy: u8;
+    static copy_user_fixup: u8;
+}
+
+// Set once SMAP is enabled, stac and clac are invalid instructions without it
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    // The range does not lie completely below the end of user space
+    InvalidRange,
+    // A page of the range is not mapped user accessible, or not writable when writing
+    NotMapped,
+}
+
+/// A pointer into user memory. It can only be accessed by copying through copy_from_user and
+/// copy_to_user, which check that the memory really belongs to user space.
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    address: VirtualAddress,
+    object: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> UserPtr<T> {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> UserPtr<T>
+where
+    T: Copy,
+{
+    pub fn new(address: VirtualAddress) -> UserPtr<T> {
+        UserPtr {
+            address,
+            object: PhantomData,
+        }
+    }
+
+    pub fn address(&self) -> VirtualAddress {
+        self.address
+    }
+
+    /// Copy the value out of user memory. User code can store any bytes there, so T has to be
+    /// valid for every bit pattern, like integers and structs of them.
+    pub fn read(&self) -> Result<T, UserCopyError> {
+        unsafe {
+            let mut value: T = mem::zeroed();
+            copy_from_user(as_bytes_mut(&mut value), self.address)?;
+            Ok(value)
+        }
+    }
+
+    /// Copy the value into user memory
+    pub fn write(&self, value: &T) -> Result<(), UserCopyError> {
+        let bytes =
+            unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) };
+        copy_to_user(self.address, bytes)
+    }
+}
+
+/// Copy from user memory starting at source into the destination buffer
+pub fn copy_from_user(
+    destination: &mut [u8],
+    source: VirtualAddress,
+) -> Result<(), UserCopyError> {
+    check_range(source, destination.len(), false)?;
+
+    let left = with_user_access(|| unsafe {
+        copy_user_bytes(destination.as_mut_ptr(), source as *const u8, destination.len())
+    });
+    if left != 0 {
+        return Err(UserCopyError::NotMapped);
+    }
+
+    Ok(())
+}
+
+/// Copy the source buffer into user memory starting at destination
+pub fn copy_to_user(destination: VirtualAddress, source: &[u8]) -> Result<(), UserCopyError> {
+    check_range(destination, source.len(), true)?;
+
+    // Copy-on-write pages are resolved by the page fault handler like for user mode writes
+    let left = with_user_access(|| unsafe {
+        copy_user_bytes(destination as *mut u8, source.as_ptr(), source.len())
+    });
+    if left != 0 {
+        return Err(UserCopyError::NotMapped);
+    }
+
+    Ok(())
+}
+
+/// Where a page fault at the instruction pointer continues if it can't be resolved, if it
+/// happened while copying between kernel and user memory. The copy fails with NotMapped then.
+pub fn copy_fault_fixup(instruction_pointer: VirtualAddress) -> Option<VirtualAddress> {
+    let (copy, fixup) = unsafe {
+        (
+            &copy_user_copy as *const u8 as usize,
+            &copy_user_fixup as *const u8 as usize,
+        )
+    };
+
+    if instruction_pointer == copy {
+        Some(fixup)
+    } else {
+        None
+    }
+}
+
+/// Run f with supervisor access to user pages allowed. Interrupts are disabled meanwhile, as
+/// their handlers would run with AC set and could touch user memory unnoticed. A page fault
+/// inside f gets the flag back from the interrupted RFLAGS on iretq.
+pub fn with_user_access<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+
+    cpu::without_interrupts(|| {
+        if smap {
+            unsafe { asm!("stac" :::: "volatile") };
+        }
+        let result = f();
+        if smap {
+            unsafe { asm!("clac" :::: "volatile") };
+        }
+
+        result
+    })
+}
+
+// Called once SMAP is turned on in CR4
+pub fn smap_enabled() {
+    SMAP_ENABLED.store(true, Ordering::Relaxed);
+}
+
+// Check that the range lies in user space and every page of it is mapped for user access
+fn check_range(start: VirtualAddress, size: usize, write: bool) -> Result<(), UserCopyError> {
+    if size == 0 {
+        return Ok(());
+    }
+
+    match start.checked_add(size) {
+        Some(end) if end <= USER_SPACE_END => {}
+        _ => return Err(UserCopyError::InvalidRange),
+    }
+
+    let guard = MEMORY_CONTROLLER.lock();
+    let controller = guard.as_ref().expect("Memory is not initialized");
+
+    let first_page = start / PAGE_SIZE;
+    let last_page = (start + size - 1) / PAGE_SIZE;
+    for number in first_page..(last_page + 1) {
+        // The effective flags, so a table entry without user access denies it too
+        let flags = match controller.translate_detailed(number * PAGE_SIZE) {
+            Translation::Mapped { flags, .. } => flags,
+            Translation::NotMapped { .. } => return Err(UserCopyError::NotMapped),
+        };
+
+        let writable = flags.intersects(EntryFlags::WRITABLE | EntryFlags::COPY_ON_WRITE);
+        if !flags.contains(EntryFlags::USER_ACCESSIBLE) || (write && !writable) {
+            return Err(UserCopyError::NotMapped);
+        }
+    }
+
+    Ok(())
+}
+
+unsafe fn as_bytes_mut<T>(value: &mut T) -> &mut [u8] {
+    slice::from_raw_parts_mut(value as *mut T as *mut u8, mem::size_of::<T>())
+}
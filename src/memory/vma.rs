@@ -0,0 +1,166 @@
+use memory::paging::VirtualAddress;
+use memory::PAGE_SIZE;
+
+// Maximum number of kernel virtual memory regions that can be allocated at once
+const MAX_VMA_REGIONS: usize = 128;
+
+// What a region of kernel virtual memory is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Memory,
+    Stack,
+    // Address space only, the owner maps and unmaps pages inside it itself
+    Window,
+}
+
+// A region of kernel virtual memory. The guard pages lie directly below start and stay unmapped.
+#[derive(Debug, Clone, Copy)]
+pub struct VmaRegion {
+    start: VirtualAddress,
+    pages: usize,
+    guard_pages: usize,
+    owner: &'static str,
+    kind: RegionKind,
+}
+
+const EMPTY_REGION: VmaRegion = VmaRegion {
+    start: 0,
+    pages: 0,
+    guard_pages: 0,
+    owner: "",
+    kind: RegionKind::Memory,
+};
+
+impl VmaRegion {
+    // First usable address of the region
+    pub fn start_address(&self) -> VirtualAddress {
+        self.start
+    }
+
+    // One past the last usable address of the region
+    pub fn end_address(&self) -> VirtualAddress {
+        self.start + self.pages * PAGE_SIZE
+    }
+
+    // Start of the region including its guard pages
+    fn base_address(&self) -> VirtualAddress {
+        self.start - self.guard_pages * PAGE_SIZE
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    // Name of the subsystem that allocated this region
+    pub fn owner(&self) -> &'static str {
+        self.owner
+    }
+
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.base_address() && address < self.end_address()
+    }
+
+    pub fn is_guard_page(&self, address: VirtualAddress) -> bool {
+        address >= self.base_address() && address < self.start
+    }
+}
+
+// Hands out page aligned regions of the kernel virtual address space. Regions are kept sorted by
+// address and new regions are placed in the first gap that is large enough.
+pub struct VmaAllocator {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    regions: [VmaRegion; MAX_VMA_REGIONS],
+    count: usize,
+}
+
+impl VmaAllocator {
+    pub fn new(start: VirtualAddress, size: usize) -> VmaAllocator {
+        assert!(start % PAGE_SIZE == 0 && size % PAGE_SIZE == 0);
+
+        VmaAllocator {
+            start,
+            end: start + size,
+            regions: [EMPTY_REGION; MAX_VMA_REGIONS],
+            count: 0,
+        }
+    }
+
+    // Allocate a region of the given number of pages with guard_pages unmapped pages below it.
+    // Returns the start of the usable part of the region.
+    pub fn allocate(
+        &mut self,
+        pages: usize,
+        guard_pages: usize,
+        owner: &'static str,
+        kind: RegionKind,
+    ) -> Option<VirtualAddress> {
+        assert!(pages > 0, "Cannot allocate an empty region");
+
+        if self.count == MAX_VMA_REGIONS {
+            return None;
+        }
+
+        // Find the first gap between the sorted regions that is large enough
+        let size = (pages + guard_pages) * PAGE_SIZE;
+        let mut base = self.start;
+        let mut index = self.count;
+        for i in 0..self.count {
+            if self.regions[i].base_address() >= base + size {
+                index = i;
+                break;
+            }
+            base = self.regions[i].end_address();
+        }
+
+        if base + size > self.end {
+            return None;
+        }
+
+        // Shift the following regions up to keep the list sorted
+        for i in (index..self.count).rev() {
+            self.regions[i + 1] = self.regions[i];
+        }
+
+        let start = base + guard_pages * PAGE_SIZE;
+        self.regions[index] = VmaRegion {
+            start,
+            pages,
+            guard_pages,
+            owner,
+            kind,
+        };
+        self.count += 1;
+
+        Some(start)
+    }
+
+    // Release the region starting at the given address and return it
+    pub fn free(&mut self, start: VirtualAddress) -> Option<VmaRegion> {
+        let index = match self.regions().iter().position(|r| r.start == start) {
+            Some(index) => index,
+            None => return None,
+        };
+
+        let region = self.regions[index];
+        for i in index..self.count - 1 {
+            self.regions[i] = self.regions[i + 1];
+        }
+        self.count -= 1;
+
+        Some(region)
+    }
+
+    // Find the region containing the address, including its guard pages
+    pub fn find(&self, address: VirtualAddress) -> Option<&VmaRegion> {
+        self.regions().iter().find(|r| r.contains(address))
+    }
+
+    pub fn regions(&self) -> &[VmaRegion] {
+        &self.regions[..self.count]
+    }
+}
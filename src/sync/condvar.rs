@@ -0,0 +1,62 @@
+use super::{MutexGuard, WaitQueue};
+use core::mem;
+
+/// Condition variable for threads waiting on a Mutex until the data it protects changes
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock the mutex and sleep until notified, then lock it again. Like with any condition
+    /// variable the condition has to be checked again afterwards.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_inner(guard, None).0
+    }
+
+    /// Like wait, but stops waiting once the timeout in milliseconds elapsed. The flag is false if
+    /// the wait timed out.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: usize,
+    ) -> (MutexGuard<'a, T>, bool) {
+        self.wait_inner(guard, Some(timeout))
+    }
+
+    /// Wake the thread that waited longest
+    pub fn notify_one(&self) {
+        self.waiters.notify_one();
+    }
+
+    /// Wake every waiting thread
+    pub fn notify_all(&self) {
+        self.waiters.notify_all();
+    }
+
+    fn wait_inner<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<usize>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex = MutexGuard::mutex(&guard);
+        mem::forget(guard);
+
+        // Unlocking with interrupts disabled right before sleeping, so a notification sent after
+        // the mutex is released cannot be missed
+        let notified = self.waiters.wait_unless(
+            || {
+                unsafe { mutex.force_unlock() };
+                false
+            },
+            timeout,
+        );
+
+        (mutex.lock(), notified)
+    }
+}
@@ -0,0 +1,91 @@
+use core::ops::{Deref, DerefMut};
+use cpu;
+use spin;
+
+/// Spinlock that disables interrupts while it is held, so that an interrupt handler taking it
+/// cannot deadlock with the code it interrupted. Unlike the sleeping locks it can be used from
+/// interrupt handlers.
+pub struct IrqSafeMutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqSafeMutexGuard<'a, T: 'a> {
+    // Taken when the guard is dropped, so the lock is released before interrupts are enabled
+    guard: Option<spin::MutexGuard<'a, T>>,
+    // Whether interrupts were enabled before the lock was taken
+    interrupts: bool,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(data: T) -> IrqSafeMutex<T> {
+        IrqSafeMutex {
+            inner: spin::Mutex::new(data),
+        }
+    }
+
+    /// Disable interrupts and spin until the lock is free
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        let interrupts = disable_interrupts();
+        IrqSafeMutexGuard {
+            guard: Some(self.inner.lock()),
+            interrupts,
+        }
+    }
+
+    /// Take the lock if it is free, with interrupts disabled while it is held
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<T>> {
+        let interrupts = disable_interrupts();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSafeMutexGuard {
+                guard: Some(guard),
+                interrupts,
+            }),
+            None => {
+                restore_interrupts(interrupts);
+                None
+            }
+        }
+    }
+
+    /// Release the lock no matter who holds it. Only for the panic handler, which has to print
+    /// even if the panicking code held the lock. The holder does not enable interrupts again.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+impl<'a, T> Deref for IrqSafeMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqSafeMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqSafeMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        restore_interrupts(self.interrupts);
+    }
+}
+
+// Returns whether interrupts were enabled before
+fn disable_interrupts() -> bool {
+    let enabled = cpu::interrupts_enabled();
+    if enabled {
+        unsafe { cpu::disable_interrupts() };
+    }
+    enabled
+}
+
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe { cpu::enable_interrupts() };
+    }
+}
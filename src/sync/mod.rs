@@ -0,0 +1,18 @@
+// Synchronization primitives. Apart from IrqSafeMutex they put waiting threads to sleep instead of
+// spinning, so they may only be used from threads and never from interrupt handlers.
+
+mod condvar;
+mod irq_safe_mutex;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod wait_queue;
+#[cfg(test)]
+mod tests;
+
+pub use self::condvar::Condvar;
+pub use self::irq_safe_mutex::{IrqSafeMutex, IrqSafeMutexGuard};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::Semaphore;
+pub use self::wait_queue::WaitQueue;
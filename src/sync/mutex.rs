@@ -0,0 +1,100 @@
+use super::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use cpu;
+
+/// Mutual exclusion lock that puts threads to sleep while it is held by another thread. The
+/// unlocking thread hands the lock directly to the longest waiting one.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Sleep until the lock is free and take it
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.waiters.wait_unless(|| self.try_acquire(), None);
+        MutexGuard { mutex: self }
+    }
+
+    /// Take the lock if it is free
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.try_acquire() {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// Sleep until the lock is free or the timeout in milliseconds elapsed
+    pub fn lock_timeout(&self, timeout: usize) -> Option<MutexGuard<T>> {
+        if self.waiters.wait_unless(|| self.try_acquire(), Some(timeout)) {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        !self.locked.swap(true, Ordering::Acquire)
+    }
+
+    /// Release the lock without its guard. Unsafe because the guard releases it again when it is
+    /// dropped, so it has to be forgotten.
+    pub unsafe fn force_unlock(&self) {
+        self.unlock();
+    }
+
+    // Pass the lock to the next waiter, or free it if there is none
+    fn unlock(&self) {
+        cpu::without_interrupts(|| {
+            if !self.waiters.notify_one() {
+                self.locked.store(false, Ordering::Release);
+            }
+        });
+    }
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// The mutex the guard locks. Not a method, so it cannot hide a method of T.
+    pub fn mutex(guard: &MutexGuard<'a, T>) -> &'a Mutex<T> {
+        guard.mutex
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,211 @@
+use super::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use cpu;
+use spin;
+
+/// Reader-writer lock that puts threads to sleep while they cannot take it. Readers and writers
+/// take turns: new readers wait behind a waiting writer, and a writer unlocking admits all readers
+/// that waited for it before the next writer.
+pub struct RwLock<T> {
+    state: spin::Mutex<State>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+// Who holds the lock. The transitions are kept apart from the wait queues they notify.
+pub struct State {
+    // Number of readers holding the lock
+    pub readers: usize,
+    pub writer: bool,
+}
+
+impl State {
+    // Take the lock for reading unless a writer holds it or waits for it
+    pub fn try_read(&mut self, writers_waiting: bool) -> bool {
+        let free = !self.writer && !writers_waiting;
+        if free {
+            self.readers += 1;
+        }
+        free
+    }
+
+    pub fn try_write(&mut self) -> bool {
+        let free = !self.writer && self.readers == 0;
+        if free {
+            self.writer = true;
+        }
+        free
+    }
+
+    // The last reader hands the lock to the writer that notify_writer wakes, if there is one
+    pub fn unlock_read<W>(&mut self, notify_writer: W)
+    where
+        W: FnOnce() -> bool,
+    {
+        self.readers -= 1;
+        if self.readers == 0 && notify_writer() {
+            self.writer = true;
+        }
+    }
+
+    // A writer hands the lock to all readers that notify_readers wakes, or to the next writer if
+    // there are none
+    pub fn unlock_write<R, W>(&mut self, notify_readers: R, notify_writer: W)
+    where
+        R: FnOnce() -> usize,
+        W: FnOnce() -> bool,
+    {
+        self.readers = notify_readers();
+        self.writer = self.readers == 0 && notify_writer();
+    }
+
+    // A writer stopped waiting. The readers might only have waited for it.
+    pub fn writer_timed_out<R>(&mut self, writers_waiting: bool, notify_readers: R)
+    where
+        R: FnOnce() -> usize,
+    {
+        if !self.writer && !writers_waiting {
+            self.readers += notify_readers();
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> RwLock<T> {
+        RwLock {
+            state: spin::Mutex::new(State {
+                readers: 0,
+                writer: false,
+            }),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Sleep until no writer holds or waits for the lock and take it for reading
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.readers.wait_unless(|| self.try_acquire_read(), None);
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        if cpu::without_interrupts(|| self.try_acquire_read()) {
+            Some(RwLockReadGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Sleep until the lock can be taken for reading or the timeout in milliseconds elapsed
+    pub fn read_timeout(&self, timeout: usize) -> Option<RwLockReadGuard<T>> {
+        let acquired = self.readers
+            .wait_unless(|| self.try_acquire_read(), Some(timeout));
+        if acquired {
+            Some(RwLockReadGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Sleep until the lock is free and take it for writing
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.writers.wait_unless(|| self.try_acquire_write(), None);
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if cpu::without_interrupts(|| self.try_acquire_write()) {
+            Some(RwLockWriteGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Sleep until the lock is free or the timeout in milliseconds elapsed
+    pub fn write_timeout(&self, timeout: usize) -> Option<RwLockWriteGuard<T>> {
+        let acquired = self.writers
+            .wait_unless(|| self.try_acquire_write(), Some(timeout));
+        if acquired {
+            return Some(RwLockWriteGuard { lock: self });
+        }
+
+        cpu::without_interrupts(|| {
+            self.state
+                .lock()
+                .writer_timed_out(!self.writers.is_empty(), || self.readers.notify_all());
+        });
+        None
+    }
+
+    // Called with interrupts disabled
+    fn try_acquire_read(&self) -> bool {
+        self.state.lock().try_read(!self.writers.is_empty())
+    }
+
+    // Called with interrupts disabled
+    fn try_acquire_write(&self) -> bool {
+        self.state.lock().try_write()
+    }
+
+    fn unlock_read(&self) {
+        cpu::without_interrupts(|| {
+            self.state.lock().unlock_read(|| self.writers.notify_one());
+        });
+    }
+
+    fn unlock_write(&self) {
+        cpu::without_interrupts(|| {
+            self.state
+                .lock()
+                .unlock_write(|| self.readers.notify_all(), || self.writers.notify_one());
+        });
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_read();
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_write();
+    }
+}
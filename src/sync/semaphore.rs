@@ -0,0 +1,56 @@
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cpu;
+
+/// Counting semaphore. Threads sleep while no permits are left, and a released permit is handed
+/// directly to the longest waiting thread.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Sleep until a permit is available and take it
+    pub fn acquire(&self) {
+        self.waiters.wait_unless(|| self.try_acquire(), None);
+    }
+
+    /// Sleep until a permit is available or the timeout in milliseconds elapsed. Returns false
+    /// on timeout.
+    pub fn acquire_timeout(&self, timeout: usize) -> bool {
+        self.waiters.wait_unless(|| self.try_acquire(), Some(timeout))
+    }
+
+    /// Take a permit if one is available
+    pub fn try_acquire(&self) -> bool {
+        // Interrupts are disabled, so nothing can take the permit between the load and the store
+        cpu::without_interrupts(|| {
+            let permits = self.permits.load(Ordering::Acquire);
+            if permits > 0 {
+                self.permits.store(permits - 1, Ordering::Release);
+            }
+            permits > 0
+        })
+    }
+
+    /// Give back a permit
+    pub fn release(&self) {
+        cpu::without_interrupts(|| {
+            if !self.waiters.notify_one() {
+                self.permits.fetch_add(1, Ordering::Release);
+            }
+        });
+    }
+
+    /// Permits that are currently available
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
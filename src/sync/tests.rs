@@ -0,0 +1,196 @@
+// Host tests for the reader-writer lock states and the list of waiters, run with cargo test. The
+// wait queues are simulated by counting the threads waiting on them.
+
+use super::rwlock::State;
+use super::wait_queue::{List, Waiter};
+use std::cell::Cell;
+
+// Threads waiting for the lock
+struct Waiting {
+    readers: Cell<usize>,
+    writers: Cell<usize>,
+}
+
+impl Waiting {
+    fn new(readers: usize, writers: usize) -> Waiting {
+        Waiting {
+            readers: Cell::new(readers),
+            writers: Cell::new(writers),
+        }
+    }
+
+    fn notify_readers(&self) -> usize {
+        self.readers.replace(0)
+    }
+
+    fn notify_writer(&self) -> bool {
+        let writers = self.writers.get();
+        if writers > 0 {
+            self.writers.set(writers - 1);
+        }
+        writers > 0
+    }
+}
+
+#[test]
+fn readers_share_the_lock() {
+    let mut state = State {
+        readers: 0,
+        writer: false,
+    };
+    assert!(state.try_read(false));
+    assert!(state.try_read(false));
+    assert_eq!(state.readers, 2);
+    assert!(!state.try_write());
+
+    state.unlock_read(|| false);
+    state.unlock_read(|| false);
+    assert_eq!(state.readers, 0);
+    assert!(state.try_write());
+    assert!(!state.try_read(false));
+}
+
+#[test]
+fn readers_wait_behind_a_waiting_writer() {
+    let mut state = State {
+        readers: 1,
+        writer: false,
+    };
+    assert!(!state.try_read(true));
+    assert_eq!(state.readers, 1);
+}
+
+#[test]
+fn last_reader_hands_off_to_writer() {
+    let mut state = State {
+        readers: 2,
+        writer: false,
+    };
+    let waiting = Waiting::new(0, 2);
+
+    state.unlock_read(|| waiting.notify_writer());
+    assert_eq!((state.readers, state.writer), (1, false));
+    assert_eq!(waiting.writers.get(), 2);
+
+    state.unlock_read(|| waiting.notify_writer());
+    assert_eq!((state.readers, state.writer), (0, true));
+    assert_eq!(waiting.writers.get(), 1);
+}
+
+#[test]
+fn last_reader_frees_the_lock_without_writers() {
+    let mut state = State {
+        readers: 1,
+        writer: false,
+    };
+    state.unlock_read(|| false);
+    assert_eq!((state.readers, state.writer), (0, false));
+}
+
+#[test]
+fn writer_admits_all_waiting_readers() {
+    let mut state = State {
+        readers: 0,
+        writer: true,
+    };
+    let waiting = Waiting::new(3, 1);
+
+    // Waiting readers go before the next writer
+    state.unlock_write(|| waiting.notify_readers(), || waiting.notify_writer());
+    assert_eq!((state.readers, state.writer), (3, false));
+    assert_eq!((waiting.readers.get(), waiting.writers.get()), (0, 1));
+
+    // The writer is next once they are done
+    for _ in 0..3 {
+        state.unlock_read(|| waiting.notify_writer());
+    }
+    assert_eq!((state.readers, state.writer), (0, true));
+    assert_eq!(waiting.writers.get(), 0);
+}
+
+#[test]
+fn writer_hands_off_to_writer_without_readers() {
+    let mut state = State {
+        readers: 0,
+        writer: true,
+    };
+    let waiting = Waiting::new(0, 1);
+
+    state.unlock_write(|| waiting.notify_readers(), || waiting.notify_writer());
+    assert_eq!((state.readers, state.writer), (0, true));
+
+    // Nobody is left to take it
+    state.unlock_write(|| waiting.notify_readers(), || waiting.notify_writer());
+    assert_eq!((state.readers, state.writer), (0, false));
+}
+
+#[test]
+fn timed_out_writer_lets_readers_back_in() {
+    // A reader holds the lock, the writer waiting for it kept two more readers out
+    let mut state = State {
+        readers: 1,
+        writer: false,
+    };
+    let waiting = Waiting::new(2, 0);
+
+    state.writer_timed_out(false, || waiting.notify_readers());
+    assert_eq!((state.readers, state.writer), (3, false));
+    assert_eq!(waiting.readers.get(), 0);
+}
+
+#[test]
+fn timed_out_writer_keeps_readers_behind_other_writers() {
+    let mut state = State {
+        readers: 1,
+        writer: false,
+    };
+    state.writer_timed_out(true, || panic!("Readers were admitted past a waiting writer"));
+    assert_eq!(state.readers, 1);
+
+    // Another writer got the lock meanwhile
+    let mut state = State {
+        readers: 0,
+        writer: true,
+    };
+    state.writer_timed_out(false, || panic!("Readers were admitted while a writer holds it"));
+    assert_eq!((state.readers, state.writer), (0, true));
+}
+
+#[test]
+fn waiters_are_woken_oldest_first() {
+    let mut list = List::new();
+    let mut waiters = [Waiter::new(1), Waiter::new(2), Waiter::new(3)];
+    for waiter in waiters.iter_mut() {
+        unsafe { list.push_back(waiter) };
+    }
+    assert!(waiters.iter().all(|waiter| waiter.queued));
+
+    assert_eq!(list.pop_front(), Some(1));
+    assert_eq!(list.pop_front(), Some(2));
+    assert_eq!(list.pop_front(), Some(3));
+    assert_eq!(list.pop_front(), None);
+    assert!(list.is_empty());
+    assert!(waiters.iter().all(|waiter| !waiter.queued));
+}
+
+#[test]
+fn timed_out_waiter_leaves_from_the_middle() {
+    let mut list = List::new();
+    let mut waiters = [Waiter::new(1), Waiter::new(2), Waiter::new(3)];
+    for waiter in waiters.iter_mut() {
+        unsafe { list.push_back(waiter) };
+    }
+
+    unsafe { list.remove(&mut waiters[1]) };
+    assert!(!waiters[1].queued);
+    assert_eq!(list.pop_front(), Some(1));
+
+    // The last one is the tail, so the list is empty afterwards
+    unsafe { list.remove(&mut waiters[2]) };
+    assert!(list.is_empty());
+    assert_eq!(list.pop_front(), None);
+
+    // New waiters are queued behind an emptied list
+    unsafe { list.push_back(&mut waiters[0]) };
+    assert_eq!(list.pop_front(), Some(1));
+}
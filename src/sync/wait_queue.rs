@@ -0,0 +1,187 @@
+use core::ptr;
+use cpu;
+use interrupts::{self, timer};
+use spin;
+use task::{self, ThreadId};
+use time;
+
+// A sleeping thread. Waiters live on the stack of their thread, which stays put while it sleeps.
+pub struct Waiter {
+    thread: ThreadId,
+    previous: *mut Waiter,
+    next: *mut Waiter,
+    // Cleared by the thread that removes the waiter to wake it
+    pub queued: bool,
+}
+
+impl Waiter {
+    pub fn new(thread: ThreadId) -> Waiter {
+        Waiter {
+            thread,
+            previous: ptr::null_mut(),
+            next: ptr::null_mut(),
+            queued: false,
+        }
+    }
+}
+
+// Doubly linked list of waiters, oldest first
+pub struct List {
+    head: *mut Waiter,
+    tail: *mut Waiter,
+}
+
+// The waiters are only accessed with the lock held
+unsafe impl Send for List {}
+
+impl List {
+    pub const fn new() -> List {
+        List {
+            head: 0 as *mut Waiter,
+            tail: 0 as *mut Waiter,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    pub unsafe fn push_back(&mut self, waiter: *mut Waiter) {
+        (*waiter).previous = self.tail;
+        (*waiter).next = ptr::null_mut();
+        (*waiter).queued = true;
+
+        if self.tail.is_null() {
+            self.head = waiter;
+        } else {
+            (*self.tail).next = waiter;
+        }
+        self.tail = waiter;
+    }
+
+    pub unsafe fn remove(&mut self, waiter: *mut Waiter) {
+        if (*waiter).previous.is_null() {
+            self.head = (*waiter).next;
+        } else {
+            (*(*waiter).previous).next = (*waiter).next;
+        }
+
+        if (*waiter).next.is_null() {
+            self.tail = (*waiter).previous;
+        } else {
+            (*(*waiter).next).previous = (*waiter).previous;
+        }
+
+        (*waiter).queued = false;
+    }
+
+    // Remove the oldest waiter and return its thread
+    pub fn pop_front(&mut self) -> Option<ThreadId> {
+        let waiter = self.head;
+        if waiter.is_null() {
+            return None;
+        }
+
+        unsafe {
+            self.remove(waiter);
+            Some((*waiter).thread)
+        }
+    }
+}
+
+/// Threads sleeping until a condition changes. They are woken in the order they started waiting.
+///
+/// The kernel runs on a single CPU, so disabling interrupts makes checking a condition and going to
+/// sleep atomic with respect to the threads that change it and notify the queue.
+pub struct WaitQueue {
+    waiters: spin::Mutex<List>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: spin::Mutex::new(List::new()),
+        }
+    }
+
+    /// Sleep unless ready returns true, which is called with interrupts disabled. Returns true if
+    /// ready did or the thread was notified, or false once the timeout in milliseconds elapsed.
+    pub fn wait_unless<F>(&self, ready: F, timeout: Option<usize>) -> bool
+    where
+        F: FnOnce() -> bool,
+    {
+        debug_assert!(!interrupts::in_interrupt(), "Sleeping in interrupt context");
+
+        cpu::without_interrupts(|| {
+            if ready() {
+                return true;
+            }
+            if timeout == Some(0) {
+                return false;
+            }
+
+            let mut waiter = Waiter::new(task::current());
+            let waiter: *mut Waiter = &mut waiter;
+
+            unsafe { self.waiters.lock().push_back(waiter) };
+            let deadline = timeout.map(time::deadline);
+
+            loop {
+                task::block(deadline);
+
+                // A waiter that is not queued anymore was notified, even if the deadline passed
+                // as well. One that is still queued was woken by its deadline, or by a stray
+                // task::wake and has to sleep again.
+                let mut waiters = self.waiters.lock();
+                unsafe {
+                    if !(*waiter).queued {
+                        return true;
+                    }
+                    if deadline.map_or(false, |deadline| timer::ticks() >= deadline) {
+                        waiters.remove(waiter);
+                        return false;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Sleep until notified
+    pub fn wait(&self) {
+        self.wait_unless(|| false, None);
+    }
+
+    /// Sleep until notified or the timeout in milliseconds elapsed. Returns false on timeout.
+    pub fn wait_timeout(&self, timeout: usize) -> bool {
+        self.wait_unless(|| false, Some(timeout))
+    }
+
+    /// Wake the thread that waited longest. Returns false if no thread was waiting.
+    pub fn notify_one(&self) -> bool {
+        // Removing and waking the thread happen together, otherwise a thread that timed out in
+        // between could be woken again from the next thing it sleeps on
+        cpu::without_interrupts(|| {
+            let thread = self.waiters.lock().pop_front();
+            match thread {
+                Some(thread) => {
+                    task::wake(thread);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Wake every waiting thread and return their number
+    pub fn notify_all(&self) -> usize {
+        let mut count = 0;
+        while self.notify_one() {
+            count += 1;
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        cpu::without_interrupts(|| self.waiters.lock().is_empty())
+    }
+}
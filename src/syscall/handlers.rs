@@ -0,0 +1,75 @@
+use super::{Arguments, Errno, SyscallResult};
+use core::cmp;
+use interrupts::timer;
+use memory::copy_from_user;
+use task;
+use time;
+use vga_buffer::WRITER;
+
+// File descriptors of the console, there are no files
+const STDOUT: usize = 1;
+const STDERR: usize = 2;
+
+// Longest write and sleep a single system call accepts
+const MAX_WRITE: usize = 64 * 1024;
+const MAX_SLEEP_MS: usize = 24 * 60 * 60 * 1000;
+
+// The user buffer is copied in chunks of this size
+const WRITE_CHUNK: usize = 256;
+
+// exit(status): end the calling thread
+pub fn exit(arguments: &Arguments) -> SyscallResult {
+    println!(
+        "Thread {} exited with status {}",
+        task::current(),
+        arguments.raw(0) as isize
+    );
+    task::exit();
+}
+
+// write(fd, buffer, length): print the buffer on the console and return the number of bytes
+// written
+pub fn write(arguments: &Arguments) -> SyscallResult {
+    match arguments.raw(0) {
+        STDOUT | STDERR => {}
+        _ => return Err(Errno::EBADF),
+    }
+    let (address, length) = arguments.user_buffer(1, 2, MAX_WRITE)?;
+
+    let mut chunk = [0; WRITE_CHUNK];
+    let mut written = 0;
+    while written < length {
+        let size = cmp::min(WRITE_CHUNK, length - written);
+        copy_from_user(&mut chunk[..size], address + written)?;
+
+        let mut writer = WRITER.lock();
+        for &byte in &chunk[..size] {
+            writer.write_byte(byte);
+        }
+        written += size;
+    }
+
+    Ok(written)
+}
+
+// yield(): let the other ready threads run
+pub fn yield_now(_: &Arguments) -> SyscallResult {
+    task::yield_now();
+    Ok(0)
+}
+
+// sleep(ms): sleep for at least the given number of milliseconds
+pub fn sleep(arguments: &Arguments) -> SyscallResult {
+    time::sleep(arguments.number(0, MAX_SLEEP_MS)?);
+    Ok(0)
+}
+
+// gettid(): id of the calling thread
+pub fn gettid(_: &Arguments) -> SyscallResult {
+    Ok(task::current())
+}
+
+// uptime(): milliseconds since boot
+pub fn uptime(_: &Arguments) -> SyscallResult {
+    Ok(timer::ticks_to_ms(timer::ticks()))
+}
@@ -0,0 +1,202 @@
+// System calls. User mode enters the kernel with SYSCALL, or int 0x80 for debugging, with the
+// number in rax and up to six arguments in rdi, rsi, rdx, r10, r8 and r9. The result comes back in
+// rax, errors as the negated error number like on Linux.
+
+mod handlers;
+
+use core::mem;
+use interrupts;
+use memory::map::USER_SPACE_END;
+use memory::{UserCopyError, UserPtr, VirtualAddress};
+use x86_64::structures::idt::HandlerFunc;
+
+/// Vector of the int 0x80 fallback
+pub const INT80_VECTOR: usize = 0x80;
+
+// Flags cleared on SYSCALL: trap, interrupts, direction and alignment check. Interrupts are
+// enabled again once the entry stub is on the kernel stack.
+const SYSCALL_FLAG_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+// Enables SYSCALL and SYSRET in the EFER MSR
+const SYSCALL_ENABLE: u64 = 1 << 0;
+
+extern "C" {
+    fn syscall_entry();
+    fn int80_entry();
+    static mut syscall_kernel_stack: usize;
+    static mut syscall_user_code: u64;
+    static mut syscall_user_data: u64;
+    static return_to_user: u8;
+}
+
+/// Error numbers, the same as on Linux
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    EBADF = 9,
+    EFAULT = 14,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+impl From<UserCopyError> for Errno {
+    fn from(_: UserCopyError) -> Errno {
+        Errno::EFAULT
+    }
+}
+
+pub type SyscallResult = Result<usize, Errno>;
+
+// Registers saved by the entry stubs, see syscall.asm
+#[repr(C)]
+pub struct Registers {
+    r9: usize,
+    r8: usize,
+    r10: usize,
+    rdx: usize,
+    rsi: usize,
+    rdi: usize,
+    // The number on entry and the result on return
+    rax: usize,
+}
+
+/// Arguments of a system call. Handlers get them through methods that check them for the type
+/// the handler expects.
+pub struct Arguments {
+    values: [usize; 6],
+}
+
+impl Arguments {
+    /// The argument as it was passed
+    pub fn raw(&self, index: usize) -> usize {
+        self.values[index]
+    }
+
+    /// An argument that must not be larger than max
+    pub fn number(&self, index: usize, max: usize) -> Result<usize, Errno> {
+        let value = self.values[index];
+        if value > max {
+            return Err(Errno::EINVAL);
+        }
+        Ok(value)
+    }
+
+    /// A pointer into user memory. Whether the memory is mapped is checked once it is accessed.
+    pub fn user_ptr<T: Copy>(&self, index: usize) -> Result<UserPtr<T>, Errno> {
+        let address = self.values[index];
+        match address.checked_add(mem::size_of::<T>()) {
+            Some(end) if end <= USER_SPACE_END => Ok(UserPtr::new(address)),
+            _ => Err(Errno::EFAULT),
+        }
+    }
+
+    /// A buffer in user memory given by an address and a length of at most max bytes. Whether
+    /// the memory is mapped is checked once it is accessed.
+    pub fn user_buffer(
+        &self,
+        address_index: usize,
+        length_index: usize,
+        max: usize,
+    ) -> Result<(VirtualAddress, usize), Errno> {
+        let address = self.values[address_index];
+        let length = self.number(length_index, max)?;
+        match address.checked_add(length) {
+            Some(end) if end <= USER_SPACE_END => Ok((address, length)),
+            _ => Err(Errno::EFAULT),
+        }
+    }
+}
+
+// Handler of a system call
+type Handler = fn(&Arguments) -> SyscallResult;
+
+// Numbers of the system calls, their index in SYSCALLS
+pub const SYS_EXIT: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_YIELD: usize = 2;
+pub const SYS_SLEEP: usize = 3;
+pub const SYS_GETTID: usize = 4;
+pub const SYS_UPTIME: usize = 5;
+
+// Dispatch table, indexed by the system call number. Numbers are part of the ABI and never reused.
+static SYSCALLS: [Handler; 6] = [
+    handlers::exit,
+    handlers::write,
+    handlers::yield_now,
+    handlers::sleep,
+    handlers::gettid,
+    handlers::uptime,
+];
+
+/// Program the MSRs for SYSCALL. Needs the GDT from interrupts::init.
+pub fn init() {
+    use x86_64::registers::msr::{rdmsr, wrmsr, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
+
+    // SYSCALL loads the kernel code segment from STAR and the kernel data segment from the entry
+    // after it. SYSRET loads the user code segment from 16 bytes and the user data segment from 8
+    // bytes after the user base in STAR, with their privilege levels.
+    let selectors = interrupts::selectors();
+    assert!(
+        selectors.kernel_data == selectors.kernel_code + 8
+            && selectors.user_code == selectors.user_data + 8,
+        "GDT layout does not fit SYSCALL and SYSRET"
+    );
+    let user_base = selectors.user_data - 8;
+    let star = (selectors.kernel_code as u64) << 32 | (user_base as u64) << 48;
+
+    unsafe {
+        syscall_user_code = selectors.user_code as u64;
+        syscall_user_data = selectors.user_data as u64;
+
+        wrmsr(IA32_STAR, star);
+        wrmsr(IA32_LSTAR, syscall_entry as u64);
+        wrmsr(IA32_FMASK, SYSCALL_FLAG_MASK);
+        wrmsr(IA32_EFER, rdmsr(IA32_EFER) | SYSCALL_ENABLE);
+    }
+}
+
+/// Set the stack the SYSCALL entry switches to, see interrupts::set_kernel_stack
+pub fn set_kernel_stack(top: usize) {
+    unsafe { syscall_kernel_stack = top };
+}
+
+/// Whether a fault at the instruction pointer happened on the iretq back to user mode. User mode
+/// chose the address it returns to then, e.g. by making a system call in the last bytes of user
+/// space, so the thread is at fault and not the kernel.
+pub fn faulted_on_return(instruction_pointer: VirtualAddress) -> bool {
+    instruction_pointer == unsafe { &return_to_user as *const u8 as usize }
+}
+
+/// The int 0x80 entry stub to put into the IDT. It is written in assembly since it needs the
+/// registers of user mode, so it only looks like an interrupt handler.
+pub fn int80_handler() -> HandlerFunc {
+    unsafe { mem::transmute(int80_entry as unsafe extern "C" fn()) }
+}
+
+/// Run the system call with the given number. Unknown numbers fail with ENOSYS.
+pub fn dispatch(number: usize, arguments: &Arguments) -> SyscallResult {
+    match SYSCALLS.get(number) {
+        Some(&handler) => handler(arguments),
+        None => Err(Errno::ENOSYS),
+    }
+}
+
+// Called by the entry stubs in syscall.asm with interrupts enabled, on the kernel stack of the
+// calling thread
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(registers: &mut Registers) {
+    let arguments = Arguments {
+        values: [
+            registers.rdi,
+            registers.rsi,
+            registers.rdx,
+            registers.r10,
+            registers.r8,
+            registers.r9,
+        ],
+    };
+
+    registers.rax = match dispatch(registers.rax, &arguments) {
+        Ok(value) => value,
+        Err(errno) => (-(errno as isize)) as usize,
+    };
+}
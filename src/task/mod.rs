@@ -0,0 +1,581 @@
+mod policy;
+mod thread;
+#[cfg(test)]
+mod tests;
+
+pub use self::policy::PolicyKind;
+pub use self::thread::{ThreadId, DEFAULT_PRIORITY, MAX_PRIORITY};
+use self::thread::{Thread, ThreadState, UserContext};
+use core::{mem, ptr};
+use cpu;
+use interrupts::{self, timer};
+use memory::{self, slab, AddressSpace, Stack, VirtualAddress};
+use spin::Mutex;
+use sync::WaitQueue;
+use time;
+
+// Maximum number of threads at once, including exited threads that were not joined yet. Every
+// slot has a timer of its own for blocking with a deadline.
+const MAX_THREADS: usize = time::THREAD_TIMERS;
+
+// Pages of the stack of every thread, the same 16 KiB as the boot stack
+const STACK_PAGES: usize = 4;
+
+extern "C" {
+    fn switch_context(old_stack_pointer: *mut VirtualAddress, new_stack_pointer: VirtualAddress);
+}
+
+// Outcome of trying to join a thread
+enum Join {
+    // The thread had exited and was removed, its stack and address space still have to be freed
+    Exited(Option<Stack>, Option<*mut AddressSpace>),
+    // The current thread was blocked until the thread exits
+    Blocked,
+}
+
+// All threads. Only locked with interrupts disabled, so a thread holding it is never switched
+// away from.
+struct Threads {
+    threads: [Option<Thread>; MAX_THREADS],
+    // Slot of the running thread
+    current: usize,
+    // Slot of the idle thread, which only runs if no other thread is ready
+    idle: Option<usize>,
+    next_id: ThreadId,
+    policy: PolicyKind,
+}
+
+// The address spaces of user threads are only used by their thread and freed by the joiner
+unsafe impl Send for Threads {}
+
+static THREADS: Mutex<Threads> = Mutex::new(Threads::new());
+
+// The reaper thread sleeps here until a detached thread exits
+static REAPER: WaitQueue = WaitQueue::new();
+
+impl Threads {
+    const fn new() -> Threads {
+        Threads {
+            threads: [None; MAX_THREADS],
+            current: 0,
+            idle: None,
+            next_id: 0,
+            policy: PolicyKind::RoundRobin,
+        }
+    }
+
+    // Add a thread to a free slot. Returns None if the table is full.
+    fn insert<F>(&mut self, make_thread: F) -> Option<ThreadId>
+    where
+        F: FnOnce(ThreadId) -> Thread,
+    {
+        let slot = match self.threads.iter().position(|thread| thread.is_none()) {
+            Some(slot) => slot,
+            None => return None,
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.threads[slot] = Some(make_thread(id));
+        self.ready(slot);
+        Some(id)
+    }
+
+    fn find(&self, id: ThreadId) -> Option<usize> {
+        self.threads.iter().position(|thread| match *thread {
+            Some(ref thread) => thread.id() == id,
+            None => false,
+        })
+    }
+
+    fn current(&mut self) -> &mut Thread {
+        self.threads[self.current]
+            .as_mut()
+            .expect("Threads are not initialized")
+    }
+
+    // Make a blocked thread ready again
+    fn wake(&mut self, id: ThreadId) {
+        if let Some(slot) = self.find(id) {
+            let blocked = {
+                let thread = self.threads[slot].as_mut().unwrap();
+                let blocked = thread.state() == ThreadState::Blocked;
+                if blocked {
+                    thread.set_state(ThreadState::Ready);
+                }
+                blocked
+            };
+
+            if blocked {
+                self.ready(slot);
+            }
+        }
+    }
+
+    // Tell the policy that the thread in slot became ready
+    fn ready(&mut self, slot: usize) {
+        let mut runnable = [0; MAX_THREADS];
+        let count = self.runnable(&mut runnable);
+        self.policy
+            .policy()
+            .ready(&mut self.threads, slot, &runnable[..count]);
+    }
+
+    // Collect the slots of the threads that can run except the idle thread, round robin starting
+    // after the current one. Returns their number.
+    fn runnable(&self, slots: &mut [usize; MAX_THREADS]) -> usize {
+        let mut count = 0;
+        for i in 1..(MAX_THREADS + 1) {
+            let slot = (self.current + i) % MAX_THREADS;
+            if Some(slot) == self.idle {
+                continue;
+            }
+
+            if let Some(ref thread) = self.threads[slot] {
+                if thread.is_runnable() {
+                    slots[count] = slot;
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    // Let the policy pick the thread to run next. The idle thread runs if nothing else can.
+    fn pick_next(&mut self) -> usize {
+        let mut candidates = [0; MAX_THREADS];
+        let count = self.runnable(&mut candidates);
+        if count == 0 {
+            return self.idle.expect("No thread is ready to run");
+        }
+
+        self.policy
+            .policy()
+            .pick_next(&mut self.threads, &candidates[..count])
+    }
+
+    // Make the next thread the current one. Returns where to save the stack pointer of the
+    // previous thread and the stack pointer to continue the next one with, or None if the current
+    // thread keeps running.
+    fn switch_next(&mut self) -> Option<(*mut VirtualAddress, VirtualAddress)> {
+        let next = self.pick_next();
+        {
+            let policy = self.policy.policy();
+            let thread = self.threads[next].as_mut().unwrap();
+            let time_slice = policy.time_slice(thread);
+            thread.set_time_slice(time_slice);
+        }
+
+        if next == self.current {
+            return None;
+        }
+
+        let old_stack_pointer = {
+            let previous = self.current();
+            if previous.state() == ThreadState::Running {
+                previous.set_state(ThreadState::Ready);
+            }
+            previous.stack_pointer_mut()
+        };
+
+        self.current = next;
+        let current = self.current();
+        current.set_state(ThreadState::Running);
+
+        // Interrupts in user mode enter the kernel on the stack of the thread. Kernel threads keep
+        // the address space of the thread before them, they only use the shared kernel half.
+        if let Some(top) = current.stack_top() {
+            interrupts::set_kernel_stack(top);
+        }
+        if let Some(user) = current.user() {
+            unsafe { (*user.address_space).load() };
+        }
+
+        Some((old_stack_pointer, current.stack_pointer()))
+    }
+
+    // Remove the thread if it exited, otherwise block the current thread until it does. Returns
+    // None if there is no such thread or it is detached and the caller is not the reaper.
+    fn join(&mut self, id: ThreadId, reaper: bool) -> Option<Join> {
+        let slot = match self.find(id) {
+            Some(slot) => slot,
+            None => return None,
+        };
+        if self.threads[slot].as_ref().unwrap().is_detached() != reaper {
+            return None;
+        }
+
+        let current = self.current().id();
+        let exited = {
+            let thread = self.threads[slot].as_mut().unwrap();
+            if thread.state() == ThreadState::Exited {
+                Some((thread.take_stack(), thread.take_address_space()))
+            } else {
+                thread.set_joiner(current);
+                None
+            }
+        };
+
+        match exited {
+            Some((stack, address_space)) => {
+                self.threads[slot] = None;
+                Some(Join::Exited(stack, address_space))
+            }
+            None => {
+                self.current().set_state(ThreadState::Blocked);
+                Some(Join::Blocked)
+            }
+        }
+    }
+
+    // A detached thread that exited and has to be freed
+    fn exited_detached(&self) -> Option<ThreadId> {
+        self.threads
+            .iter()
+            .filter_map(|thread| thread.as_ref())
+            .find(|thread| thread.is_detached() && thread.state() == ThreadState::Exited)
+            .map(|thread| thread.id())
+    }
+
+    // Account a timer tick to the running thread. Returns true if it should be preempted.
+    fn tick(&mut self) -> bool {
+        let idle = Some(self.current) == self.idle;
+        let policy = self.policy.policy();
+        match self.threads[self.current] {
+            // The idle thread gives way as soon as another thread is ready
+            Some(ref mut thread) if idle => {
+                thread.tick();
+                true
+            }
+            Some(ref mut thread) if thread.state() == ThreadState::Running => {
+                policy.charge(thread);
+                thread.tick()
+            }
+            // Not initialized yet, or the thread is already about to switch away
+            _ => false,
+        }
+    }
+}
+
+/// Turn the boot flow of control into the first thread and start the idle thread
+pub fn init() {
+    cpu::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        assert!(threads.idle.is_none(), "Threads are already initialized");
+
+        threads.current = 0;
+        threads.insert(Thread::boot).unwrap();
+    });
+
+    let idle_id = spawn(idle).expect("Could not create the idle thread");
+    cpu::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        threads.idle = threads.find(idle_id);
+    });
+}
+
+/// Start the thread that frees detached threads once they exit. It inherits the interrupt flag,
+/// so this has to be called after interrupts are enabled.
+pub fn start_reaper() -> ThreadId {
+    assert!(cpu::interrupts_enabled(), "The reaper would run with interrupts disabled");
+    spawn(reaper).expect("Could not create the reaper thread")
+}
+
+/// Start a kernel thread running entry on a stack of its own. The thread exits when entry
+/// returns and has to be joined or detached to free its stack. Returns None if out of memory or
+/// threads.
+pub fn spawn(entry: fn()) -> Option<ThreadId> {
+    spawn_thread(entry, None)
+}
+
+/// Start a thread that runs a user program in the address space, beginning at entry with the
+/// given user stack pointer. The thread owns the address space and exits once the program causes
+/// an exception. Joining it frees the address space as well. Returns None if out of memory or
+/// threads.
+pub fn spawn_user(
+    address_space: AddressSpace,
+    entry: VirtualAddress,
+    stack_pointer: VirtualAddress,
+) -> Option<ThreadId> {
+    // Moved out of the way, the thread table is copied around too often for it
+    let memory = match slab::kmalloc(mem::size_of::<AddressSpace>()) {
+        Some(memory) => memory as *mut AddressSpace,
+        None => return None,
+    };
+    unsafe { ptr::write(memory, address_space) };
+
+    let user = UserContext {
+        address_space: memory,
+        entry,
+        stack_pointer,
+    };
+
+    let id = spawn_thread(user_main, Some(user));
+    if id.is_none() {
+        free_address_space(memory);
+    }
+    id
+}
+
+fn spawn_thread(entry: fn(), user: Option<UserContext>) -> Option<ThreadId> {
+    let stack = match memory::alloc_stack(STACK_PAGES, "thread") {
+        Some(stack) => stack,
+        None => return None,
+    };
+
+    // The thread starts with interrupts enabled if they are enabled for its creator
+    let interrupts = cpu::interrupts_enabled();
+
+    let mut stack = Some(stack);
+    let id = cpu::without_interrupts(|| {
+        THREADS.lock().insert(|id| {
+            let mut thread = Thread::new(id, stack.take().unwrap(), entry, interrupts);
+            if let Some(user) = user {
+                thread.set_user(user);
+            }
+            thread
+        })
+    });
+
+    // The closure only takes the stack if there was a free slot
+    if let Some(stack) = stack {
+        memory::free_stack(stack);
+    }
+
+    id
+}
+
+/// Id of the running thread
+pub fn current() -> ThreadId {
+    cpu::without_interrupts(|| THREADS.lock().current().id())
+}
+
+/// Change the scheduling priority of a thread, between 0 and MAX_PRIORITY. Returns false if there
+/// is no such thread or the priority is out of range.
+pub fn set_priority(id: ThreadId, priority: usize) -> bool {
+    cpu::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        match threads.find(id) {
+            Some(slot) => threads.threads[slot].as_mut().unwrap().set_priority(priority),
+            None => false,
+        }
+    })
+}
+
+/// Switch to another scheduling policy. Takes effect at the next thread switch.
+pub fn set_policy(policy: PolicyKind) {
+    cpu::without_interrupts(|| THREADS.lock().policy = policy);
+}
+
+/// Put the running thread to sleep until another thread wakes it or, if given, the timer reaches
+/// the deadline tick. Callers have to disable interrupts before deciding to block and make sure
+/// that the thread that will wake it knows about it, or the wakeup could be missed.
+pub fn block(deadline: Option<usize>) {
+    debug_assert!(!interrupts::in_interrupt(), "Blocking in interrupt context");
+
+    cpu::without_interrupts(|| {
+        let (slot, id) = {
+            let mut threads = THREADS.lock();
+            let slot = threads.current;
+            let current = threads.current();
+            current.set_state(ThreadState::Blocked);
+            (slot, current.id())
+        };
+
+        // The timer of the slot is free again once block returns, whether it fired or not
+        let timer = deadline.map(|deadline| time::add_thread_timer_at(slot, deadline, wake, id));
+
+        schedule();
+
+        if let Some(timer) = timer {
+            time::cancel(timer);
+        }
+    });
+}
+
+/// Make a blocked thread ready to run again. Does nothing if it is not blocked.
+pub fn wake(id: ThreadId) {
+    cpu::without_interrupts(|| THREADS.lock().wake(id));
+}
+
+/// Let the other ready threads run before continuing
+pub fn yield_now() {
+    schedule();
+}
+
+/// End the running thread
+pub fn exit() -> ! {
+    cpu::without_interrupts(|| {
+        let detached = {
+            let mut threads = THREADS.lock();
+            let (joiner, detached) = {
+                let current = threads.current();
+                current.set_state(ThreadState::Exited);
+                (current.joiner(), current.is_detached())
+            };
+
+            if let Some(joiner) = joiner {
+                threads.wake(joiner);
+            }
+            detached
+        };
+
+        // Still with interrupts disabled, once preempted the exited thread never runs again
+        if detached {
+            REAPER.notify_one();
+        }
+    });
+
+    schedule();
+    unreachable!("Exited thread was scheduled again");
+}
+
+/// Wait for a thread to exit and free its stack and address space. Returns false if there is no
+/// such thread or it is detached.
+pub fn join(id: ThreadId) -> bool {
+    join_thread(id, false)
+}
+
+/// Free the thread once it exits, without anyone joining it. Returns false if there is no such
+/// thread.
+pub fn detach(id: ThreadId) -> bool {
+    cpu::without_interrupts(|| {
+        let exited = {
+            let mut threads = THREADS.lock();
+            let slot = match threads.find(id) {
+                Some(slot) => slot,
+                None => return false,
+            };
+
+            let thread = threads.threads[slot].as_mut().unwrap();
+            thread.detach();
+            thread.state() == ThreadState::Exited
+        };
+
+        if exited {
+            REAPER.notify_one();
+        }
+        true
+    })
+}
+
+fn join_thread(id: ThreadId, reaper: bool) -> bool {
+    assert!(id != current(), "Thread cannot join itself");
+
+    loop {
+        match cpu::without_interrupts(|| THREADS.lock().join(id, reaper)) {
+            Some(Join::Exited(stack, address_space)) => {
+                if let Some(stack) = stack {
+                    memory::free_stack(stack);
+                }
+                if let Some(address_space) = address_space {
+                    free_address_space(address_space);
+                }
+                return true;
+            }
+            Some(Join::Blocked) => schedule(),
+            None => return false,
+        }
+    }
+}
+
+/// Called by the timer interrupt to preempt the running thread once its time slice is used up
+pub fn tick() {
+    // Interrupted deferred work finishes before any thread switch
+    if interrupts::in_interrupt() {
+        return;
+    }
+
+    // Interrupts are disabled in the interrupt handler
+    let preempt = THREADS.lock().tick();
+    if preempt {
+        schedule();
+    }
+}
+
+/// Print every thread with its scheduling state and the CPU time it used
+pub fn print_threads() {
+    let policy = cpu::without_interrupts(|| THREADS.lock().policy.policy());
+    println!(
+        "Threads, {} scheduling, {} ms since boot:",
+        policy.name(),
+        timer::ticks_to_ms(timer::ticks())
+    );
+    println!("   id  state     priority   cpu ms   vruntime");
+
+    // Copy one thread at a time to keep the scheduler locked only briefly
+    for slot in 0..MAX_THREADS {
+        let (thread, idle) = cpu::without_interrupts(|| {
+            let threads = THREADS.lock();
+            (threads.threads[slot], threads.idle == Some(slot))
+        });
+
+        if let Some(thread) = thread {
+            println!(
+                "{:>5}  {:<9} {:>8} {:>8} {:>10}{}",
+                thread.id(),
+                thread.state().name(),
+                thread.priority(),
+                timer::ticks_to_ms(thread.cpu_ticks()),
+                thread.vruntime(),
+                if idle { "  (idle)" } else { "" }
+            );
+        }
+    }
+}
+
+// Switch to the thread picked by the scheduler. The current thread continues once it is picked
+// again, which requires it to be ready.
+fn schedule() {
+    cpu::without_interrupts(|| {
+        let switch = THREADS.lock().switch_next();
+        if let Some((old_stack_pointer, new_stack_pointer)) = switch {
+            unsafe { switch_context(old_stack_pointer, new_stack_pointer) };
+        }
+    });
+}
+
+// Called by thread_start in switch.asm as the first function of every new thread
+#[no_mangle]
+pub extern "C" fn thread_main(entry: fn()) -> ! {
+    entry();
+    exit();
+}
+
+// First function of the threads started by spawn_user. Their address space is already active,
+// the switch to the thread loaded it.
+fn user_main() {
+    let user = cpu::without_interrupts(|| THREADS.lock().current().user())
+        .expect("Not a user thread");
+    interrupts::enter_user(user.entry, user.stack_pointer);
+}
+
+// Drop an address space moved into kmalloc memory by spawn_user. Dropping it switches back to the
+// kernel page table if it is still active.
+fn free_address_space(address_space: *mut AddressSpace) {
+    unsafe {
+        ptr::drop_in_place(address_space);
+        slab::kfree(address_space as *mut u8);
+    }
+}
+
+// Frees the detached threads that exited
+fn reaper() {
+    loop {
+        REAPER.wait_unless(|| THREADS.lock().exited_detached().is_some(), None);
+
+        while let Some(id) = cpu::without_interrupts(|| THREADS.lock().exited_detached()) {
+            join_thread(id, true);
+        }
+    }
+}
+
+// Runs when no other thread is ready
+fn idle() {
+    loop {
+        // Halt until the next interrupt instead of spinning, it may have made a thread ready. The
+        // idle thread is created before interrupts are enabled, so it enables them itself.
+        unsafe { cpu::enable_interrupts_and_halt() };
+        yield_now();
+    }
+}
@@ -0,0 +1,155 @@
+use super::thread::{Thread, MAX_PRIORITY};
+
+/// Timer ticks a thread runs before it is preempted, unless the policy says otherwise
+pub const DEFAULT_TIME_SLICE: usize = 5;
+
+// Virtual runtime a thread with priority 0 gains per tick under the fair policy. Higher
+// priorities gain proportionally less, so they get a larger share of the CPU.
+const VRUNTIME_PER_TICK: usize = 1024;
+
+/// Decides which thread runs next and for how long
+pub trait Policy {
+    fn name(&self) -> &'static str;
+
+    /// Pick the next thread to run from candidates, the slots of the ready threads in round robin
+    /// order. The running thread is the last candidate if it can keep running.
+    fn pick_next(&self, threads: &mut [Option<Thread>], candidates: &[usize]) -> usize;
+
+    /// Ticks the thread may run once it is picked
+    fn time_slice(&self, _thread: &Thread) -> usize {
+        DEFAULT_TIME_SLICE
+    }
+
+    /// Account a tick the thread spent running
+    fn charge(&self, _thread: &mut Thread) {}
+
+    /// Called when the thread in slot becomes ready after being created or blocked. Runnable are
+    /// the slots of all ready and running threads.
+    fn ready(&self, _threads: &mut [Option<Thread>], _slot: usize, _runnable: &[usize]) {}
+}
+
+/// Available scheduling policies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyKind {
+    /// Every ready thread runs in turn for the same time slice
+    RoundRobin,
+    /// The thread with the highest priority runs. Waiting raises the priority of a thread
+    /// temporarily, so low priorities do not starve.
+    Priority,
+    /// The thread that received the least CPU time relative to its priority runs
+    Fair,
+}
+
+static ROUND_ROBIN: RoundRobin = RoundRobin;
+static PRIORITY: PriorityAging = PriorityAging;
+static FAIR: Fair = Fair;
+
+impl PolicyKind {
+    pub fn policy(self) -> &'static Policy {
+        match self {
+            PolicyKind::RoundRobin => &ROUND_ROBIN,
+            PolicyKind::Priority => &PRIORITY,
+            PolicyKind::Fair => &FAIR,
+        }
+    }
+}
+
+struct RoundRobin;
+
+impl Policy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round robin"
+    }
+
+    fn pick_next(&self, _threads: &mut [Option<Thread>], candidates: &[usize]) -> usize {
+        candidates[0]
+    }
+}
+
+struct PriorityAging;
+
+impl Policy for PriorityAging {
+    fn name(&self) -> &'static str {
+        "priority with aging"
+    }
+
+    fn pick_next(&self, threads: &mut [Option<Thread>], candidates: &[usize]) -> usize {
+        // The first of equal candidates wins, so threads of the same priority take turns
+        let next = {
+            let effective = |slot: usize| {
+                let thread = threads[slot].as_ref().unwrap();
+                thread.priority() + thread.age()
+            };
+
+            let mut next = candidates[0];
+            for &slot in &candidates[1..] {
+                if effective(slot) > effective(next) {
+                    next = slot;
+                }
+            }
+            next
+        };
+
+        // Every thread passed over gets a little closer to being picked
+        for &slot in candidates {
+            let thread = threads[slot].as_mut().unwrap();
+            if slot == next {
+                thread.set_age(0);
+            } else if thread.priority() + thread.age() < MAX_PRIORITY {
+                let age = thread.age();
+                thread.set_age(age + 1);
+            }
+        }
+
+        next
+    }
+
+    fn time_slice(&self, thread: &Thread) -> usize {
+        // Higher priorities also run longer, between 1 and 8 ticks
+        1 + thread.priority() * 7 / MAX_PRIORITY
+    }
+}
+
+struct Fair;
+
+impl Policy for Fair {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn pick_next(&self, threads: &mut [Option<Thread>], candidates: &[usize]) -> usize {
+        let vruntime = |slot: usize| threads[slot].as_ref().unwrap().vruntime();
+
+        let mut next = candidates[0];
+        for &slot in &candidates[1..] {
+            if vruntime(slot) < vruntime(next) {
+                next = slot;
+            }
+        }
+        next
+    }
+
+    fn charge(&self, thread: &mut Thread) {
+        let vruntime = thread.vruntime() + VRUNTIME_PER_TICK / (thread.priority() + 1);
+        thread.set_vruntime(vruntime);
+    }
+
+    fn ready(&self, threads: &mut [Option<Thread>], slot: usize, runnable: &[usize]) {
+        // A thread that was blocked for a long time would otherwise monopolize the CPU until its
+        // virtual runtime caught up, so it starts with the smallest one of the other threads
+        let mut min_vruntime = None;
+        for &i in runnable {
+            let vruntime = threads[i].as_ref().unwrap().vruntime();
+            if i != slot && min_vruntime.map_or(true, |min| vruntime < min) {
+                min_vruntime = Some(vruntime);
+            }
+        }
+
+        if let Some(min_vruntime) = min_vruntime {
+            let thread = threads[slot].as_mut().unwrap();
+            if thread.vruntime() < min_vruntime {
+                thread.set_vruntime(min_vruntime);
+            }
+        }
+    }
+}
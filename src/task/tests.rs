@@ -0,0 +1,141 @@
+// Host tests for the scheduling policies, run with cargo test. The threads are never run, the
+// tests only look at which slot a policy picks.
+
+use super::policy::{PolicyKind, DEFAULT_TIME_SLICE};
+use super::thread::{Thread, MAX_PRIORITY};
+
+// Threads with the given priorities in the slots of the same index
+fn threads(priorities: &[usize]) -> Vec<Option<Thread>> {
+    priorities
+        .iter()
+        .enumerate()
+        .map(|(id, &priority)| {
+            let mut thread = Thread::boot(id);
+            assert!(thread.set_priority(priority));
+            Some(thread)
+        })
+        .collect()
+}
+
+fn set_vruntimes(threads: &mut [Option<Thread>], vruntimes: &[usize]) {
+    for (thread, &vruntime) in threads.iter_mut().zip(vruntimes) {
+        thread.as_mut().unwrap().set_vruntime(vruntime);
+    }
+}
+
+#[test]
+fn round_robin_takes_the_next_candidate() {
+    let policy = PolicyKind::RoundRobin.policy();
+    let mut threads = threads(&[0, MAX_PRIORITY, 5]);
+    assert_eq!(policy.pick_next(&mut threads, &[2, 0, 1]), 2);
+    assert_eq!(policy.time_slice(threads[1].as_ref().unwrap()), DEFAULT_TIME_SLICE);
+}
+
+#[test]
+fn priority_picks_the_highest() {
+    let policy = PolicyKind::Priority.policy();
+    let mut threads = threads(&[3, 20, 10]);
+    assert_eq!(policy.pick_next(&mut threads, &[0, 1, 2]), 1);
+}
+
+#[test]
+fn aging_eventually_picks_a_low_priority_thread() {
+    let policy = PolicyKind::Priority.policy();
+    let mut threads = threads(&[0, MAX_PRIORITY - 1, MAX_PRIORITY]);
+
+    // Candidates in round robin order after the running thread, which comes last
+    let mut current = 2;
+    let mut picked = [0; 3];
+    for _ in 0..(4 * (MAX_PRIORITY + 1)) {
+        let candidates: Vec<usize> = (1..4).map(|i| (current + i) % 3).collect();
+        current = policy.pick_next(&mut threads, &candidates);
+        picked[current] += 1;
+    }
+
+    assert!(picked.iter().all(|&count| count > 0), "Picked {:?}", picked);
+    // The highest priority still runs most of the time
+    assert!(picked[2] > picked[0]);
+}
+
+#[test]
+fn aging_resets_once_picked() {
+    let policy = PolicyKind::Priority.policy();
+    let mut threads = threads(&[0, 10]);
+
+    assert_eq!(policy.pick_next(&mut threads, &[0, 1]), 1);
+    assert_eq!(threads[0].as_ref().unwrap().age(), 1);
+    assert_eq!(threads[1].as_ref().unwrap().age(), 0);
+
+    threads[0].as_mut().unwrap().set_age(10);
+    assert_eq!(policy.pick_next(&mut threads, &[0, 1]), 0);
+    assert_eq!(threads[0].as_ref().unwrap().age(), 0);
+    assert_eq!(threads[1].as_ref().unwrap().age(), 1);
+}
+
+#[test]
+fn time_slice_grows_with_priority() {
+    let policy = PolicyKind::Priority.policy();
+    let mut thread = Thread::boot(0);
+    let mut previous = 0;
+
+    for priority in 0..(MAX_PRIORITY + 1) {
+        assert!(thread.set_priority(priority));
+        let slice = policy.time_slice(&thread);
+        assert!(slice >= 1 && slice <= 8, "Slice {} for priority {}", slice, priority);
+        assert!(slice >= previous);
+        previous = slice;
+    }
+
+    thread.set_priority(0);
+    assert_eq!(policy.time_slice(&thread), 1);
+    thread.set_priority(MAX_PRIORITY);
+    assert_eq!(policy.time_slice(&thread), 8);
+}
+
+#[test]
+fn fair_picks_the_smallest_vruntime() {
+    let policy = PolicyKind::Fair.policy();
+    let mut threads = threads(&[16, 16, 16]);
+    set_vruntimes(&mut threads, &[300, 100, 200]);
+    assert_eq!(policy.pick_next(&mut threads, &[0, 1, 2]), 1);
+
+    // The first of equal vruntimes wins
+    set_vruntimes(&mut threads, &[100, 100, 200]);
+    assert_eq!(policy.pick_next(&mut threads, &[2, 1, 0]), 1);
+}
+
+#[test]
+fn fair_charges_less_for_higher_priorities() {
+    let policy = PolicyKind::Fair.policy();
+    let mut threads = threads(&[0, MAX_PRIORITY]);
+    for _ in 0..10 {
+        for thread in threads.iter_mut() {
+            policy.charge(thread.as_mut().unwrap());
+        }
+    }
+
+    let vruntime = |slot: usize| threads[slot].as_ref().unwrap().vruntime();
+    assert!(vruntime(1) > 0);
+    assert!(vruntime(0) > vruntime(1));
+}
+
+#[test]
+fn fair_raises_a_returning_thread_to_the_runnable_minimum() {
+    let policy = PolicyKind::Fair.policy();
+    let mut threads = threads(&[16, 16, 16, 16]);
+    set_vruntimes(&mut threads, &[300, 100, 200, 0]);
+
+    // Slot 3 was blocked while the others ran
+    policy.ready(&mut threads, 3, &[0, 1, 2, 3]);
+    assert_eq!(threads[3].as_ref().unwrap().vruntime(), 100);
+
+    // A thread that is ahead keeps its vruntime
+    threads[3].as_mut().unwrap().set_vruntime(500);
+    policy.ready(&mut threads, 3, &[0, 1, 2, 3]);
+    assert_eq!(threads[3].as_ref().unwrap().vruntime(), 500);
+
+    // Nothing to compare with
+    threads[3].as_mut().unwrap().set_vruntime(0);
+    policy.ready(&mut threads, 3, &[3]);
+    assert_eq!(threads[3].as_ref().unwrap().vruntime(), 0);
+}
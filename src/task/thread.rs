@@ -0,0 +1,261 @@
+use core::mem;
+use memory::{AddressSpace, Stack, VirtualAddress};
+
+pub type ThreadId = usize;
+
+/// Highest scheduling priority, 0 is the lowest
+pub const MAX_PRIORITY: usize = 31;
+/// Priority of new threads
+pub const DEFAULT_PRIORITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    // Waiting to be picked by the scheduler
+    Ready,
+    // Executing on the CPU
+    Running,
+    // Waiting for something, the scheduler skips the thread until it is made ready again
+    Blocked,
+    // Finished, the thread is kept until another thread joins it
+    Exited,
+}
+
+impl ThreadState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Exited => "exited",
+        }
+    }
+}
+
+// Registers pushed by switch_context below the return address, see switch.asm
+const SAVED_REGISTERS: usize = 7;
+
+// RFLAGS bits of a new thread. Bit 1 is reserved and always set.
+const RESERVED_FLAG: usize = 1 << 1;
+const INTERRUPT_FLAG: usize = 1 << 9;
+
+extern "C" {
+    fn thread_start();
+}
+
+// What a thread running a user program enters user mode with
+#[derive(Debug, Clone, Copy)]
+pub struct UserContext {
+    // Owned by the thread, in memory from kmalloc
+    pub address_space: *mut AddressSpace,
+    pub entry: VirtualAddress,
+    pub stack_pointer: VirtualAddress,
+}
+
+// Thread control block
+#[derive(Debug, Clone, Copy)]
+pub struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    // Saved by switch_context while the thread is not running
+    stack_pointer: VirtualAddress,
+    // The stack from alloc_stack, None for the boot thread which runs on the boot stack
+    stack: Option<(VirtualAddress, VirtualAddress)>,
+    // Thread blocked in join until this one exits
+    joiner: Option<ThreadId>,
+    // Freed by the reaper thread once it exits instead of being joined
+    detached: bool,
+    priority: usize,
+    // Timer ticks the thread was running for
+    cpu_ticks: usize,
+    // Ticks left until the thread is preempted
+    time_slice: usize,
+    // Used by the scheduling policies, see policy.rs
+    age: usize,
+    vruntime: usize,
+    // None for kernel threads
+    user: Option<UserContext>,
+}
+
+impl Thread {
+    /// Thread that starts executing entry on the given stack once it is switched to, with
+    /// interrupts enabled or not
+    pub fn new(id: ThreadId, stack: Stack, entry: fn(), interrupts: bool) -> Thread {
+        let flags = if interrupts {
+            RESERVED_FLAG | INTERRUPT_FLAG
+        } else {
+            RESERVED_FLAG
+        };
+
+        // Initial frame popped by switch_context: the flags, r15, r14, r13, r12, rbx with the entry
+        // for thread_start, a null rbp and thread_start as the return address. The stack top is
+        // page aligned, so rsp is 16 byte aligned when thread_start calls thread_main as the ABI
+        // requires.
+        let frame: [usize; SAVED_REGISTERS + 1] = [
+            flags,
+            0,
+            0,
+            0,
+            0,
+            entry as usize,
+            0,
+            thread_start as usize,
+        ];
+
+        let stack_pointer = stack.top() - mem::size_of_val(&frame);
+        unsafe { *(stack_pointer as *mut [usize; SAVED_REGISTERS + 1]) = frame };
+
+        Thread {
+            id,
+            state: ThreadState::Ready,
+            stack_pointer,
+            stack: Some((stack.top(), stack.bottom())),
+            joiner: None,
+            detached: false,
+            priority: DEFAULT_PRIORITY,
+            cpu_ticks: 0,
+            time_slice: 0,
+            age: 0,
+            vruntime: 0,
+            user: None,
+        }
+    }
+
+    /// The thread that runs rust_main on the boot stack
+    pub fn boot(id: ThreadId) -> Thread {
+        Thread {
+            id,
+            state: ThreadState::Running,
+            stack_pointer: 0,
+            stack: None,
+            joiner: None,
+            detached: false,
+            priority: DEFAULT_PRIORITY,
+            cpu_ticks: 0,
+            time_slice: 0,
+            age: 0,
+            vruntime: 0,
+            user: None,
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: ThreadState) {
+        self.state = state;
+    }
+
+    /// Whether the scheduler may pick the thread
+    pub fn is_runnable(&self) -> bool {
+        self.state == ThreadState::Ready || self.state == ThreadState::Running
+    }
+
+    // Where switch_context stores the stack pointer when switching away from the thread
+    pub fn stack_pointer_mut(&mut self) -> *mut VirtualAddress {
+        &mut self.stack_pointer
+    }
+
+    pub fn stack_pointer(&self) -> VirtualAddress {
+        self.stack_pointer
+    }
+
+    /// Top of the stack from alloc_stack, None for the boot thread
+    pub fn stack_top(&self) -> Option<VirtualAddress> {
+        self.stack.map(|(top, _)| top)
+    }
+
+    /// Give up the stack so that it can be freed. Only valid once the thread has exited.
+    pub fn take_stack(&mut self) -> Option<Stack> {
+        assert!(self.state == ThreadState::Exited, "Thread is still running");
+        self.stack
+            .take()
+            .map(|(top, bottom)| Stack::new(top, bottom))
+    }
+
+    pub fn user(&self) -> Option<UserContext> {
+        self.user
+    }
+
+    /// Make the thread run a user program, see task::spawn_user
+    pub fn set_user(&mut self, user: UserContext) {
+        self.user = Some(user);
+    }
+
+    /// Give up the address space so that it can be freed. Only valid once the thread has exited.
+    pub fn take_address_space(&mut self) -> Option<*mut AddressSpace> {
+        assert!(self.state == ThreadState::Exited, "Thread is still running");
+        self.user.take().map(|user| user.address_space)
+    }
+
+    pub fn joiner(&self) -> Option<ThreadId> {
+        self.joiner
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Let the reaper thread free the thread once it exits, see task::detach
+    pub fn detach(&mut self) {
+        self.detached = true;
+    }
+
+    pub fn set_joiner(&mut self, joiner: ThreadId) {
+        assert!(
+            self.joiner.is_none() || self.joiner == Some(joiner),
+            "Thread is already being joined"
+        );
+        self.joiner = Some(joiner);
+    }
+
+    pub fn priority(&self) -> usize {
+        self.priority
+    }
+
+    // Returns false if the priority is above MAX_PRIORITY
+    pub fn set_priority(&mut self, priority: usize) -> bool {
+        if priority > MAX_PRIORITY {
+            return false;
+        }
+
+        self.priority = priority;
+        true
+    }
+
+    pub fn cpu_ticks(&self) -> usize {
+        self.cpu_ticks
+    }
+
+    /// Start a new time slice of the given number of ticks
+    pub fn set_time_slice(&mut self, ticks: usize) {
+        self.time_slice = ticks;
+    }
+
+    /// Account a tick the thread was running for. Returns true if its time slice is used up.
+    pub fn tick(&mut self) -> bool {
+        self.cpu_ticks += 1;
+        self.time_slice = self.time_slice.saturating_sub(1);
+        self.time_slice == 0
+    }
+
+    pub fn age(&self) -> usize {
+        self.age
+    }
+
+    pub fn set_age(&mut self, age: usize) {
+        self.age = age;
+    }
+
+    pub fn vruntime(&self) -> usize {
+        self.vruntime
+    }
+
+    pub fn set_vruntime(&mut self, vruntime: usize) {
+        self.vruntime = vruntime;
+    }
+}
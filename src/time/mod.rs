@@ -0,0 +1,86 @@
+// Kernel timers on top of the timer interrupt. Callbacks run in interrupt context, so they must not
+// sleep and should only do little work, like waking a thread or a task.
+
+mod wheel;
+#[cfg(test)]
+mod tests;
+
+pub use self::wheel::{TimerId, THREAD_TIMERS};
+use self::wheel::Wheel;
+use interrupts::timer;
+use sync::IrqSafeMutex;
+use task;
+
+static WHEEL: IrqSafeMutex<Wheel> = IrqSafeMutex::new(Wheel::new());
+
+/// Call callback with data once after delay milliseconds. Returns None if there is no timer left.
+pub fn add_timer(delay: usize, callback: fn(usize), data: usize) -> Option<TimerId> {
+    add_timer_at(deadline(delay), callback, data)
+}
+
+/// Call callback with data once the timer reaches the deadline tick
+pub fn add_timer_at(deadline: usize, callback: fn(usize), data: usize) -> Option<TimerId> {
+    WHEEL.lock().add(deadline, 0, callback, data)
+}
+
+/// Call callback with data once the timer reaches the deadline tick, using the timer reserved
+/// for the thread slot. The previous timer of the slot has to be cancelled or have fired.
+pub fn add_thread_timer_at(
+    slot: usize,
+    deadline: usize,
+    callback: fn(usize),
+    data: usize,
+) -> TimerId {
+    WHEEL.lock().add_reserved(slot, deadline, callback, data)
+}
+
+/// Call callback with data every period milliseconds, at least every tick, until it is cancelled
+pub fn add_periodic(period: usize, callback: fn(usize), data: usize) -> Option<TimerId> {
+    let ticks = timer::ms_to_ticks(period);
+    let ticks = if ticks == 0 { 1 } else { ticks };
+    WHEEL.lock().add(timer::ticks() + ticks, ticks, callback, data)
+}
+
+/// Stop a timer. Returns false if it already fired or was cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    WHEEL.lock().cancel(id)
+}
+
+/// Tick at which a wait of the given number of milliseconds from now ends
+pub fn deadline(ms: usize) -> usize {
+    timer::ticks() + timer::ms_to_ticks(ms)
+}
+
+/// Park the current thread for at least the given number of milliseconds
+pub fn sleep(ms: usize) {
+    sleep_until(deadline(ms));
+}
+
+/// Park the current thread until the timer reaches the deadline tick
+pub fn sleep_until(deadline: usize) {
+    // Something else may wake the thread early
+    while timer::ticks() < deadline {
+        task::block(Some(deadline));
+    }
+}
+
+/// Earliest tick at which a timer fires. For tickless operation, the timer can be programmed to
+/// interrupt at that tick instead of periodically, as run_expired catches up on skipped ticks.
+pub fn next_deadline() -> Option<usize> {
+    WHEEL.lock().next_deadline()
+}
+
+/// Run the callbacks of all timers that expired by the now tick. Called by the timer interrupt.
+pub fn run_expired(now: usize) {
+    loop {
+        // The wheel is not locked while a callback runs, so callbacks can add and cancel timers
+        let expired = WHEEL.lock().next_expired(now);
+        match expired {
+            Some(expired) => {
+                (expired.callback)(expired.data);
+                WHEEL.lock().finish(expired.id);
+            }
+            None => return,
+        }
+    }
+}
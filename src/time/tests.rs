@@ -0,0 +1,159 @@
+// Host tests for the timer wheel, run with cargo test. The callbacks are not called, the tests
+// look at the data of the timers that expire instead.
+
+use super::wheel::{Wheel, MAX_TIMERS, THREAD_TIMERS};
+
+fn nothing(_: usize) {}
+
+// Advance the wheel one tick at a time up to the last tick and return the ticks at which the timer
+// with the data fired
+fn fired_at(wheel: &mut Wheel, data: usize, last: usize) -> Vec<usize> {
+    let mut ticks = Vec::new();
+    for now in 0..(last + 1) {
+        while let Some(expired) = wheel.next_expired(now) {
+            if expired.data == data {
+                ticks.push(now);
+            }
+            wheel.finish(expired.id);
+        }
+    }
+    ticks
+}
+
+#[test]
+fn one_shot() {
+    let mut wheel = Wheel::new();
+    wheel.add(5, 0, nothing, 1).unwrap();
+    assert_eq!(fired_at(&mut wheel, 1, 100), vec![5]);
+}
+
+#[test]
+fn timers_on_every_level() {
+    let mut wheel = Wheel::new();
+    let deadlines = [1, 63, 64, 65, 4095, 4096, 5000, 262_143, 262_144, 300_000];
+    for (data, &deadline) in deadlines.iter().enumerate() {
+        wheel.add(deadline, 0, nothing, data).unwrap();
+    }
+
+    let mut fired = Vec::new();
+    for now in 0..300_001 {
+        while let Some(expired) = wheel.next_expired(now) {
+            assert_eq!(deadlines[expired.data], now);
+            fired.push(expired.data);
+            wheel.finish(expired.id);
+        }
+    }
+    assert_eq!(fired, (0..deadlines.len()).collect::<Vec<_>>());
+}
+
+#[test]
+fn beyond_the_last_level() {
+    let mut wheel = Wheel::new();
+    let deadline = (1 << 24) + 100;
+    wheel.add(deadline, 0, nothing, 1).unwrap();
+
+    assert!(wheel.next_expired(deadline - 1).is_none());
+    let expired = wheel.next_expired(deadline).unwrap();
+    assert_eq!(expired.data, 1);
+}
+
+#[test]
+fn periodic() {
+    let mut wheel = Wheel::new();
+    wheel.add(10, 10, nothing, 1).unwrap();
+    assert_eq!(fired_at(&mut wheel, 1, 35), vec![10, 20, 30]);
+}
+
+#[test]
+fn cancel() {
+    let mut wheel = Wheel::new();
+    let id = wheel.add(5, 0, nothing, 1).unwrap();
+    wheel.add(6, 0, nothing, 2).unwrap();
+
+    assert!(wheel.cancel(id));
+    assert!(!wheel.cancel(id));
+    assert!(fired_at(&mut wheel, 1, 10).is_empty());
+}
+
+#[test]
+fn cancel_after_firing() {
+    let mut wheel = Wheel::new();
+    let id = wheel.add(5, 0, nothing, 1).unwrap();
+    assert_eq!(fired_at(&mut wheel, 1, 10), vec![5]);
+
+    // The entry is reused, the old id must not cancel the new timer
+    let new_id = wheel.add(20, 0, nothing, 2).unwrap();
+    assert!(!wheel.cancel(id));
+    assert!(wheel.cancel(new_id));
+}
+
+#[test]
+fn periodic_cancelled_by_its_callback() {
+    let mut wheel = Wheel::new();
+    wheel.add(10, 10, nothing, 1).unwrap();
+
+    let expired = wheel.next_expired(10).unwrap();
+    assert!(wheel.cancel(expired.id));
+    wheel.finish(expired.id);
+    assert!(fired_at(&mut wheel, 1, 50).is_empty());
+}
+
+#[test]
+fn skipped_ticks() {
+    let mut wheel = Wheel::new();
+    wheel.add(3, 0, nothing, 1).unwrap();
+    wheel.add(70, 0, nothing, 2).unwrap();
+    wheel.add(200, 0, nothing, 3).unwrap();
+
+    let mut fired = Vec::new();
+    while let Some(expired) = wheel.next_expired(100) {
+        fired.push(expired.data);
+        wheel.finish(expired.id);
+    }
+    assert_eq!(fired, vec![1, 2]);
+    assert_eq!(wheel.next_deadline(), Some(200));
+}
+
+#[test]
+fn due_timers_fire_on_the_next_tick() {
+    let mut wheel = Wheel::new();
+    assert!(wheel.next_expired(50).is_none());
+
+    wheel.add(10, 0, nothing, 1).unwrap();
+    assert_eq!(wheel.next_deadline(), Some(51));
+    assert!(wheel.next_expired(50).is_none());
+    assert_eq!(wheel.next_expired(51).unwrap().data, 1);
+}
+
+#[test]
+fn out_of_timers() {
+    let mut wheel = Wheel::new();
+    for i in 0..MAX_TIMERS {
+        wheel.add(i, 0, nothing, i).unwrap();
+    }
+    assert!(wheel.add(0, 0, nothing, 0).is_none());
+}
+
+#[test]
+fn reserved_timers_are_left_when_the_others_run_out() {
+    let mut wheel = Wheel::new();
+    for i in 0..MAX_TIMERS {
+        wheel.add(i, 0, nothing, i).unwrap();
+    }
+
+    let id = wheel.add_reserved(THREAD_TIMERS - 1, 10, nothing, 1000);
+    assert!(wheel.cancel(id));
+    wheel.add_reserved(THREAD_TIMERS - 1, 10, nothing, 1000);
+    assert_eq!(fired_at(&mut wheel, 1000, 20), vec![10]);
+
+    // Free again once it finished
+    wheel.add_reserved(THREAD_TIMERS - 1, 30, nothing, 1000);
+}
+
+#[test]
+#[should_panic(expected = "Reserved timer 0 is still in use")]
+fn reserved_timer_in_use() {
+    let mut wheel = Wheel::new();
+    wheel.add_reserved(0, 10, nothing, 0);
+    wheel.add_reserved(0, 20, nothing, 0);
+}
@@ -0,0 +1,347 @@
+use core::cmp;
+
+// Levels of the wheel. Each level has SLOTS slots covering SLOTS times the span of a slot of the
+// level below, so timers up to SLOTS^LEVELS ticks ahead are placed exactly.
+const LEVELS: usize = 4;
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: usize = SLOTS - 1;
+
+// Furthest a timer can be placed ahead of the next tick. Timers further out are parked in the last
+// level and placed again whenever it is cascaded.
+const MAX_DELTA: usize = 1 << (SLOT_BITS * LEVELS);
+
+/// Maximum number of timers added with add at once
+pub const MAX_TIMERS: usize = 256;
+
+/// Timers that are only added with add_reserved, one for every thread slot. Blocking with a
+/// deadline can't run out of timers that way.
+pub const THREAD_TIMERS: usize = 64;
+
+// A list for every slot of every level, followed by the list of timers that expired but were not
+// run yet
+const LISTS: usize = LEVELS * SLOTS + 1;
+const EXPIRED: usize = LEVELS * SLOTS;
+
+// End of a list
+const NONE: usize = !0;
+
+/// Handle of a timer. It does not match the timer anymore once it was cancelled or has fired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: usize,
+    generation: usize,
+}
+
+/// Timer that expired, to be run by the caller
+#[derive(Clone, Copy)]
+pub struct Expired {
+    pub id: TimerId,
+    pub callback: fn(usize),
+    pub data: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Free,
+    // In the list of a slot or in the expired list
+    Pending,
+    // Taken out of the expired list, its callback is running
+    Running,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    state: State,
+    // Tick at which the timer fires
+    expires: usize,
+    // Ticks between firings of a periodic timer, 0 for a one-shot timer
+    period: usize,
+    callback: Option<fn(usize)>,
+    data: usize,
+    // Incremented whenever the entry is freed, so old ids do not match a new timer
+    generation: usize,
+    list: usize,
+    previous: usize,
+    next: usize,
+}
+
+const FREE_ENTRY: Entry = Entry {
+    state: State::Free,
+    expires: 0,
+    period: 0,
+    callback: None,
+    data: 0,
+    generation: 0,
+    list: NONE,
+    previous: NONE,
+    next: NONE,
+};
+
+/// Hierarchical timing wheel. Adding and cancelling a timer takes constant time, and every tick
+/// only looks at the timers of one slot, apart from moving the timers of a higher level slot down
+/// once the level below wrapped around.
+pub struct Wheel {
+    // The reserved timers follow the others
+    entries: [Entry; MAX_TIMERS + THREAD_TIMERS],
+    heads: [usize; LISTS],
+    // The tick processed next
+    next_tick: usize,
+}
+
+impl Wheel {
+    pub const fn new() -> Wheel {
+        Wheel {
+            entries: [FREE_ENTRY; MAX_TIMERS + THREAD_TIMERS],
+            heads: [NONE; LISTS],
+            next_tick: 0,
+        }
+    }
+
+    /// Add a timer that calls callback with data at the expires tick and then every period ticks
+    /// unless the period is 0. Timers that are already due fire on the next tick. Returns None if
+    /// there is no timer left.
+    pub fn add(
+        &mut self,
+        expires: usize,
+        period: usize,
+        callback: fn(usize),
+        data: usize,
+    ) -> Option<TimerId> {
+        let index = match self.entries[..MAX_TIMERS]
+            .iter()
+            .position(|entry| entry.state == State::Free)
+        {
+            Some(index) => index,
+            None => return None,
+        };
+
+        Some(self.arm(index, expires, period, callback, data))
+    }
+
+    /// Add a one-shot timer like add, but use the reserved timer with the given number. It must
+    /// have been cancelled or have finished since it was added the last time.
+    pub fn add_reserved(
+        &mut self,
+        reserved: usize,
+        expires: usize,
+        callback: fn(usize),
+        data: usize,
+    ) -> TimerId {
+        assert!(reserved < THREAD_TIMERS, "There is no reserved timer {}", reserved);
+        let index = MAX_TIMERS + reserved;
+        assert!(
+            self.entries[index].state == State::Free,
+            "Reserved timer {} is still in use",
+            reserved
+        );
+
+        self.arm(index, expires, 0, callback, data)
+    }
+
+    /// Stop a timer. Returns false if it already fired or was cancelled. A periodic timer can
+    /// cancel itself from its callback.
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        let state = self.entries[id.index].state;
+        if self.entries[id.index].generation != id.generation || state == State::Free {
+            return false;
+        }
+
+        if state == State::Pending {
+            self.unlink(id.index);
+        }
+        self.free(id.index);
+        true
+    }
+
+    /// Take the next timer that expired by the now tick. Returns None once every tick up to now
+    /// was processed, which can be several if ticks were skipped. The caller runs the callback and
+    /// then calls finish.
+    pub fn next_expired(&mut self, now: usize) -> Option<Expired> {
+        loop {
+            let index = self.heads[EXPIRED];
+            if index != NONE {
+                self.unlink(index);
+                let entry = &mut self.entries[index];
+                entry.state = State::Running;
+                return Some(Expired {
+                    id: TimerId {
+                        index,
+                        generation: entry.generation,
+                    },
+                    callback: entry.callback.unwrap(),
+                    data: entry.data,
+                });
+            }
+
+            if self.next_tick > now {
+                return None;
+            }
+
+            let tick = self.next_tick;
+            self.process_tick(tick);
+            self.next_tick += 1;
+        }
+    }
+
+    /// Rearm a periodic timer or free a one-shot timer after its callback ran
+    pub fn finish(&mut self, id: TimerId) {
+        let entry = self.entries[id.index];
+        if entry.generation != id.generation || entry.state != State::Running {
+            // Cancelled by its callback
+            return;
+        }
+
+        if entry.period == 0 {
+            self.free(id.index);
+        } else {
+            self.entries[id.index].state = State::Pending;
+            self.entries[id.index].expires = entry.expires + entry.period;
+            self.insert(id.index);
+        }
+    }
+
+    /// Earliest tick at which a timer fires
+    pub fn next_deadline(&self) -> Option<usize> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.state == State::Pending)
+            .map(|entry| cmp::max(entry.expires, self.next_tick))
+            .min()
+    }
+
+    // Set up the free entry as a pending timer
+    fn arm(
+        &mut self,
+        index: usize,
+        expires: usize,
+        period: usize,
+        callback: fn(usize),
+        data: usize,
+    ) -> TimerId {
+        {
+            let entry = &mut self.entries[index];
+            entry.state = State::Pending;
+            entry.expires = expires;
+            entry.period = period;
+            entry.callback = Some(callback);
+            entry.data = data;
+        }
+        self.insert(index);
+
+        TimerId {
+            index,
+            generation: self.entries[index].generation,
+        }
+    }
+
+    // Move the timers of the level 0 slot of the tick to the expired list, after refilling the
+    // lower levels from the next higher ones if they wrapped around
+    fn process_tick(&mut self, tick: usize) {
+        if tick & SLOT_MASK == 0 {
+            for level in 1..LEVELS {
+                let slot = (tick >> (SLOT_BITS * level)) & SLOT_MASK;
+                self.cascade(level * SLOTS + slot);
+                if slot != 0 {
+                    break;
+                }
+            }
+        }
+
+        let mut index = self.take_list(tick & SLOT_MASK);
+        while index != NONE {
+            let next = self.entries[index].next;
+            if self.entries[index].expires <= tick {
+                self.push(EXPIRED, index);
+            } else {
+                // Placed for a later rotation of the wheel
+                self.insert(index);
+            }
+            index = next;
+        }
+    }
+
+    // Place every timer of a list again relative to the next tick
+    fn cascade(&mut self, list: usize) {
+        let mut index = self.take_list(list);
+        while index != NONE {
+            let next = self.entries[index].next;
+            self.insert(index);
+            index = next;
+        }
+    }
+
+    fn insert(&mut self, index: usize) {
+        let list = self.list_for(self.entries[index].expires);
+        self.push(list, index);
+    }
+
+    // The slot a timer expiring at the tick belongs to
+    fn list_for(&self, expires: usize) -> usize {
+        let expires = cmp::max(expires, self.next_tick);
+        let delta = expires - self.next_tick;
+
+        for level in 0..LEVELS {
+            if delta < 1 << (SLOT_BITS * (level + 1)) {
+                return level * SLOTS + ((expires >> (SLOT_BITS * level)) & SLOT_MASK);
+            }
+        }
+
+        let expires = self.next_tick + MAX_DELTA - 1;
+        let level = LEVELS - 1;
+        level * SLOTS + ((expires >> (SLOT_BITS * level)) & SLOT_MASK)
+    }
+
+    // Timers in a slot expire in any order, and the expired list is only refilled once it is
+    // empty, so the lists need no particular order
+    fn push(&mut self, list: usize, index: usize) {
+        let next = self.heads[list];
+        {
+            let entry = &mut self.entries[index];
+            entry.list = list;
+            entry.previous = NONE;
+            entry.next = next;
+        }
+
+        self.heads[list] = index;
+        if next != NONE {
+            self.entries[next].previous = index;
+        }
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let Entry {
+            list,
+            previous,
+            next,
+            ..
+        } = self.entries[index];
+
+        if previous == NONE {
+            self.heads[list] = next;
+        } else {
+            self.entries[previous].next = next;
+        }
+        if next != NONE {
+            self.entries[next].previous = previous;
+        }
+
+        let entry = &mut self.entries[index];
+        entry.list = NONE;
+        entry.previous = NONE;
+        entry.next = NONE;
+    }
+
+    // Detach a whole list and return its first entry. The entries keep their links to each other.
+    fn take_list(&mut self, list: usize) -> usize {
+        let head = self.heads[list];
+        self.heads[list] = NONE;
+        head
+    }
+
+    fn free(&mut self, index: usize) {
+        let generation = self.entries[index].generation;
+        self.entries[index] = FREE_ENTRY;
+        self.entries[index].generation = generation.wrapping_add(1);
+    }
+}
@@ -1,7 +1,7 @@
 use core::ptr::Unique;
 use core::fmt;
 use volatile::Volatile;
-use spin::Mutex;
+use sync::IrqSafeMutex;
 
 use memory::map::VGA_BUFFER_VMA;
 
@@ -139,7 +139,8 @@ impl fmt::Write for Writer {
     }
 }
 
-pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
+// Interrupt and exception handlers print as well, so the lock keeps them out while it is held
+pub static WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::LightGreen, Color::Black),
     buffer: unsafe { Unique::new_unchecked(VGA_BUFFER_VMA as *mut _) },
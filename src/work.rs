@@ -0,0 +1,235 @@
+// Deferred work. Interrupt handlers queue work items instead of doing everything themselves. High
+// priority items run as soon as the outermost handler returns, with interrupts enabled, and the
+// rest on a worker thread. Items must not sleep in either case.
+
+use interrupts::{self, timer};
+use sync::{IrqSafeMutex, WaitQueue};
+use task::{self, ThreadId, MAX_PRIORITY};
+
+// Items of each priority that can be queued at once
+const QUEUE_SIZE: usize = 64;
+
+// High priority items run on return from an interrupt, at most this many at once so that a flood
+// of interrupts cannot starve the threads. The worker thread runs the rest.
+const INTERRUPT_BUDGET: usize = 8;
+
+const PRIORITIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Runs when the interrupt returns
+    High = 0,
+    /// Runs on the worker thread
+    Normal = 1,
+    /// Runs on the worker thread once no normal priority item is left
+    Low = 2,
+}
+
+const ALL_PRIORITIES: [Priority; PRIORITIES] = [Priority::High, Priority::Normal, Priority::Low];
+
+impl Priority {
+    fn name(&self) -> &'static str {
+        match *self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub queued: usize,
+    // Not queued because the queue was full
+    pub dropped: usize,
+    pub run_on_interrupt_return: usize,
+    pub run_by_worker: usize,
+    pub max_pending: usize,
+    // Timer ticks from queueing an item until it ran
+    pub total_latency: usize,
+    pub max_latency: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Item {
+    work: fn(usize),
+    data: usize,
+    queued_at: usize,
+}
+
+struct Queue {
+    items: [Option<Item>; QUEUE_SIZE],
+    start: usize,
+    len: usize,
+    stats: Stats,
+}
+
+impl Queue {
+    const fn new() -> Queue {
+        Queue {
+            items: [None; QUEUE_SIZE],
+            start: 0,
+            len: 0,
+            stats: Stats {
+                queued: 0,
+                dropped: 0,
+                run_on_interrupt_return: 0,
+                run_by_worker: 0,
+                max_pending: 0,
+                total_latency: 0,
+                max_latency: 0,
+            },
+        }
+    }
+
+    fn push(&mut self, item: Item) -> bool {
+        if self.len == QUEUE_SIZE {
+            self.stats.dropped += 1;
+            return false;
+        }
+
+        self.items[(self.start + self.len) % QUEUE_SIZE] = Some(item);
+        self.len += 1;
+        self.stats.queued += 1;
+        if self.len > self.stats.max_pending {
+            self.stats.max_pending = self.len;
+        }
+        true
+    }
+
+    fn pop(&mut self) -> Option<Item> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let item = self.items[self.start].take();
+        self.start = (self.start + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        item
+    }
+}
+
+static QUEUES: IrqSafeMutex<[Queue; PRIORITIES]> =
+    IrqSafeMutex::new([Queue::new(), Queue::new(), Queue::new()]);
+
+// The worker thread sleeps here while there is nothing to do
+static WORKER: WaitQueue = WaitQueue::new();
+
+/// Start the worker thread
+pub fn init() -> ThreadId {
+    let id = task::spawn(worker).expect("Could not start the work queue thread");
+    // Deferred work is still part of handling interrupts, so it goes before other threads
+    task::set_priority(id, MAX_PRIORITY);
+    id
+}
+
+/// Queue work to be called with data later. Can be called from interrupt handlers. Returns false
+/// if the queue of the priority is full.
+pub fn queue(work: fn(usize), data: usize, priority: Priority) -> bool {
+    let item = Item {
+        work,
+        data,
+        queued_at: timer::ticks(),
+    };
+
+    // High priority items queued by a handler run once it returns, any others need the worker
+    let queued = QUEUES.lock()[priority as usize].push(item);
+    if queued && (priority != Priority::High || !interrupts::in_interrupt()) {
+        WORKER.notify_one();
+    }
+    queued
+}
+
+/// Run high priority items. Called with interrupts enabled once the outermost interrupt handler
+/// is done. Leaves the items beyond the budget to the worker thread.
+pub fn run_on_interrupt_return() {
+    for _ in 0..INTERRUPT_BUDGET {
+        if !run_one(&[Priority::High], true) {
+            return;
+        }
+    }
+
+    if pending(&[Priority::High]) {
+        WORKER.notify_one();
+    }
+}
+
+pub fn stats(priority: Priority) -> Stats {
+    QUEUES.lock()[priority as usize].stats
+}
+
+pub fn print_stats() {
+    println!("priority  queued  dropped  irq-return  worker  max-pending  avg/max latency ms");
+    for &priority in &ALL_PRIORITIES {
+        let stats = stats(priority);
+        let run = stats.run_on_interrupt_return + stats.run_by_worker;
+        let average = if run == 0 {
+            0
+        } else {
+            stats.total_latency / run
+        };
+
+        println!(
+            "{:<8} {:>7} {:>8} {:>11} {:>7} {:>12}  {}/{}",
+            priority.name(),
+            stats.queued,
+            stats.dropped,
+            stats.run_on_interrupt_return,
+            stats.run_by_worker,
+            stats.max_pending,
+            timer::ticks_to_ms(average),
+            timer::ticks_to_ms(stats.max_latency)
+        );
+    }
+}
+
+fn worker() {
+    loop {
+        while run_one(&ALL_PRIORITIES, false) {}
+        WORKER.wait_unless(|| pending(&ALL_PRIORITIES), None);
+    }
+}
+
+// Run the oldest item of the first of the priorities that has one. Returns false if there was
+// none.
+fn run_one(priorities: &[Priority], interrupt_return: bool) -> bool {
+    let item = {
+        let mut queues = QUEUES.lock();
+        let mut found = None;
+        for &priority in priorities {
+            let queue = &mut queues[priority as usize];
+            if let Some(item) = queue.pop() {
+                let latency = timer::ticks() - item.queued_at;
+                queue.stats.total_latency += latency;
+                if latency > queue.stats.max_latency {
+                    queue.stats.max_latency = latency;
+                }
+                if interrupt_return {
+                    queue.stats.run_on_interrupt_return += 1;
+                } else {
+                    queue.stats.run_by_worker += 1;
+                }
+
+                found = Some(item);
+                break;
+            }
+        }
+        found
+    };
+
+    match item {
+        // Called without the lock, so the item can queue more work
+        Some(item) => {
+            (item.work)(item.data);
+            true
+        }
+        None => false,
+    }
+}
+
+fn pending(priorities: &[Priority]) -> bool {
+    let queues = QUEUES.lock();
+    priorities
+        .iter()
+        .any(|&priority| queues[priority as usize].len > 0)
+}
@@ -0,0 +1,25 @@
+; Prints a greeting through the write system call and exits
+
+global _start
+
+SYS_EXIT equ 0
+SYS_WRITE equ 1
+STDOUT equ 1
+
+section .text
+bits 64
+_start:
+    mov rax, SYS_WRITE
+    mov rdi, STDOUT
+    mov rsi, message
+    mov rdx, message_length
+    syscall
+
+    mov rax, SYS_EXIT
+    xor rdi, rdi
+    syscall
+
+section .rodata
+message:
+    db "Hello from user space", 10
+message_length equ $ - message